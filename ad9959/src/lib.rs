@@ -404,28 +404,15 @@ impl<I: Interface> Ad9959<I> {
         channel: Channel,
         amplitude: f32,
     ) -> Result<f32, Error> {
-        if !(0.0..=1.0).contains(&amplitude) {
-            return Err(Error::Bounds);
-        }
-
-        let amplitude_control: u16 = (amplitude * (1 << 10) as f32) as u16;
-
-        let mut acr: [u8; 3] = [0; 3];
-
-        // Enable the amplitude multiplier for the channel if required. The amplitude control has
-        // full-scale at 0x3FF (amplitude of 1), so the multiplier should be disabled whenever
-        // full-scale is used.
-        if amplitude_control < (1 << 10) {
-            let masked_control = amplitude_control & 0x3FF;
-            acr[1] = masked_control.to_be_bytes()[0];
-            acr[2] = masked_control.to_be_bytes()[1];
-
-            // Enable the amplitude multiplier
-            acr[1].set_bit(4, true);
-        }
+        let acr = amplitude_to_acr(amplitude)?;
 
-        self.modify_channel(channel, Register::ACR, &acr)?;
+        self.modify_channel(channel, Register::ACR, &acr.to_be_bytes()[1..])?;
 
+        let amplitude_control = if acr.get_bit(12) {
+            acr & 0x3FF
+        } else {
+            1 << 10
+        };
         Ok(amplitude_control as f32 / (1 << 10) as f32)
     }
 
@@ -462,15 +449,8 @@ impl<I: Interface> Ad9959<I> {
         channel: Channel,
         frequency: f32,
     ) -> Result<f32, Error> {
-        if frequency < 0.0 || frequency > self.system_clock_frequency() {
-            return Err(Error::Bounds);
-        }
-
-        // The function for channel frequency is `f_out = FTW * f_s / 2^32`, where FTW is the
-        // frequency tuning word and f_s is the system clock rate.
-        let tuning_word: u32 = ((frequency / self.system_clock_frequency())
-            * 1u64.wrapping_shl(32) as f32)
-            as u32;
+        let tuning_word =
+            frequency_to_ftw(frequency, self.system_clock_frequency())?;
 
         self.modify_channel(
             channel,
@@ -511,6 +491,55 @@ impl<I: Interface> Ad9959<I> {
     }
 }
 
+/// Convert a frequency into a frequency tuning word.
+///
+/// Arguments:
+/// * `frequency` - The desired output frequency in Hz.
+/// * `system_clock` - The DDS system clock frequency in Hz.
+///
+/// Returns:
+/// The frequency tuning word (FTW) to program into the CFTW0 register.
+pub fn frequency_to_ftw(
+    frequency: f32,
+    system_clock: f32,
+) -> Result<u32, Error> {
+    if frequency < 0.0 || frequency > system_clock {
+        return Err(Error::Bounds);
+    }
+
+    // The function for channel frequency is `f_out = FTW * f_s / 2^32`, where FTW is the
+    // frequency tuning word and f_s is the system clock rate.
+    Ok(((frequency / system_clock) * 1u64.wrapping_shl(32) as f32) as u32)
+}
+
+/// Convert a normalized amplitude into an amplitude control register value.
+///
+/// Arguments:
+/// * `amplitude` - A normalized amplitude setting [0, 1].
+///
+/// Returns:
+/// The 24-bit amplitude control register (ACR) value stored in the 3 LSB.
+pub fn amplitude_to_acr(amplitude: f32) -> Result<u32, Error> {
+    if !(0.0..=1.0).contains(&amplitude) {
+        return Err(Error::Bounds);
+    }
+
+    let amplitude_control = (amplitude * (1 << 10) as f32) as u32;
+
+    // Enable the amplitude multiplier for the channel if required. The amplitude control has
+    // full-scale at 0x3FF (amplitude of 1), so the multiplier should be disabled whenever
+    // full-scale is used.
+    let mut acr = 0;
+    if amplitude_control < (1 << 10) {
+        acr = amplitude_control & 0x3FF;
+
+        // Enable the amplitude multiplier
+        acr.set_bit(12, true);
+    }
+
+    Ok(acr)
+}
+
 /// Represents a means of serializing a DDS profile for writing to a stream.
 pub struct ProfileSerializer {
     // heapless::Vec<u8, 32>, especially its extend_from_slice() is slow
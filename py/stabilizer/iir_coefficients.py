@@ -238,6 +238,9 @@ def _main():
 
     parser.add_argument("--channel", "-c", type=int, choices=[0, 1],
                         required=True, help="The filter channel to configure.")
+    parser.add_argument("--stage", type=int, default=0,
+                        help="The cascade stage of the filter channel to configure "
+                        "(%(default)s)")
     parser.add_argument("--sample-period", type=float,
                         default=stabilizer.SAMPLE_PERIOD,
                         help="Sample period in seconds (%(default)s s)")
@@ -297,8 +300,7 @@ def _main():
         interface = await miniconf.Miniconf.create(prefix, args.broker)
 
         # Set the filter coefficients.
-        # Note: In the future, we will need to Handle higher-order cascades.
-        await interface.set(f"/iir_ch/{args.channel}/0", {
+        await interface.set(f"/iir_ch/{args.channel}/{args.stage}", {
             "ba": coefficients,
             "y_min": stabilizer.voltage_to_machine_units(args.y_min),
             "y_max": stabilizer.voltage_to_machine_units(args.y_max),
@@ -5,6 +5,7 @@ import argparse
 import asyncio
 import logging
 import struct
+import zlib
 import socket
 import ipaddress
 from collections import namedtuple
@@ -39,41 +40,142 @@ def get_local_ip(remote):
 class AdcDac:
     """Stabilizer default striming data format"""
     format_id = 1
+    # Channel labels by channel mask bit
+    labels = {0: "ADC0", 1: "ADC1", 2: "DAC0", 3: "DAC1", 5: "CMD0", 6: "CMD1"}
 
-    def __init__(self, header, body):
+    def __init__(self, header, body, layout=None):
         self.header = header
         self.body = body
+        # Channel mask of the stream descriptor, all channels if unknown
+        mask = 0b1111 if layout is None else layout.channels
+        self.channels = [i for i in self.labels if mask & (1 << i)]
 
     def size(self):
         """Return the data size of the frame in bytes"""
         return len(self.body)
 
     def to_mu(self):
-        """Return the raw data in machine units"""
-        data = np.frombuffer(self.body, "<i2")
+        """Return the raw data of the present channels in machine units"""
+        n = len([c for c in self.channels if c < 4])
+        m = len(self.channels) - n
+        data = np.frombuffer(self.body, "<u1").reshape(self.header.batches, -1)
+        # ADC and DAC codes (i16) are followed by the commands (i32)
+        split = 2 * n * (data.shape[1] // (2 * n + 4 * m))
         # batch, channel, sample
-        data = data.reshape(self.header.batches, 4, -1)
-        data = data.swapaxes(0, 1).reshape(4, -1)
+        codes = data[:, :split].copy().view("<i2")
+        codes = codes.reshape(self.header.batches, n, -1)
         # convert DAC offset binary to two's complement
-        data[2:] ^= np.int16(0x8000)
-        return data
+        for row, channel in enumerate(self.channels[:n]):
+            if channel >= 2:
+                codes[:, row] ^= np.int16(0x8000)
+        if m:
+            commands = data[:, split:].copy().view("<i4")
+            commands = commands.reshape(self.header.batches, m, -1)
+            codes = np.concatenate([codes.astype("<i4"), commands], axis=1)
+        return codes.swapaxes(0, 1).reshape(n + m, -1).copy()
 
     def to_si(self):
-        """Convert the raw data to SI units"""
+        """Convert the raw data of the present channels to SI units"""
         data = self.to_mu() * DAC_VOLTS_PER_LSB
         return {
-            "adc": data[:2],
-            "dac": data[2:],
+            "adc": data[[i for i, c in enumerate(self.channels) if c < 2]],
+            "dac": data[[i for i, c in enumerate(self.channels) if 2 <= c < 4]],
+            "cmd": data[[i for i, c in enumerate(self.channels) if c >= 5]],
         }
 
     def to_traces(self):
         """Convert the raw data to labelled Trace instances"""
         data = self.to_mu()
         return [
-            Trace(data[0], scale=DAC_VOLTS_PER_LSB, label='ADC0'),
-            Trace(data[1], scale=DAC_VOLTS_PER_LSB, label='ADC1'),
-            Trace(data[2], scale=DAC_VOLTS_PER_LSB, label='DAC0'),
-            Trace(data[3], scale=DAC_VOLTS_PER_LSB, label='DAC1')
+            Trace(values, scale=DAC_VOLTS_PER_LSB, label=self.labels[channel])
+            for values, channel in zip(data, self.channels)
+        ]
+
+
+class AdcDacTimestamp(AdcDac):
+    """Stabilizer streaming data format with a timestamp for each batch"""
+    format_id = 3
+
+    def __init__(self, header, body, layout=None):
+        self._size = len(body)
+        data = np.frombuffer(body, "<u1").reshape(header.batches, -1)
+        # Timer ticks of the timestamp timer at the start of the sampling period of the first
+        # sample of each batch
+        self.timestamps = data[:, :4].copy().view("<u4").ravel()
+        # Application-selected internal signal of each batch, if present
+        self.probe = None
+        if layout is not None and layout.channels & 0b10000:
+            self.probe = data[:, -4:].copy().view("<i4").ravel()
+            data = data[:, :-4]
+        super().__init__(header, data[:, 4:].tobytes(), layout)
+
+    def size(self):
+        """Return the data size of the frame in bytes"""
+        return self._size
+
+
+class AdcDacVolts(AdcDac):
+    """Stabilizer streaming data format with samples in volts"""
+    format_id = 4
+
+    def to_mu(self):
+        """Return the data of the present channels in volts"""
+        n = len(self.channels)
+        data = np.frombuffer(self.body, "<f4")
+        # batch, channel, sample
+        data = data.reshape(self.header.batches, n, -1)
+        return data.swapaxes(0, 1).reshape(n, -1)
+
+    def to_si(self):
+        """Return the data of the present channels in volts"""
+        data = self.to_mu()
+        return {
+            "adc": data[[i for i, c in enumerate(self.channels) if c < 2]],
+            "dac": data[[i for i, c in enumerate(self.channels) if 2 <= c < 4]],
+            "cmd": data[[i for i, c in enumerate(self.channels) if c >= 5]],
+        }
+
+    def to_traces(self):
+        """Convert the data to labelled Trace instances"""
+        data = self.to_mu()
+        return [
+            Trace(values, scale=1, label=self.labels[channel])
+            for values, channel in zip(data, self.channels)
+        ]
+
+
+class LockinHarmonics:
+    """Lockin streaming data format with the squared magnitudes of the reference harmonics"""
+    format_id = 5
+
+    def __init__(self, header, body, layout=None):
+        self.header = header
+        self.body = body
+        data = np.frombuffer(body, "<u4").reshape(header.batches, -1)
+        # Timer ticks of the timestamp timer, see `AdcDacTimestamp`
+        self.timestamps = data[:, 0].copy()
+        self.data = data[:, 1:].T.copy()
+        # Bit n of the channel mask selects harmonic n + 1, all harmonics if unknown
+        mask = (1 << len(self.data)) - 1 if layout is None else layout.channels
+        self.harmonics = [n + 1 for n in range(8) if mask & (1 << n)]
+
+    def size(self):
+        """Return the data size of the frame in bytes"""
+        return len(self.body)
+
+    def to_mu(self):
+        """Return the squared magnitudes of the present harmonics"""
+        return self.data
+
+    def to_si(self):
+        """Return the squared magnitudes of the present harmonics"""
+        return {"harmonics": self.data}
+
+    def to_traces(self):
+        """Convert the data to labelled Trace instances"""
+        return [
+            Trace(values, scale=1, label=f"H{harmonic}")
+            for values, harmonic in zip(self.data, self.harmonics)
         ]
 
 
@@ -83,12 +185,20 @@ class StabilizerStream(asyncio.DatagramProtocol):
     magic = 0x057B
     header_fmt = struct.Struct("<HBBI")
     header = namedtuple("Header", "magic format_id batches sequence")
+    # The reserved format code of stream descriptor frames.
+    descriptor_format_id = 0xFF
+    descriptor_fmt = struct.Struct("<BBHBI")
+    descriptor = namedtuple("Descriptor",
+                            "version format_id batch_size channels decimation")
     parsers = {
         AdcDac.format_id: AdcDac,
+        AdcDacTimestamp.format_id: AdcDacTimestamp,
+        AdcDacVolts.format_id: AdcDacVolts,
+        LockinHarmonics.format_id: LockinHarmonics,
     }
 
     @classmethod
-    async def open(cls, addr, port, broker, maxsize=1):
+    async def open(cls, addr, port, broker, maxsize=1, crc=False):
         """Open a UDP socket and start receiving frames"""
         loop = asyncio.get_running_loop()
         sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM, socket.IPPROTO_UDP)
@@ -113,11 +223,15 @@ class StabilizerStream(asyncio.DatagramProtocol):
         else:
             sock.bind((addr, port))
 
-        transport, protocol = await loop.create_datagram_endpoint(lambda: cls(maxsize), sock=sock)
+        transport, protocol = await loop.create_datagram_endpoint(lambda: cls(maxsize, crc), sock=sock)
         return transport, protocol
 
-    def __init__(self, maxsize):
+    def __init__(self, maxsize, crc=False):
         self.queue = asyncio.Queue(maxsize)
+        # Data frames are followed by a CRC-32 (firmware `stream_crc` feature)
+        self.crc = crc
+        # The most recently received stream descriptor
+        self.layout = None
 
     def connection_made(self, _transport):
         logger.info("Connection made (listening)")
@@ -130,12 +244,22 @@ class StabilizerStream(asyncio.DatagramProtocol):
         if header.magic != self.magic:
             logger.warning("Bad frame magic: %#04x, ignoring", header.magic)
             return
+        if header.format_id == self.descriptor_format_id:
+            self.layout = self.descriptor._make(
+                self.descriptor_fmt.unpack_from(data, self.header_fmt.size))
+            logger.info("Stream descriptor: %s", self.layout)
+            return
+        if self.crc:
+            data, (crc,) = data[:-4], struct.unpack("<I", data[-4:])
+            if zlib.crc32(data) != crc:
+                logger.warning("Bad frame CRC: %#08x, ignoring", header.sequence)
+                return
         try:
             parser = self.parsers[header.format_id]
         except KeyError:
             logger.warning("No parser for format %s, ignoring", header.format_id)
             return
-        frame = parser(header, data[self.header_fmt.size:])
+        frame = parser(header, data[self.header_fmt.size:], self.layout)
         if self.queue.full():
             old = self.queue.get_nowait()
             logger.debug("Dropping frame: %#08x", old.header.sequence)
@@ -193,11 +317,13 @@ async def main():
                         help="Frame queue size")
     parser.add_argument("--duration", type=float, default=1.,
                         help="Test duration")
+    parser.add_argument("--crc", action="store_true",
+                        help="Verify the frame CRC-32 (`stream_crc` firmware feature)")
     args = parser.parse_args()
 
     logging.basicConfig(level=logging.INFO)
     _transport, stream = await StabilizerStream.open(
-        args.host, args.port, args.broker, args.maxsize)
+        args.host, args.port, args.broker, args.maxsize, args.crc)
     await measure(stream, args.duration)
 
 
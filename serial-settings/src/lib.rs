@@ -336,6 +336,11 @@ impl<'a, P: Platform> Runner<'a, P> {
         self.0.context.platform.settings()
     }
 
+    /// Get the platform.
+    pub fn platform_mut(&mut self) -> &mut P {
+        &mut self.0.context.platform
+    }
+
     /// Get the device communication interface
     pub fn interface_mut(&mut self) -> &mut P::Interface {
         self.0.context.platform.interface_mut()
@@ -0,0 +1,159 @@
+//! # DSP Benchmark
+//!
+//! The `dsp-bench` application measures the execution time of the math functions in
+//! [stabilizer::dsp] on the target. The results are logged over RTT once after startup.
+//!
+//! ## Features
+//! * Execution time in CPU cycles per call, measured with the DWT cycle counter
+//! * Mean over [INPUTS] pseudo-random inputs with the loop overhead subtracted
+//! * Worst-case error of the arctangent approximations versus double precision
+#![deny(warnings)]
+#![no_std]
+#![no_main]
+
+use cortex_m::peripheral::DWT;
+use fugit::ExtU64;
+use num_traits::Float;
+use rand_core::{RngCore, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+use stabilizer::{
+    dsp,
+    hardware::{self, SystemTimer, Systick},
+};
+
+// The number of samples in each batch process
+const BATCH_SIZE: usize = 8;
+
+// The logarithm of the number of 100MHz timer ticks between each sample. With a value of 2^7 =
+// 128, there is 1.28uS per sample, corresponding to a sampling frequency of 781.25 KHz.
+const SAMPLE_TICKS_LOG2: u8 = 7;
+const SAMPLE_TICKS: u32 = 1 << SAMPLE_TICKS_LOG2;
+
+/// The number of inputs each function is evaluated with.
+pub const INPUTS: usize = 1024;
+
+/// Measure the mean execution time of a function.
+///
+/// # Note
+/// The inputs are read with volatile reads to keep the calls between the cycle counter reads. The
+/// measurement is repeated to report the timing with a warm instruction cache.
+///
+/// # Args
+/// * `inputs` - The arguments to evaluate the function with.
+/// * `f` - The function to measure.
+///
+/// # Returns
+/// The mean number of CPU cycles per call including the loop overhead and the wrapping sum of the
+/// results.
+#[inline(never)]
+fn measure(inputs: &[(i32, i32)], f: impl Fn(i32, i32) -> i32) -> (u32, i32) {
+    let mut result = (0, 0);
+    for _ in 0..2 {
+        let start = DWT::cycle_count();
+        let sum = inputs.iter().fold(0i32, |sum, input| {
+            // Note(unsafe): The reference is valid and aligned.
+            let (y, x) = unsafe { core::ptr::read_volatile(input) };
+            sum.wrapping_add(f(y, x))
+        });
+        let cycles = DWT::cycle_count().wrapping_sub(start);
+        result = (cycles / inputs.len() as u32, sum);
+    }
+    result
+}
+
+/// Determine the worst-case error of a two-argument arctangent.
+///
+/// # Args
+/// * `inputs` - The `(y, x)` arguments to evaluate the arctangent with.
+/// * `atan2` - The arctangent scaled such that `1 << 31` corresponds to pi.
+///
+/// # Returns
+/// The maximum absolute deviation from the double precision arctangent in LSB.
+fn atan2_error(inputs: &[(i32, i32)], atan2: impl Fn(i32, i32) -> i32) -> u32 {
+    inputs.iter().fold(0, |max, &(y, x)| {
+        let exact = (y as f64).atan2(x as f64) * (1u64 << 31) as f64
+            / core::f64::consts::PI;
+        // The difference wraps at +-pi.
+        let error =
+            (atan2(y, x) as i64).wrapping_sub(exact.round() as i64) as i32;
+        max.max(error.unsigned_abs())
+    })
+}
+
+#[rtic::app(device = stabilizer::hardware::hal::stm32, peripherals = true, dispatchers=[DCMI])]
+mod app {
+    use super::*;
+
+    #[monotonic(binds = SysTick, default = true, priority = 2)]
+    type Monotonic = Systick;
+
+    #[shared]
+    struct Shared {}
+
+    #[local]
+    struct Local {}
+
+    #[init]
+    fn init(c: init::Context) -> (Shared, Local, init::Monotonics) {
+        let clock = SystemTimer::new(|| monotonics::now().ticks() as u32);
+
+        // Configure the microcontroller
+        let (stabilizer, _pounder) = hardware::setup::setup(
+            c.core,
+            c.device,
+            clock,
+            BATCH_SIZE,
+            SAMPLE_TICKS,
+        );
+
+        bench::spawn_after(100.millis()).unwrap();
+
+        (Shared {}, Local {}, init::Monotonics(stabilizer.systick))
+    }
+
+    #[task(priority = 1)]
+    fn bench(_: bench::Context) {
+        let mut rng = XorShiftRng::from_seed([0; 16]);
+        let mut inputs = [(0i32, 0i32); INPUTS];
+        for input in inputs.iter_mut() {
+            let r = rng.next_u64();
+            *input = ((r >> 32) as i32, r as i32);
+        }
+
+        let (overhead, _) = measure(&inputs, |y, x| y ^ x);
+        log::info!("Loop overhead: {} cycles per call", overhead);
+
+        let (cycles, sum) = measure(&inputs, |y, x| {
+            dsp::sqrt(((y as u32 as u64) << 32) | x as u32 as u64) as i32
+        });
+        log::info!(
+            "sqrt: {} cycles per call (result sum {})",
+            cycles.saturating_sub(overhead),
+            sum
+        );
+
+        let (cycles, sum) = measure(&inputs, idsp::atan2);
+        log::info!(
+            "atan2: {} cycles per call, max error {} LSB (result sum {})",
+            cycles.saturating_sub(overhead),
+            atan2_error(&inputs, idsp::atan2),
+            sum
+        );
+
+        let (cycles, sum) = measure(&inputs, dsp::atan2_precise);
+        log::info!(
+            "atan2_precise: {} cycles per call, max error {} LSB (result sum {})",
+            cycles.saturating_sub(overhead),
+            atan2_error(&inputs, dsp::atan2_precise),
+            sum
+        );
+    }
+
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::wfi();
+        }
+    }
+}
@@ -14,6 +14,8 @@
 //! * Generic biquad (second order) IIR filter
 //! * Anti-windup
 //! * Derivative kick avoidance
+//! * Optional mains harmonic notch filters on the inputs
+//! * Output interlock on a digital input
 //!
 //! ## Settings
 //! Refer to the [Settings] structure for documentation of run-time configurable settings for this
@@ -29,29 +31,40 @@
 #![no_std]
 #![no_main]
 
-use core::mem::MaybeUninit;
 use core::sync::atomic::{fence, Ordering};
 
+use cortex_m::peripheral::DWT;
 use fugit::ExtU64;
 use mutex_trait::prelude::*;
 
 use idsp::iir;
 
 use stabilizer::{
+    dsp::{
+        decimate,
+        notch_bank::{NotchBank, NotchState},
+        rms::Rms,
+    },
     hardware::{
         self,
-        adc::{Adc0Input, Adc1Input, AdcCode},
+        adc::{Adc0Input, Adc1Input, AdcCalibration, AdcCode},
         afe::Gain,
         dac::{Dac0Output, Dac1Output, DacCode},
         hal,
+        output_stage::OutputStage,
         signal_generator::{self, SignalGenerator},
         timers::SamplingTimer,
         DigitalInput0, DigitalInput1, SerialTerminal, SystemTimer, Systick,
-        UsbDevice, AFE0, AFE1,
+        UsbDevice, Watchdog, AFE0, AFE1,
     },
     net::{
-        data_stream::{FrameGenerator, StreamFormat, StreamTarget},
+        data_stream::{
+            AdcDacLayout, AdcDacStream, FrameGenerator, StreamFormat,
+            StreamTarget, ADC_CHANNELS, ALL_CHANNELS, COMMAND_CHANNELS,
+            DAC_CHANNELS,
+        },
         miniconf::Tree,
+        serde::{Deserialize, Serialize},
         telemetry::{Telemetry, TelemetryBuffer},
         NetworkState, NetworkUsers,
     },
@@ -59,9 +72,19 @@ use stabilizer::{
 
 const SCALE: f32 = i16::MAX as _;
 
-// The number of cascaded IIR biquads per channel. Select 1 or 2!
+// The number of cascaded IIR biquads per channel. Any length is supported, but each additional
+// biquad adds on the order of 30 CPU cycles (75 ns at 400 MHz) of processing time per sample and
+// channel. With the default batch size and sample rate, each stage thus costs roughly 1.2 us of the
+// 10.24 us batch period. Check the processing time when selecting more than a few stages.
 const IIR_CASCADE_LENGTH: usize = 1;
 
+// The number of mains harmonics suppressed by the optional notch filter bank and the quality
+// factor of the notch at each harmonic. Each notch adds five `f64` multiply-accumulates per
+// sample and channel while the bank is enabled. Check the `process_cycles` telemetry when
+// enabling the notch filters.
+const NOTCH_HARMONICS: usize = 3;
+const NOTCH_Q: [f64; NOTCH_HARMONICS] = [10., 20., 30.];
+
 // The number of samples in each batch process
 const BATCH_SIZE: usize = 8;
 
@@ -72,6 +95,21 @@ const SAMPLE_TICKS: u32 = 1 << SAMPLE_TICKS_LOG2;
 const SAMPLE_PERIOD: f32 =
     SAMPLE_TICKS as f32 * hardware::design_parameters::TIMER_PERIOD;
 
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+enum Interlock {
+    /// The DAC outputs are always enabled
+    Disabled,
+    /// The DAC outputs are enabled while DI0 is high
+    Di0,
+    /// The DAC outputs are enabled while DI1 is high
+    Di1,
+}
+
+// The paths of one-shot commands. They are not persisted in flash so that they do not fire again
+// at the next power-up.
+const ONE_SHOT_SETTINGS: &[&str] =
+    &["/stream_capture", "/stream_trigger", "/commit"];
+
 #[derive(Clone, Copy, Debug, Tree)]
 pub struct Settings {
     /// Configure the Analog Front End (AFE) gain.
@@ -86,13 +124,49 @@ pub struct Settings {
     #[tree]
     afe: [Gain; 2],
 
+    /// Configure the ADC offset and gain correction.
+    ///
+    /// # Path
+    /// `adc_calibration/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// See [AdcCalibration#miniconf]
+    #[tree]
+    adc_calibration: [AdcCalibration; 2],
+
+    /// Specifies the setpoint of the control error.
+    ///
+    /// # Path
+    /// `error_setpoint/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// The input voltage in volts, referred to the AFE input, around which the RMS of the
+    /// control error is reported as `error_rms` telemetry. It does not affect the filters.
+    /// Setpoints outside of the ADC range are replaced by 0 V.
+    #[tree]
+    error_setpoint: [f32; 2],
+
+    /// Specifies the window of the control error RMS.
+    ///
+    /// # Path
+    /// `error_rms_window`
+    ///
+    /// # Value
+    /// The number of samples over which the mean square of the control error is accumulated
+    /// before the RMS is updated.
+    error_rms_window: u32,
+
     /// Configure the IIR filter parameters.
     ///
     /// # Path
     /// `iir_ch/<n>/<m>`
     ///
     /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
-    /// * `<m>` specifies which cascade to configure. `<m>` := [0, 1], depending on [IIR_CASCADE_LENGTH]
+    /// * `<m>` specifies which cascade to configure. `<m>` := [0, [IIR_CASCADE_LENGTH] - 1]
     ///
     /// # Value
     /// See [iir::IIR#miniconf]
@@ -117,6 +191,45 @@ pub struct Settings {
     /// "true" or "false"
     force_hold: bool,
 
+    /// Specifies whether the filters process the batch mean instead of every sample.
+    ///
+    /// # Path
+    /// `batch_average`
+    ///
+    /// # Value
+    /// "true" to average each batch of input samples (after the notch filters) and run the IIR
+    /// filters once per batch on the mean. The output is held for the whole batch. The filters
+    /// then run at the batch rate (the sample rate divided by the batch size), so their
+    /// coefficients must be designed for that rate and the control bandwidth is limited to well
+    /// below half the batch rate. The averaging is a boxcar with its first null at the batch rate
+    /// and adds half a batch period of latency. The sampling, the notch filters and the signal
+    /// generators keep running at the full sample rate. "false" filters every sample.
+    batch_average: bool,
+
+    /// Specifies whether the DAC outputs are frozen.
+    ///
+    /// # Path
+    /// `freeze`
+    ///
+    /// # Value
+    /// "true" to hold the DAC outputs at their last value. The filters keep running on the inputs
+    /// so that their state is current when the outputs are released. "false" updates the outputs.
+    /// An open interlock overrides the freeze.
+    freeze: bool,
+
+    /// Specifies the output update divider.
+    ///
+    /// # Path
+    /// `output_divider`
+    ///
+    /// # Value
+    /// The DAC outputs are updated only every `output_divider` batches and held in between (a
+    /// zero-order hold), e.g. for downstream devices that can not follow full-rate updates. Each
+    /// update writes the last computed sample to the entire batch. The filters keep running at
+    /// the full sample rate. Zero and one update every sample. An open interlock overrides the
+    /// hold. The `dac_slew_limit` then limits the change of each update instead of each sample.
+    output_divider: u32,
+
     /// Specifies the telemetry output period in seconds.
     ///
     /// # Path
@@ -126,6 +239,94 @@ pub struct Settings {
     /// Any non-zero value less than 65536.
     telemetry_period: u16,
 
+    /// Specifies the DAC output soft-start ramp.
+    ///
+    /// # Path
+    /// `soft_start`
+    ///
+    /// # Value
+    /// The number of batches over which the DAC outputs are ramped linearly from 0 V to their
+    /// computed value after the outputs are enabled at power-up. Zero disables the ramp. Changes
+    /// take effect at the next power-up.
+    soft_start: u32,
+
+    /// Specifies the DAC output limits.
+    ///
+    /// # Path
+    /// `dac_limit/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// The maximum output magnitude as a fraction of the DAC full scale [0, 1]. The DAC samples
+    /// are clamped to this range after all other processing, regardless of how they were computed.
+    #[tree]
+    dac_limit: [f32; 2],
+
+    /// Specifies the DAC output offsets.
+    ///
+    /// # Path
+    /// `dac_offset/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// A constant bias in volts added to the computed output before the soft-start ramp and
+    /// `dac_limit` are applied. Offsets beyond the DAC range saturate at full scale.
+    #[tree]
+    dac_offset: [f32; 2],
+
+    /// Specifies the DAC output slew-rate limits.
+    ///
+    /// # Path
+    /// `dac_slew_limit/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// The maximum change of the DAC output between consecutive samples in LSB. The limit bounds
+    /// the rate of change, not the level, and applies to the output after `dac_limit`. Zero
+    /// disables the limit.
+    #[tree]
+    dac_slew_limit: [u16; 2],
+
+    /// Specifies the digital input enabling the DAC outputs.
+    ///
+    /// # Path
+    /// `interlock`
+    ///
+    /// # Value
+    /// One of the variants of [Interlock] enclosed in double quotes. While the selected input is
+    /// low, the DAC outputs are forced to `interlock_level`. The filters keep running.
+    interlock: Interlock,
+
+    /// Specifies the DAC output level while the interlock disables the outputs.
+    ///
+    /// # Path
+    /// `interlock_level/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// The output voltage in volts. It is subject to `dac_limit`. Levels outside of the DAC range
+    /// are replaced by 0 V.
+    #[tree]
+    interlock_level: [f32; 2],
+
+    /// Specifies the mains frequency suppressed on the ADC inputs.
+    ///
+    /// # Path
+    /// `mains_notch/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// The mains fundamental frequency in Hz, i.e. 50 or 60. Notch filters at the fundamental and
+    /// its harmonics are applied to the ADC input before the IIR filters. Zero disables the
+    /// notch filters.
+    #[tree]
+    mains_notch: [f32; 2],
+
     /// Specifies the target for data livestreaming.
     ///
     /// # Path
@@ -135,6 +336,93 @@ pub struct Settings {
     /// See [StreamTarget#miniconf]
     stream_target: StreamTarget,
 
+    /// Specifies the target for the split data stream.
+    ///
+    /// # Path
+    /// `stream_split_target`
+    ///
+    /// # Value
+    /// See [StreamTarget#miniconf]. If an address is specified, the DAC and command channels
+    /// selected by `stream_channels` are streamed to this target in a separate stream while
+    /// `stream_target` only receives the ADC channels. An unspecified address (the default)
+    /// streams all selected channels to `stream_target`.
+    stream_split_target: StreamTarget,
+
+    /// Specifies whether data is streamed.
+    ///
+    /// # Path
+    /// `stream_enabled`
+    ///
+    /// # Value
+    /// "true" or "false". Data generated while the stream is disabled is discarded.
+    stream_enabled: bool,
+
+    /// Specifies the channels included in the data stream.
+    ///
+    /// # Path
+    /// `stream_channels`
+    ///
+    /// # Value
+    /// A bit mask of the streamed channels. Bits 0 through 3 correspond to ADC0, ADC1, DAC0, and
+    /// DAC1 respectively. Bits 5 and 6 correspond to the commanded outputs CMD0 and CMD1, i.e. the
+    /// outputs before the DAC limits, the slew-rate limits and any other override are applied. They
+    /// are not saturated, so a difference between the command and the DAC output reveals
+    /// saturation.
+    stream_channels: u8,
+
+    /// Specifies the number of batches in a one-shot stream capture.
+    ///
+    /// # Path
+    /// `stream_capture`
+    ///
+    /// # Value
+    /// The number of batches to stream after a capture is triggered, after which streaming stops.
+    /// Zero streams continuously. The remaining number of batches is reported as `stream_capture`
+    /// telemetry.
+    stream_capture: u32,
+
+    /// Triggers a one-shot stream capture.
+    ///
+    /// # Path
+    /// `stream_trigger`
+    ///
+    /// # Value
+    /// Any value different from the current one (re-)starts a capture of `stream_capture`
+    /// batches.
+    stream_trigger: u32,
+
+    /// Specifies whether streamed samples are converted to volts.
+    ///
+    /// # Path
+    /// `stream_volts`
+    ///
+    /// # Value
+    /// "true" to stream in the [StreamFormat::AdcDacVolts] format, "false" to stream raw codes in
+    /// the [StreamFormat::AdcDacData] format.
+    stream_volts: bool,
+
+    /// Specifies the decimation of the streamed data.
+    ///
+    /// # Path
+    /// `stream_decimation`
+    ///
+    /// # Value
+    /// The number of consecutive samples averaged into each streamed sample, from 1 (no
+    /// decimation) to 65536. Every streamed batch then spans `stream_decimation` processed
+    /// batches. Processing always runs at the full sample rate.
+    stream_decimation: u32,
+
+    /// Specifies the number of batches in each stream frame.
+    ///
+    /// # Path
+    /// `stream_frame_batches`
+    ///
+    /// # Value
+    /// The maximum number of batches sent in each UDP frame. Small values (e.g. 1) minimize the
+    /// stream latency, e.g. for real-time displays. Zero fills each frame completely, which
+    /// maximizes the throughput, e.g. for bulk captures.
+    stream_frame_batches: u8,
+
     /// Specifies the config for signal generators to add on to DAC0/DAC1 outputs.
     ///
     /// # Path
@@ -146,6 +434,17 @@ pub struct Settings {
     /// See [signal_generator::BasicConfig#miniconf]
     #[tree(depth(2))]
     signal_generator: [signal_generator::BasicConfig; 2],
+
+    /// Persists the settings in flash.
+    ///
+    /// # Path
+    /// `commit`
+    ///
+    /// # Value
+    /// Any value different from the current one stores the current settings in flash. They are
+    /// restored at the next power-up. Storing may stall the network for several seconds while a
+    /// flash sector is erased.
+    commit: u32,
 }
 
 impl Default for Settings {
@@ -153,6 +452,10 @@ impl Default for Settings {
         Self {
             // Analog frontend programmable gain amplifier gains (G1, G2, G5, G10)
             afe: [Gain::G1, Gain::G1],
+            adc_calibration: [AdcCalibration::default(); 2],
+            error_setpoint: [0.; 2],
+            // About 84 ms at the default sample rate.
+            error_rms_window: 1 << 16,
             // IIR filter tap gains are an array `[b0, b1, b2, a1, a2]` such that the
             // new output is computed as `y0 = a1*y1 + a2*y2 + b0*x0 + b1*x1 + b2*x2`.
             // The array is `iir_state[channel-index][cascade-index][coeff-index]`.
@@ -164,12 +467,31 @@ impl Default for Settings {
             allow_hold: false,
             // Force suppress filter output updates.
             force_hold: false,
+            batch_average: false,
+            freeze: false,
+            output_divider: 1,
             // The default telemetry period in seconds.
             telemetry_period: 10,
+            soft_start: 0,
+            dac_limit: [1.; 2],
+            dac_offset: [0.; 2],
+            dac_slew_limit: [0; 2],
+            interlock: Interlock::Disabled,
+            interlock_level: [0.; 2],
+            mains_notch: [0.; 2],
 
             signal_generator: [signal_generator::BasicConfig::default(); 2],
 
             stream_target: StreamTarget::default(),
+            stream_split_target: StreamTarget::default(),
+            stream_enabled: true,
+            stream_channels: ALL_CHANNELS,
+            stream_capture: 0,
+            stream_trigger: 0,
+            stream_volts: false,
+            stream_decimation: 1,
+            stream_frame_batches: 0,
+            commit: 0,
         }
     }
 }
@@ -184,24 +506,35 @@ mod app {
     #[shared]
     struct Shared {
         usb: UsbDevice,
+        usb_terminal: SerialTerminal,
         network: NetworkUsers<Settings, Telemetry, 3>,
 
         settings: Settings,
         telemetry: TelemetryBuffer,
         signal_generator: [SignalGenerator; 2],
+        notch: [Option<NotchBank<NOTCH_HARMONICS>>; 2],
     }
 
     #[local]
     struct Local {
-        usb_terminal: SerialTerminal,
         sampling_timer: SamplingTimer,
         digital_inputs: (DigitalInput0, DigitalInput1),
         afes: (AFE0, AFE1),
         adcs: (Adc0Input, Adc1Input),
         dacs: (Dac0Output, Dac1Output),
         iir_state: [[iir::Vec5<f32>; IIR_CASCADE_LENGTH]; 2],
+        notch_state: [[NotchState; NOTCH_HARMONICS]; 2],
         generator: FrameGenerator,
+        split_generator: FrameGenerator,
+        stream: AdcDacStream<BATCH_SIZE>,
+        capture: (u32, u32),
+        sample_period: f32,
+        output_stage: OutputStage<2>,
+        error_rms: [Rms; 2],
+        held: [u16; 2],
+        output_count: u32,
         cpu_temp_sensor: stabilizer::hardware::cpu_temp_sensor::CpuTempSensor,
+        watchdog: Watchdog,
     }
 
     #[init]
@@ -209,7 +542,7 @@ mod app {
         let clock = SystemTimer::new(|| monotonics::now().ticks() as u32);
 
         // Configure the microcontroller
-        let (stabilizer, _pounder) = hardware::setup::setup(
+        let (mut stabilizer, _pounder) = hardware::setup::setup(
             c.core,
             c.device,
             clock,
@@ -217,49 +550,84 @@ mod app {
             SAMPLE_TICKS,
         );
 
-        let settings = stabilizer.usb_serial.settings();
+        // The sample period including the device specific sample rate divider.
+        let sample_period = SAMPLE_PERIOD * stabilizer.sample_divider as f32;
+
+        // Restore the run-time settings persisted in flash.
+        let mut settings = Settings::default();
+        stabilizer
+            .usb_serial
+            .platform_mut()
+            .load(env!("CARGO_BIN_NAME"), &mut settings);
+
+        // Fall back to no correction if a restored ADC calibration is invalid.
+        AdcCalibration::validate(
+            &mut settings.adc_calibration,
+            &[AdcCalibration::default(); 2],
+        );
+
+        // Fall back to a silent default waveform if a restored one is invalid.
+        let signal_generator = [0, 1].map(|i| {
+            let config = settings.signal_generator[i]
+                .try_into_config(sample_period, DacCode::FULL_SCALE)
+                .unwrap_or_else(|err| {
+                    log::error!(
+                        "Invalid signal generation on DAC{} in flash: {:?}",
+                        i,
+                        err
+                    );
+                    settings.signal_generator[i] = Default::default();
+                    Default::default()
+                });
+            SignalGenerator::new(config)
+        });
+
+        let device = stabilizer.usb_serial.settings();
         let mut network = NetworkUsers::new(
             stabilizer.net.stack,
             stabilizer.net.phy,
             clock,
             env!("CARGO_BIN_NAME"),
-            &settings.broker,
-            &settings.id,
+            &device,
+            settings,
         );
+        network.telemetry.set_sample_rate(1. / sample_period);
 
-        let generator = network.configure_streaming(StreamFormat::AdcDacData);
-
-        let settings = Settings::default();
+        let generator =
+            network.configure_streaming(StreamFormat::AdcDacData, BATCH_SIZE);
+        let split_generator = network
+            .configure_split_streaming(StreamFormat::AdcDacData, BATCH_SIZE);
 
         let shared = Shared {
             usb: stabilizer.usb,
+            usb_terminal: stabilizer.usb_serial,
             network,
             settings,
             telemetry: TelemetryBuffer::default(),
-            signal_generator: [
-                SignalGenerator::new(
-                    settings.signal_generator[0]
-                        .try_into_config(SAMPLE_PERIOD, DacCode::FULL_SCALE)
-                        .unwrap(),
-                ),
-                SignalGenerator::new(
-                    settings.signal_generator[1]
-                        .try_into_config(SAMPLE_PERIOD, DacCode::FULL_SCALE)
-                        .unwrap(),
-                ),
-            ],
+            signal_generator,
+            // The notch filters are configured by the initial settings update.
+            notch: [None; 2],
         };
 
         let mut local = Local {
-            usb_terminal: stabilizer.usb_serial,
             sampling_timer: stabilizer.adc_dac_timer,
             digital_inputs: stabilizer.digital_inputs,
             afes: stabilizer.afes,
             adcs: stabilizer.adcs,
             dacs: stabilizer.dacs,
             iir_state: [[[0.; 5]; IIR_CASCADE_LENGTH]; 2],
+            notch_state: [[[0.; 4]; NOTCH_HARMONICS]; 2],
             generator,
+            split_generator,
+            stream: AdcDacStream::default(),
+            capture: (0, 0),
+            sample_period,
+            output_stage: OutputStage::new(settings.soft_start),
+            error_rms: Default::default(),
+            held: [DacCode::from(0i16).0; 2],
+            output_count: 0,
             cpu_temp_sensor: stabilizer.temperature_sensor,
+            watchdog: stabilizer.watchdog,
         };
 
         // Enable ADC/DAC events
@@ -275,6 +643,11 @@ mod app {
         usb::spawn().unwrap();
         start::spawn_after(100.millis()).unwrap();
 
+        // Reset the device if the idle loop stalls.
+        local.watchdog.start(fugit::MillisDurationU32::millis(
+            hardware::WATCHDOG_TIMEOUT_MS,
+        ));
+
         (shared, local, init::Monotonics(stabilizer.systick))
     }
 
@@ -291,7 +664,8 @@ mod app {
     ///
     /// DSP application code starts after the ADC has generated a batch of samples and must be
     /// completed by the time the next batch of ADC samples has been acquired (plus the FIFO buffer
-    /// time). If this constraint is not met, firmware will panic due to an ADC input overrun.
+    /// time). If this constraint is not met, an ADC input overrun discards the batch and restarts
+    /// sampling.
     ///
     /// The DSP application code must also fill out the next DAC output buffer in time such that the
     /// DAC can switch to it when it has completed the current buffer. If this constraint is not met
@@ -300,13 +674,16 @@ mod app {
     ///
     /// Because the ADC and DAC operate at the same rate, these two constraints actually implement
     /// the same time bounds, meeting one also means the other is also met.
-    #[task(binds=DMA1_STR4, local=[digital_inputs, adcs, dacs, iir_state, generator], shared=[settings, signal_generator, telemetry], priority=3)]
+    #[task(binds=DMA1_STR4, local=[digital_inputs, adcs, dacs, iir_state, notch_state, generator, split_generator, stream, capture, output_stage, error_rms, held, output_count], shared=[settings, signal_generator, notch, telemetry], priority=3)]
     #[link_section = ".itcm.process"]
     fn process(c: process::Context) {
+        let start = DWT::cycle_count();
+
         let process::SharedResources {
             settings,
             telemetry,
             signal_generator,
+            notch,
         } = c.shared;
 
         let process::LocalResources {
@@ -314,11 +691,19 @@ mod app {
             adcs: (adc0, adc1),
             dacs: (dac0, dac1),
             iir_state,
+            notch_state,
             generator,
+            split_generator,
+            stream,
+            capture,
+            output_stage,
+            error_rms,
+            held,
+            output_count,
         } = c.local;
 
-        (settings, telemetry, signal_generator).lock(
-            |settings, telemetry, signal_generator| {
+        (settings, telemetry, signal_generator, notch).lock(
+            |settings, telemetry, signal_generator, notch| {
                 let digital_inputs =
                     [digital_inputs.0.is_high(), digital_inputs.1.is_high()];
                 telemetry.digital_inputs = digital_inputs;
@@ -326,67 +711,203 @@ mod app {
                 let hold = settings.force_hold
                     || (digital_inputs[1] && settings.allow_hold);
 
+                let enabled = match settings.interlock {
+                    Interlock::Disabled => true,
+                    Interlock::Di0 => digital_inputs[0],
+                    Interlock::Di1 => digital_inputs[1],
+                };
+
                 (adc0, adc1, dac0, dac1).lock(|adc0, adc1, dac0, dac1| {
-                    let adc_samples = [adc0, adc1];
+                    let mut adc_samples = [adc0, adc1];
                     let dac_samples = [dac0, dac1];
 
                     // Preserve instruction and data ordering w.r.t. DMA flag access.
                     fence(Ordering::SeqCst);
 
+                    // Correct the ADC offset and gain errors.
+                    for (samples, calibration) in
+                        adc_samples.iter_mut().zip(&settings.adc_calibration)
+                    {
+                        calibration.apply(samples);
+                    }
+
+                    // Track the RMS of the control error.
+                    for (channel, (samples, rms)) in
+                        adc_samples.iter().zip(error_rms.iter_mut()).enumerate()
+                    {
+                        let setpoint = AdcCode::try_from(
+                            settings.error_setpoint[channel]
+                                * settings.afe[channel].as_multiplier(),
+                        )
+                        .map(i16::from)
+                        .unwrap_or(0);
+                        rms.update_errors(
+                            samples.iter().map(|x| i16::from(AdcCode(*x))),
+                            setpoint,
+                            settings.error_rms_window,
+                        );
+                    }
+                    telemetry.error_rms =
+                        Some([error_rms[0].get(), error_rms[1].get()]);
+
+                    // Both channels share the soft-start gain of the batch.
+                    output_stage.update(
+                        &settings.dac_offset,
+                        &settings.dac_limit,
+                        &settings.dac_slew_limit,
+                    );
+
+                    let safe = settings.interlock_level.map(|level| {
+                        DacCode::try_from(level).map(i16::from).unwrap_or(0)
+                    });
+
+                    // The commanded outputs in DAC LSB before saturation.
+                    let mut commands = [[0i32; BATCH_SIZE]; 2];
+
+                    // With an output divider, the slew limit applies to each held output update
+                    // instead of each sample. Otherwise the held output could step by the
+                    // per-sample limit times the number of samples between updates.
+                    let divided = settings.output_divider > 1 && enabled;
+
                     for channel in 0..adc_samples.len() {
-                        adc_samples[channel]
-                            .iter()
+                        let x = adc_samples[channel].iter().map(|ai| {
+                            let x = f32::from(*ai as i16);
+                            match &notch[channel] {
+                                Some(notch) => {
+                                    notch.update(&mut notch_state[channel], x)
+                                }
+                                None => x,
+                            }
+                        });
+
+                        let mut iir = |x: f32| {
+                            settings.iir_ch[channel]
+                                .iter()
+                                .zip(iir_state[channel].iter_mut())
+                                .fold(x, |yi, (ch, state)| {
+                                    ch.update(state, yi, hold)
+                                })
+                        };
+
+                        // Filter every sample or, when averaging, only the batch mean.
+                        let mut y = [0.; BATCH_SIZE];
+                        if settings.batch_average {
+                            let mean = x.sum::<f32>() / BATCH_SIZE as f32;
+                            y = [iir(mean); BATCH_SIZE];
+                        } else {
+                            for (y, x) in y.iter_mut().zip(x) {
+                                *y = iir(x);
+                            }
+                        }
+
+                        y.iter()
                             .zip(dac_samples[channel].iter_mut())
+                            .zip(commands[channel].iter_mut())
                             .zip(&mut signal_generator[channel])
-                            .map(|((ai, di), signal)| {
-                                let x = f32::from(*ai as i16);
-                                let y = settings.iir_ch[channel]
-                                    .iter()
-                                    .zip(iir_state[channel].iter_mut())
-                                    .fold(x, |yi, (ch, state)| {
-                                        ch.update(state, yi, hold)
-                                    });
-
+                            .map(|(((&y, di), command), signal)| {
                                 // Note(unsafe): The filter limits must ensure that the value is in range.
                                 // The truncation introduces 1/2 LSB distortion.
                                 let y: i16 = unsafe { y.to_int_unchecked() };
 
-                                let y = y.saturating_add(signal);
-
-                                // Convert to DAC code
-                                *di = DacCode::from(y).0;
+                                *command = output_stage
+                                    .command(channel, y as i32 + signal as i32);
+                                *di = DacCode::from(y.saturating_add(signal)).0;
                             })
                             .last();
+
+                        // Force the safe level while the interlock is open.
+                        if enabled {
+                            output_stage
+                                .scale(channel, &mut dac_samples[channel][..]);
+                        } else {
+                            dac_samples[channel]
+                                .fill(DacCode::from(safe[channel]).0);
+                        }
+                        if !divided {
+                            output_stage
+                                .limit(channel, &mut dac_samples[channel][..]);
+                        }
                     }
 
-                    // Stream the data.
-                    const N: usize = BATCH_SIZE * core::mem::size_of::<i16>();
-                    generator.add(|buf| {
-                        for (data, buf) in adc_samples
-                            .iter()
-                            .chain(dac_samples.iter())
-                            .zip(buf.chunks_exact_mut(N))
+                    // Hold the last output while frozen. Every batch buffer is filled with the held
+                    // code so that the output does not alternate between the DMA buffers. The
+                    // interlock takes precedence.
+                    //
+                    // With an output divider, the outputs are only updated every
+                    // `output_divider` batches with the last computed sample.
+                    let update = *output_count == 0;
+                    *output_count =
+                        (*output_count + 1) % settings.output_divider.max(1);
+                    for channel in 0..dac_samples.len() {
+                        if settings.freeze && enabled {
+                            dac_samples[channel].fill(held[channel]);
+                            // Continue from the held output when released.
+                            output_stage.set(channel, held[channel]);
+                        } else if divided {
+                            if update {
+                                // Note(unwrap): The batch is not empty.
+                                let mut last =
+                                    [*dac_samples[channel].last().unwrap()];
+                                output_stage.limit(channel, &mut last);
+                                held[channel] = last[0];
+                            }
+                            dac_samples[channel].fill(held[channel]);
+                        } else if let Some(&code) = dac_samples[channel].last()
                         {
-                            let data = unsafe {
-                                core::slice::from_raw_parts(
-                                    data.as_ptr() as *const MaybeUninit<u8>,
-                                    N,
-                                )
-                            };
-                            buf.copy_from_slice(data)
+                            held[channel] = code;
                         }
-                        N * 4
-                    });
-                    // Update telemetry measurements.
-                    telemetry.adcs = [
-                        AdcCode(adc_samples[0][0]),
-                        AdcCode(adc_samples[1][0]),
-                    ];
+                    }
 
-                    telemetry.dacs = [
-                        DacCode(dac_samples[0][0]),
-                        DacCode(dac_samples[1][0]),
-                    ];
+                    generator.set_frame_batches(settings.stream_frame_batches);
+                    split_generator
+                        .set_frame_batches(settings.stream_frame_batches);
+
+                    // Start or stop a one-shot capture.
+                    let request =
+                        (settings.stream_capture, settings.stream_trigger);
+                    if request != *capture {
+                        let batches = (settings.stream_capture != 0)
+                            .then_some(settings.stream_capture);
+                        generator.capture(batches);
+                        split_generator.capture(batches);
+                        *capture = request;
+                    }
+
+                    // Decimate and stream the data. With a split target, the DAC and command
+                    // channels are streamed separately from the ADC channels.
+                    stream.add(
+                        [&mut *generator, &mut *split_generator],
+                        [&*adc_samples[0], &*adc_samples[1]],
+                        [&*dac_samples[0], &*dac_samples[1]],
+                        [&commands[0], &commands[1]],
+                        &AdcDacLayout {
+                            decimation: settings.stream_decimation,
+                            channels: settings.stream_channels,
+                            split: !settings
+                                .stream_split_target
+                                .is_unspecified(),
+                            volts: settings.stream_volts.then(|| {
+                                [
+                                    settings.afe[0].as_multiplier(),
+                                    settings.afe[1].as_multiplier(),
+                                ]
+                            }),
+                        },
+                    );
+
+                    // Update telemetry measurements.
+                    telemetry.stream_dropped = Some(
+                        generator
+                            .dropped()
+                            .wrapping_add(split_generator.dropped()),
+                    );
+                    telemetry.stream_capture = generator.capture_remaining();
+                    telemetry.update_codes(&adc_samples, &dac_samples);
+
+                    // Track the processing time of the batch.
+                    telemetry.update_process_cycles(
+                        DWT::cycle_count().wrapping_sub(start),
+                    );
 
                     // Preserve instruction and data ordering w.r.t. DMA flag access.
                     fence(Ordering::SeqCst);
@@ -395,9 +916,11 @@ mod app {
         );
     }
 
-    #[idle(shared=[network, usb])]
+    #[idle(shared=[network, usb], local=[watchdog])]
     fn idle(mut c: idle::Context) -> ! {
         loop {
+            c.local.watchdog.feed();
+
             match c.shared.network.lock(|net| net.update()) {
                 NetworkState::SettingsChanged(_path) => {
                     settings_update::spawn().unwrap()
@@ -416,38 +939,112 @@ mod app {
         }
     }
 
-    #[task(priority = 1, local=[afes], shared=[network, settings, signal_generator])]
+    #[task(priority = 1, local=[afes, sample_period], shared=[network, settings, usb_terminal, signal_generator, notch])]
     fn settings_update(mut c: settings_update::Context) {
-        let settings = c.shared.network.lock(|net| *net.miniconf.settings());
-        c.shared.settings.lock(|current| *current = settings);
-
-        c.local.afes.0.set_gain(settings.afe[0]);
-        c.local.afes.1.set_gain(settings.afe[1]);
+        let mut settings =
+            c.shared.network.lock(|net| *net.miniconf.settings());
+        // Keep the stream decimation in the supported range.
+        settings.stream_decimation =
+            settings.stream_decimation.clamp(1, decimate::MAX_FACTOR);
+
+        c.shared.settings.lock(|current| {
+            // The ADC gain corrections must be in range, keep the previous ones otherwise.
+            AdcCalibration::validate(
+                &mut settings.adc_calibration,
+                &current.adc_calibration,
+            );
+        });
 
         // Update the signal generators
-        for (i, &config) in settings.signal_generator.iter().enumerate() {
-            match config.try_into_config(SAMPLE_PERIOD, DacCode::FULL_SCALE) {
+        for (i, config) in settings.signal_generator.iter_mut().enumerate() {
+            match config
+                .try_into_config(*c.local.sample_period, DacCode::FULL_SCALE)
+            {
                 Ok(config) => {
                     c.shared
                         .signal_generator
                         .lock(|generator| generator[i].update_waveform(config));
                 }
-                Err(err) => log::error!(
-                    "Failed to update signal generation on DAC{}: {:?}",
-                    i,
-                    err
-                ),
+                Err(err) => {
+                    log::error!(
+                        "Failed to update signal generation on DAC{}: {:?}",
+                        i,
+                        err
+                    );
+                    // Keep the current waveform so that the rejected one is not persisted.
+                    *config = c
+                        .shared
+                        .settings
+                        .lock(|current| current.signal_generator[i]);
+                }
             }
         }
+        let commit = c.shared.settings.lock(|current| {
+            let commit = settings.commit != current.commit;
+            *current = settings;
+            commit
+        });
+
+        c.local.afes.0.set_gain(settings.afe[0]);
+        c.local.afes.1.set_gain(settings.afe[1]);
+
+        // Update the mains notch filters.
+        let sample_period = *c.local.sample_period as f64;
+        let notch = settings.mains_notch.map(|frequency| {
+            (frequency > 0.).then(|| {
+                NotchBank::new(frequency as f64 * sample_period, NOTCH_Q)
+            })
+        });
+        c.shared.notch.lock(|current| *current = notch);
 
         let target = settings.stream_target.into();
-        c.shared.network.lock(|net| net.direct_stream(target));
+        let split_target = settings.stream_split_target;
+        c.shared.network.lock(|net| {
+            net.direct_stream(target);
+            net.direct_split_stream(split_target.into());
+            net.enable_stream(settings.stream_enabled);
+            if split_target.is_unspecified() {
+                net.select_stream_channels(settings.stream_channels);
+            } else {
+                net.select_stream_channels(
+                    settings.stream_channels & ADC_CHANNELS,
+                );
+                net.select_split_stream_channels(
+                    settings.stream_channels
+                        & (DAC_CHANNELS | COMMAND_CHANNELS),
+                );
+            }
+            net.select_stream_format(if settings.stream_volts {
+                StreamFormat::AdcDacVolts
+            } else {
+                StreamFormat::AdcDacData
+            });
+            net.select_stream_decimation(settings.stream_decimation);
+        });
+
+        // Persist the settings in flash on an explicit commit.
+        if commit {
+            c.shared.usb_terminal.lock(|terminal| {
+                terminal
+                    .platform_mut()
+                    .store(env!("CARGO_BIN_NAME"), &settings, ONE_SHOT_SETTINGS)
+                    .map_err(|e| {
+                        log::error!("Failed to persist settings: {:?}", e)
+                    })
+                    .ok();
+            });
+        }
     }
 
     #[task(priority = 1, shared=[network, settings, telemetry], local=[cpu_temp_sensor])]
     fn telemetry(mut c: telemetry::Context) {
-        let telemetry: TelemetryBuffer =
-            c.shared.telemetry.lock(|telemetry| *telemetry);
+        // The processing time covers a single telemetry period.
+        let mut telemetry: TelemetryBuffer =
+            c.shared.telemetry.lock(|telemetry| {
+                let current = *telemetry;
+                telemetry.process_cycles = None;
+                current
+            });
 
         let (gains, telemetry_period) = c
             .shared
@@ -455,6 +1052,7 @@ mod app {
             .lock(|settings| (settings.afe, settings.telemetry_period));
 
         c.shared.network.lock(|net| {
+            telemetry.streaming = Some(net.is_streaming());
             net.telemetry.publish(&telemetry.finalize(
                 gains[0],
                 gains[1],
@@ -467,14 +1065,16 @@ mod app {
             .unwrap();
     }
 
-    #[task(priority = 1, shared=[usb], local=[usb_terminal])]
+    #[task(priority = 1, shared=[usb, usb_terminal])]
     fn usb(mut c: usb::Context) {
         // Handle the USB serial terminal.
-        c.shared.usb.lock(|usb| {
-            usb.poll(&mut [c.local.usb_terminal.interface_mut().inner_mut()]);
-        });
+        c.shared.usb_terminal.lock(|terminal| {
+            c.shared.usb.lock(|usb| {
+                usb.poll(&mut [terminal.interface_mut().inner_mut()]);
+            });
 
-        c.local.usb_terminal.process().unwrap();
+            terminal.process().unwrap();
+        });
 
         // Schedule to run this task every 10 milliseconds.
         usb::spawn_after(10u64.millis()).unwrap();
@@ -491,14 +1091,16 @@ mod app {
         unsafe { hal::ethernet::interrupt_handler() }
     }
 
-    #[task(binds = SPI2, priority = 4)]
+    // The ADC error handlers run at the priority of `process()` so that an overrun recovery never
+    // preempts the processing of a batch.
+    #[task(binds = SPI2, priority = 3)]
     fn spi2(_: spi2::Context) {
-        panic!("ADC0 SPI error");
+        Adc0Input::handle_error();
     }
 
-    #[task(binds = SPI3, priority = 4)]
+    #[task(binds = SPI3, priority = 3)]
     fn spi3(_: spi3::Context) {
-        panic!("ADC1 SPI error");
+        Adc1Input::handle_error();
     }
 
     #[task(binds = SPI4, priority = 4)]
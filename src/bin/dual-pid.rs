@@ -4,28 +4,34 @@
 #![no_std]
 #![no_main]
 
-use core::mem::MaybeUninit;
 use core::sync::atomic::{fence, Ordering};
 
+use cortex_m::peripheral::DWT;
 use fugit::ExtU64;
 use mutex_trait::prelude::*;
 
 use idsp::pid;
 
 use stabilizer::{
+    dsp::{decimate, rms::Rms},
     hardware::{
         self,
-        adc::{Adc0Input, Adc1Input, AdcCode},
+        adc::{Adc0Input, Adc1Input, AdcCalibration, AdcCode},
         afe::Gain,
         dac::{Dac0Output, Dac1Output, DacCode},
         hal,
+        output_stage::OutputStage,
         signal_generator::{self, SignalGenerator},
         timers::SamplingTimer,
         DigitalInput0, DigitalInput1, SerialTerminal, SystemTimer, Systick,
-        UsbDevice, AFE0, AFE1,
+        UsbDevice, Watchdog, AFE0, AFE1,
     },
     net::{
-        data_stream::{FrameGenerator, StreamFormat, StreamTarget},
+        data_stream::{
+            AdcDacLayout, AdcDacStream, FrameGenerator, StreamFormat,
+            StreamTarget, ADC_CHANNELS, ALL_CHANNELS, COMMAND_CHANNELS,
+            DAC_CHANNELS,
+        },
         miniconf::Tree,
         telemetry::{Telemetry, TelemetryBuffer},
         NetworkState, NetworkUsers,
@@ -44,6 +50,11 @@ const SAMPLE_TICKS: u32 = 1 << SAMPLE_TICKS_LOG2;
 const SAMPLE_PERIOD: f32 =
     SAMPLE_TICKS as f32 * hardware::design_parameters::TIMER_PERIOD;
 
+// The paths of one-shot commands. They are not persisted in flash so that they do not fire again
+// at the next power-up.
+const ONE_SHOT_SETTINGS: &[&str] =
+    &["/stream_capture", "/stream_trigger", "/commit"];
+
 #[derive(Clone, Copy, Debug, Tree)]
 pub struct Settings {
     /// Configure the Analog Front End (AFE) gain.
@@ -58,6 +69,42 @@ pub struct Settings {
     #[tree]
     afe: [Gain; 2],
 
+    /// Configure the ADC offset and gain correction.
+    ///
+    /// # Path
+    /// `adc_calibration/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// See [AdcCalibration#miniconf]
+    #[tree]
+    adc_calibration: [AdcCalibration; 2],
+
+    /// Specifies the setpoint of the control error.
+    ///
+    /// # Path
+    /// `error_setpoint/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// The input voltage in volts, referred to the AFE input, around which the RMS of the
+    /// control error is reported as `error_rms` telemetry. It does not affect the filters.
+    /// Setpoints outside of the ADC range are replaced by 0 V.
+    #[tree]
+    error_setpoint: [f32; 2],
+
+    /// Specifies the window of the control error RMS.
+    ///
+    /// # Path
+    /// `error_rms_window`
+    ///
+    /// # Value
+    /// The number of samples over which the mean square of the control error is accumulated
+    /// before the RMS is updated.
+    error_rms_window: u32,
+
     /// Configure the PID filter parameters.
     ///
     /// # Path
@@ -96,6 +143,57 @@ pub struct Settings {
     /// Any non-zero value less than 65536.
     telemetry_period: u16,
 
+    /// Specifies the DAC output soft-start ramp.
+    ///
+    /// # Path
+    /// `soft_start`
+    ///
+    /// # Value
+    /// The number of batches over which the DAC outputs are ramped linearly from 0 V to their
+    /// computed value after the outputs are enabled at power-up. Zero disables the ramp. Changes
+    /// take effect at the next power-up.
+    soft_start: u32,
+
+    /// Specifies the DAC output limits.
+    ///
+    /// # Path
+    /// `dac_limit/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// The maximum output magnitude as a fraction of the DAC full scale [0, 1]. The DAC samples
+    /// are clamped to this range after all other processing, regardless of how they were computed.
+    #[tree]
+    dac_limit: [f32; 2],
+
+    /// Specifies the DAC output offsets.
+    ///
+    /// # Path
+    /// `dac_offset/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// A constant bias in volts added to the computed output before the soft-start ramp and
+    /// `dac_limit` are applied. Offsets beyond the DAC range saturate at full scale.
+    #[tree]
+    dac_offset: [f32; 2],
+
+    /// Specifies the DAC output slew-rate limits.
+    ///
+    /// # Path
+    /// `dac_slew_limit/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// The maximum change of the DAC output between consecutive samples in LSB. The limit bounds
+    /// the rate of change, not the level, and applies to the output after `dac_limit`. Zero
+    /// disables the limit.
+    #[tree]
+    dac_slew_limit: [u16; 2],
+
     /// Specifies the target for data livestreaming.
     ///
     /// # Path
@@ -105,6 +203,93 @@ pub struct Settings {
     /// See [StreamTarget#miniconf]
     stream_target: StreamTarget,
 
+    /// Specifies the target for the split data stream.
+    ///
+    /// # Path
+    /// `stream_split_target`
+    ///
+    /// # Value
+    /// See [StreamTarget#miniconf]. If an address is specified, the DAC and command channels
+    /// selected by `stream_channels` are streamed to this target in a separate stream while
+    /// `stream_target` only receives the ADC channels. An unspecified address (the default)
+    /// streams all selected channels to `stream_target`.
+    stream_split_target: StreamTarget,
+
+    /// Specifies whether data is streamed.
+    ///
+    /// # Path
+    /// `stream_enabled`
+    ///
+    /// # Value
+    /// "true" or "false". Data generated while the stream is disabled is discarded.
+    stream_enabled: bool,
+
+    /// Specifies the channels included in the data stream.
+    ///
+    /// # Path
+    /// `stream_channels`
+    ///
+    /// # Value
+    /// A bit mask of the streamed channels. Bits 0 through 3 correspond to ADC0, ADC1, DAC0, and
+    /// DAC1 respectively. Bits 5 and 6 correspond to the commanded outputs CMD0 and CMD1, i.e. the
+    /// outputs before the DAC limits, the slew-rate limits and any other override are applied. They
+    /// are not saturated, so a difference between the command and the DAC output reveals
+    /// saturation.
+    stream_channels: u8,
+
+    /// Specifies the number of batches in a one-shot stream capture.
+    ///
+    /// # Path
+    /// `stream_capture`
+    ///
+    /// # Value
+    /// The number of batches to stream after a capture is triggered, after which streaming stops.
+    /// Zero streams continuously. The remaining number of batches is reported as `stream_capture`
+    /// telemetry.
+    stream_capture: u32,
+
+    /// Triggers a one-shot stream capture.
+    ///
+    /// # Path
+    /// `stream_trigger`
+    ///
+    /// # Value
+    /// Any value different from the current one (re-)starts a capture of `stream_capture`
+    /// batches.
+    stream_trigger: u32,
+
+    /// Specifies whether streamed samples are converted to volts.
+    ///
+    /// # Path
+    /// `stream_volts`
+    ///
+    /// # Value
+    /// "true" to stream in the [StreamFormat::AdcDacVolts] format, "false" to stream raw codes in
+    /// the [StreamFormat::AdcDacData] format.
+    stream_volts: bool,
+
+    /// Specifies the decimation of the streamed data.
+    ///
+    /// # Path
+    /// `stream_decimation`
+    ///
+    /// # Value
+    /// The number of consecutive samples averaged into each streamed sample, from 1 (no
+    /// decimation) to 65536. Every streamed batch then spans `stream_decimation` processed
+    /// batches. Processing always runs at the full sample rate.
+    stream_decimation: u32,
+
+    /// Specifies the number of batches in each stream frame.
+    ///
+    /// # Path
+    /// `stream_frame_batches`
+    ///
+    /// # Value
+    /// The maximum number of batches sent in each UDP frame. Small values (e.g. 1) minimize the
+    /// stream latency, e.g. for real-time displays. Zero fills each frame completely, which
+    /// maximizes the throughput, e.g. for bulk captures.
+    stream_frame_batches: u8,
+
     /// Specifies the config for signal generators to add on to DAC0/DAC1 outputs.
     ///
     /// # Path
@@ -116,6 +301,17 @@ pub struct Settings {
     /// See [signal_generator::BasicConfig#miniconf]
     #[tree(depth(2))]
     signal_generator: [signal_generator::BasicConfig; 2],
+
+    /// Persists the settings in flash.
+    ///
+    /// # Path
+    /// `commit`
+    ///
+    /// # Value
+    /// Any value different from the current one stores the current settings in flash. They are
+    /// restored at the next power-up. Storing may stall the network for several seconds while a
+    /// flash sector is erased.
+    commit: u32,
 }
 
 impl Default for Settings {
@@ -123,6 +319,10 @@ impl Default for Settings {
         Self {
             // Analog frontend programmable gain amplifier gains (G1, G2, G5, G10)
             afe: [Gain::G1, Gain::G1],
+            adc_calibration: [AdcCalibration::default(); 2],
+            error_setpoint: [0.; 2],
+            // About 84 ms at the default sample rate.
+            error_rms_window: 1 << 16,
             // PID Parameters
             pid_ch: [pid::PID::new(-SCALE, SCALE, SCALE); 2],
 
@@ -132,10 +332,23 @@ impl Default for Settings {
             force_hold: false,
             // The default telemetry period in seconds.
             telemetry_period: 10,
+            soft_start: 0,
+            dac_limit: [1.; 2],
+            dac_offset: [0.; 2],
+            dac_slew_limit: [0; 2],
 
             signal_generator: [signal_generator::BasicConfig::default(); 2],
 
             stream_target: StreamTarget::default(),
+            stream_split_target: StreamTarget::default(),
+            stream_enabled: true,
+            stream_channels: ALL_CHANNELS,
+            stream_capture: 0,
+            stream_trigger: 0,
+            stream_volts: false,
+            stream_decimation: 1,
+            stream_frame_batches: 0,
+            commit: 0,
         }
     }
 }
@@ -150,6 +363,7 @@ mod app {
     #[shared]
     struct Shared {
         usb: UsbDevice,
+        usb_terminal: SerialTerminal,
         network: NetworkUsers<Settings, Telemetry, 3>,
 
         settings: Settings,
@@ -159,7 +373,6 @@ mod app {
 
     #[local]
     struct Local {
-        usb_terminal: SerialTerminal,
         sampling_timer: SamplingTimer,
         digital_inputs: (DigitalInput0, DigitalInput1),
         afes: (AFE0, AFE1),
@@ -167,7 +380,14 @@ mod app {
         dacs: (Dac0Output, Dac1Output),
         pid_state: [pid::Vec3<f32>; 2],
         generator: FrameGenerator,
+        split_generator: FrameGenerator,
+        stream: AdcDacStream<BATCH_SIZE>,
+        capture: (u32, u32),
+        sample_period: f32,
+        output_stage: OutputStage<2>,
+        error_rms: [Rms; 2],
         cpu_temp_sensor: stabilizer::hardware::cpu_temp_sensor::CpuTempSensor,
+        watchdog: Watchdog,
     }
 
     #[init]
@@ -175,7 +395,7 @@ mod app {
         let clock = SystemTimer::new(|| monotonics::now().ticks() as u32);
 
         // Configure the microcontroller
-        let (stabilizer, _pounder) = hardware::setup::setup(
+        let (mut stabilizer, _pounder) = hardware::setup::setup(
             c.core,
             c.device,
             clock,
@@ -183,41 +403,64 @@ mod app {
             SAMPLE_TICKS,
         );
 
-        let settings = stabilizer.usb_serial.settings();
+        // The sample period including the device specific sample rate divider.
+        let sample_period = SAMPLE_PERIOD * stabilizer.sample_divider as f32;
+
+        // Restore the run-time settings persisted in flash.
+        let mut settings = Settings::default();
+        stabilizer
+            .usb_serial
+            .platform_mut()
+            .load(env!("CARGO_BIN_NAME"), &mut settings);
+
+        // Fall back to no correction if a restored ADC calibration is invalid.
+        AdcCalibration::validate(
+            &mut settings.adc_calibration,
+            &[AdcCalibration::default(); 2],
+        );
+
+        // Fall back to a silent default waveform if a restored one is invalid.
+        let signal_generator = [0, 1].map(|i| {
+            let config = settings.signal_generator[i]
+                .try_into_config(sample_period, DacCode::FULL_SCALE)
+                .unwrap_or_else(|err| {
+                    log::error!(
+                        "Invalid signal generation on DAC{} in flash: {:?}",
+                        i,
+                        err
+                    );
+                    settings.signal_generator[i] = Default::default();
+                    Default::default()
+                });
+            SignalGenerator::new(config)
+        });
+
+        let device = stabilizer.usb_serial.settings();
         let mut network = NetworkUsers::new(
             stabilizer.net.stack,
             stabilizer.net.phy,
             clock,
             env!("CARGO_BIN_NAME"),
-            &settings.broker,
-            &settings.id,
+            &device,
+            settings,
         );
+        network.telemetry.set_sample_rate(1. / sample_period);
 
-        let generator = network.configure_streaming(StreamFormat::AdcDacData);
-
-        let settings = Settings::default();
+        let generator =
+            network.configure_streaming(StreamFormat::AdcDacData, BATCH_SIZE);
+        let split_generator = network
+            .configure_split_streaming(StreamFormat::AdcDacData, BATCH_SIZE);
 
         let shared = Shared {
             usb: stabilizer.usb,
+            usb_terminal: stabilizer.usb_serial,
             network,
             settings,
             telemetry: TelemetryBuffer::default(),
-            signal_generator: [
-                SignalGenerator::new(
-                    settings.signal_generator[0]
-                        .try_into_config(SAMPLE_PERIOD, DacCode::FULL_SCALE)
-                        .unwrap(),
-                ),
-                SignalGenerator::new(
-                    settings.signal_generator[1]
-                        .try_into_config(SAMPLE_PERIOD, DacCode::FULL_SCALE)
-                        .unwrap(),
-                ),
-            ],
+            signal_generator,
         };
 
         let mut local = Local {
-            usb_terminal: stabilizer.usb_serial,
             sampling_timer: stabilizer.adc_dac_timer,
             digital_inputs: stabilizer.digital_inputs,
             afes: stabilizer.afes,
@@ -225,7 +468,14 @@ mod app {
             dacs: stabilizer.dacs,
             pid_state: [[0.; 3]; 2],
             generator,
+            split_generator,
+            stream: AdcDacStream::default(),
+            capture: (0, 0),
+            sample_period,
+            output_stage: OutputStage::new(settings.soft_start),
+            error_rms: Default::default(),
             cpu_temp_sensor: stabilizer.temperature_sensor,
+            watchdog: stabilizer.watchdog,
         };
 
         // Enable ADC/DAC events
@@ -241,6 +491,11 @@ mod app {
         usb::spawn().unwrap();
         start::spawn_after(100.millis()).unwrap();
 
+        // Reset the device if the idle loop stalls.
+        local.watchdog.start(fugit::MillisDurationU32::millis(
+            hardware::WATCHDOG_TIMEOUT_MS,
+        ));
+
         (shared, local, init::Monotonics(stabilizer.systick))
     }
 
@@ -257,7 +512,8 @@ mod app {
     ///
     /// DSP application code starts after the ADC has generated a batch of samples and must be
     /// completed by the time the next batch of ADC samples has been acquired (plus the FIFO buffer
-    /// time). If this constraint is not met, firmware will panic due to an ADC input overrun.
+    /// time). If this constraint is not met, an ADC input overrun discards the batch and restarts
+    /// sampling.
     ///
     /// The DSP application code must also fill out the next DAC output buffer in time such that the
     /// DAC can switch to it when it has completed the current buffer. If this constraint is not met
@@ -266,9 +522,11 @@ mod app {
     ///
     /// Because the ADC and DAC operate at the same rate, these two constraints actually implement
     /// the same time bounds, meeting one also means the other is also met.
-    #[task(binds=DMA1_STR4, local=[digital_inputs, adcs, dacs, pid_state, generator], shared=[settings, signal_generator, telemetry], priority=3)]
+    #[task(binds=DMA1_STR4, local=[digital_inputs, adcs, dacs, pid_state, generator, split_generator, stream, capture, output_stage, error_rms], shared=[settings, signal_generator, telemetry], priority=3)]
     #[link_section = ".itcm.process"]
     fn process(c: process::Context) {
+        let start = DWT::cycle_count();
+
         let process::SharedResources {
             settings,
             telemetry,
@@ -281,6 +539,11 @@ mod app {
             dacs: (dac0, dac1),
             pid_state,
             generator,
+            split_generator,
+            stream,
+            capture,
+            output_stage,
+            error_rms,
         } = c.local;
 
         (settings, telemetry, signal_generator).lock(
@@ -293,60 +556,120 @@ mod app {
                     || (digital_inputs[1] && settings.allow_hold);
 
                 (adc0, adc1, dac0, dac1).lock(|adc0, adc1, dac0, dac1| {
-                    let adc_samples = [adc0, adc1];
+                    let mut adc_samples = [adc0, adc1];
                     let dac_samples = [dac0, dac1];
 
                     // Preserve instruction and data ordering w.r.t. DMA flag access.
                     fence(Ordering::SeqCst);
 
+                    // Correct the ADC offset and gain errors.
+                    for (samples, calibration) in
+                        adc_samples.iter_mut().zip(&settings.adc_calibration)
+                    {
+                        calibration.apply(samples);
+                    }
+
+                    // Track the RMS of the control error.
+                    for (channel, (samples, rms)) in
+                        adc_samples.iter().zip(error_rms.iter_mut()).enumerate()
+                    {
+                        let setpoint = AdcCode::try_from(
+                            settings.error_setpoint[channel]
+                                * settings.afe[channel].as_multiplier(),
+                        )
+                        .map(i16::from)
+                        .unwrap_or(0);
+                        rms.update_errors(
+                            samples.iter().map(|x| i16::from(AdcCode(*x))),
+                            setpoint,
+                            settings.error_rms_window,
+                        );
+                    }
+                    telemetry.error_rms =
+                        Some([error_rms[0].get(), error_rms[1].get()]);
+
+                    // Both channels share the soft-start gain of the batch.
+                    output_stage.update(
+                        &settings.dac_offset,
+                        &settings.dac_limit,
+                        &settings.dac_slew_limit,
+                    );
+
+                    // The commanded outputs in DAC LSB before saturation.
+                    let mut commands = [[0i32; BATCH_SIZE]; 2];
+
                     for channel in 0..adc_samples.len() {
                         adc_samples[channel]
                             .iter()
                             .zip(dac_samples[channel].iter_mut())
+                            .zip(commands[channel].iter_mut())
                             .zip(&mut signal_generator[channel])
-                            .map(|((ai, di), signal)| {
+                            .map(|(((ai, di), command), signal)| {
                                 let x = f32::from(*ai as i16);
                                 let y = settings.pid_ch[channel].update(&mut pid_state[channel], x, hold);
                                 // Note(unsafe): The pid limits must ensure that the value is in range.
                                 // The truncation introduces 1/2 LSB distortion.
                                 let y: i16 = unsafe { y.to_int_unchecked() };
 
-                                let y = y.saturating_add(signal);
-
-                                // Convert to DAC code
-                                *di = DacCode::from(y).0;
+                                *command = output_stage
+                                    .command(channel, y as i32 + signal as i32);
+                                *di = DacCode::from(y.saturating_add(signal)).0;
                             })
                             .last();
+                        output_stage
+                            .apply(channel, &mut dac_samples[channel][..]);
                     }
 
-                    // Stream the data.
-                    const N: usize = BATCH_SIZE * core::mem::size_of::<i16>();
-                    generator.add(|buf| {
-                        for (data, buf) in adc_samples
-                            .iter()
-                            .chain(dac_samples.iter())
-                            .zip(buf.chunks_exact_mut(N))
-                        {
-                            let data = unsafe {
-                                core::slice::from_raw_parts(
-                                    data.as_ptr() as *const MaybeUninit<u8>,
-                                    N,
-                                )
-                            };
-                            buf.copy_from_slice(data)
-                        }
-                        N * 4
-                    });
-                    // Update telemetry measurements.
-                    telemetry.adcs = [
-                        AdcCode(adc_samples[0][0]),
-                        AdcCode(adc_samples[1][0]),
-                    ];
+                    generator.set_frame_batches(settings.stream_frame_batches);
+                    split_generator
+                        .set_frame_batches(settings.stream_frame_batches);
+
+                    // Start or stop a one-shot capture.
+                    let request =
+                        (settings.stream_capture, settings.stream_trigger);
+                    if request != *capture {
+                        let batches = (settings.stream_capture != 0)
+                            .then_some(settings.stream_capture);
+                        generator.capture(batches);
+                        split_generator.capture(batches);
+                        *capture = request;
+                    }
 
-                    telemetry.dacs = [
-                        DacCode(dac_samples[0][0]),
-                        DacCode(dac_samples[1][0]),
-                    ];
+                    // Decimate and stream the data. With a split target, the DAC and command
+                    // channels are streamed separately from the ADC channels.
+                    stream.add(
+                        [&mut *generator, &mut *split_generator],
+                        [&*adc_samples[0], &*adc_samples[1]],
+                        [&*dac_samples[0], &*dac_samples[1]],
+                        [&commands[0], &commands[1]],
+                        &AdcDacLayout {
+                            decimation: settings.stream_decimation,
+                            channels: settings.stream_channels,
+                            split: !settings
+                                .stream_split_target
+                                .is_unspecified(),
+                            volts: settings.stream_volts.then(|| {
+                                [
+                                    settings.afe[0].as_multiplier(),
+                                    settings.afe[1].as_multiplier(),
+                                ]
+                            }),
+                        },
+                    );
+
+                    // Update telemetry measurements.
+                    telemetry.stream_dropped = Some(
+                        generator
+                            .dropped()
+                            .wrapping_add(split_generator.dropped()),
+                    );
+                    telemetry.stream_capture = generator.capture_remaining();
+                    telemetry.update_codes(&adc_samples, &dac_samples);
+
+                    // Track the processing time of the batch.
+                    telemetry.update_process_cycles(
+                        DWT::cycle_count().wrapping_sub(start),
+                    );
 
                     // Preserve instruction and data ordering w.r.t. DMA flag access.
                     fence(Ordering::SeqCst);
@@ -355,9 +678,11 @@ mod app {
         );
     }
 
-    #[idle(shared=[network, usb])]
+    #[idle(shared=[network, usb], local=[watchdog])]
     fn idle(mut c: idle::Context) -> ! {
         loop {
+            c.local.watchdog.feed();
+
             match c.shared.network.lock(|net| net.update()) {
                 NetworkState::SettingsChanged(_path) => {
                     settings_update::spawn().unwrap()
@@ -376,38 +701,103 @@ mod app {
         }
     }
 
-    #[task(priority = 1, local=[afes], shared=[network, settings, signal_generator])]
+    #[task(priority = 1, local=[afes, sample_period], shared=[network, settings, usb_terminal, signal_generator])]
     fn settings_update(mut c: settings_update::Context) {
-        let settings = c.shared.network.lock(|net| *net.miniconf.settings());
-        c.shared.settings.lock(|current| *current = settings);
-
-        c.local.afes.0.set_gain(settings.afe[0]);
-        c.local.afes.1.set_gain(settings.afe[1]);
+        let mut settings =
+            c.shared.network.lock(|net| *net.miniconf.settings());
+        // Keep the stream decimation in the supported range.
+        settings.stream_decimation =
+            settings.stream_decimation.clamp(1, decimate::MAX_FACTOR);
+
+        c.shared.settings.lock(|current| {
+            // The ADC gain corrections must be in range, keep the previous ones otherwise.
+            AdcCalibration::validate(
+                &mut settings.adc_calibration,
+                &current.adc_calibration,
+            );
+        });
 
         // Update the signal generators
-        for (i, &config) in settings.signal_generator.iter().enumerate() {
-            match config.try_into_config(SAMPLE_PERIOD, DacCode::FULL_SCALE) {
+        for (i, config) in settings.signal_generator.iter_mut().enumerate() {
+            match config
+                .try_into_config(*c.local.sample_period, DacCode::FULL_SCALE)
+            {
                 Ok(config) => {
                     c.shared
                         .signal_generator
                         .lock(|generator| generator[i].update_waveform(config));
                 }
-                Err(err) => log::error!(
-                    "Failed to update signal generation on DAC{}: {:?}",
-                    i,
-                    err
-                ),
+                Err(err) => {
+                    log::error!(
+                        "Failed to update signal generation on DAC{}: {:?}",
+                        i,
+                        err
+                    );
+                    // Keep the current waveform so that the rejected one is not persisted.
+                    *config = c
+                        .shared
+                        .settings
+                        .lock(|current| current.signal_generator[i]);
+                }
             }
         }
+        let commit = c.shared.settings.lock(|current| {
+            let commit = settings.commit != current.commit;
+            *current = settings;
+            commit
+        });
+
+        c.local.afes.0.set_gain(settings.afe[0]);
+        c.local.afes.1.set_gain(settings.afe[1]);
 
         let target = settings.stream_target.into();
-        c.shared.network.lock(|net| net.direct_stream(target));
+        let split_target = settings.stream_split_target;
+        c.shared.network.lock(|net| {
+            net.direct_stream(target);
+            net.direct_split_stream(split_target.into());
+            net.enable_stream(settings.stream_enabled);
+            if split_target.is_unspecified() {
+                net.select_stream_channels(settings.stream_channels);
+            } else {
+                net.select_stream_channels(
+                    settings.stream_channels & ADC_CHANNELS,
+                );
+                net.select_split_stream_channels(
+                    settings.stream_channels
+                        & (DAC_CHANNELS | COMMAND_CHANNELS),
+                );
+            }
+            net.select_stream_format(if settings.stream_volts {
+                StreamFormat::AdcDacVolts
+            } else {
+                StreamFormat::AdcDacData
+            });
+            net.select_stream_decimation(settings.stream_decimation);
+        });
+
+        // Persist the settings in flash on an explicit commit.
+        if commit {
+            c.shared.usb_terminal.lock(|terminal| {
+                terminal
+                    .platform_mut()
+                    .store(env!("CARGO_BIN_NAME"), &settings, ONE_SHOT_SETTINGS)
+                    .map_err(|e| {
+                        log::error!("Failed to persist settings: {:?}", e)
+                    })
+                    .ok();
+            });
+        }
     }
 
     #[task(priority = 1, shared=[network, settings, telemetry], local=[cpu_temp_sensor])]
     fn telemetry(mut c: telemetry::Context) {
-        let telemetry: TelemetryBuffer =
-            c.shared.telemetry.lock(|telemetry| *telemetry);
+        // The processing time covers a single telemetry period.
+        let mut telemetry: TelemetryBuffer =
+            c.shared.telemetry.lock(|telemetry| {
+                let current = *telemetry;
+                telemetry.process_cycles = None;
+                current
+            });
 
         let (gains, telemetry_period) = c
             .shared
@@ -415,6 +805,7 @@ mod app {
             .lock(|settings| (settings.afe, settings.telemetry_period));
 
         c.shared.network.lock(|net| {
+            telemetry.streaming = Some(net.is_streaming());
             net.telemetry.publish(&telemetry.finalize(
                 gains[0],
                 gains[1],
@@ -427,14 +818,16 @@ mod app {
             .unwrap();
     }
 
-    #[task(priority = 1, shared=[usb], local=[usb_terminal])]
+    #[task(priority = 1, shared=[usb, usb_terminal])]
     fn usb(mut c: usb::Context) {
         // Handle the USB serial terminal.
-        c.shared.usb.lock(|usb| {
-            usb.poll(&mut [c.local.usb_terminal.interface_mut().inner_mut()]);
-        });
+        c.shared.usb_terminal.lock(|terminal| {
+            c.shared.usb.lock(|usb| {
+                usb.poll(&mut [terminal.interface_mut().inner_mut()]);
+            });
 
-        c.local.usb_terminal.process().unwrap();
+            terminal.process().unwrap();
+        });
 
         // Schedule to run this task every 10 milliseconds.
         usb::spawn_after(10u64.millis()).unwrap();
@@ -451,14 +844,16 @@ mod app {
         unsafe { hal::ethernet::interrupt_handler() }
     }
 
-    #[task(binds = SPI2, priority = 4)]
+    // The ADC error handlers run at the priority of `process()` so that an overrun recovery never
+    // preempts the processing of a batch.
+    #[task(binds = SPI2, priority = 3)]
     fn spi2(_: spi2::Context) {
-        panic!("ADC0 SPI error");
+        Adc0Input::handle_error();
     }
 
-    #[task(binds = SPI3, priority = 4)]
+    #[task(binds = SPI3, priority = 3)]
     fn spi3(_: spi3::Context) {
-        panic!("ADC1 SPI error");
+        Adc1Input::handle_error();
     }
 
     #[task(binds = SPI4, priority = 4)]
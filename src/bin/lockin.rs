@@ -11,7 +11,9 @@
 //!     2. External: Reciprocal PLL, reference input applied to DI0.
 //! * Adjustable PLL and locking time constants
 //! * Adjustable phase offset and harmonic index
-//! * Run-time configurable output modes (in-phase, quadrature, magnitude, log2 power, phase, frequency)
+//! * Selectable input channel (ADC0 or ADC1) or dual-channel demodulation of both
+//! * Run-time configurable output modes (in-phase, quadrature, magnitude, amplitude, log2 power,
+//!   phase, unwrapped phase, frequency)
 //! * Input/output data streamng via UDP
 //!
 //! ## Settings
@@ -34,26 +36,43 @@ use core::{
     sync::atomic::{fence, Ordering},
 };
 
+use cortex_m::peripheral::DWT;
 use fugit::ExtU64;
 use mutex_trait::prelude::*;
 
-use idsp::{Accu, Complex, ComplexExt, Filter, Lockin, Lowpass, Repeat, RPLL};
+use idsp::{Accu, Complex, ComplexExt, Filter, Lockin, Lowpass, RPLL};
 
 use stabilizer::{
+    dsp::{
+        self,
+        boxcar::Boxcar,
+        cascade::{Cascade, CascadeConfig},
+        dc_block::DcBlock,
+        lock_detect::LockDetector,
+        lockin::MultiLockin,
+        quadrature_null::QuadratureNull,
+        reference_gate::ReferenceGate,
+        synthetic_reference::SyntheticReference,
+        unwrap::PhaseUnwrapper,
+    },
     hardware::{
         self,
-        adc::{Adc0Input, Adc1Input, AdcCode},
+        adc::{Adc0Input, Adc1Input, AdcCalibration},
         afe::Gain,
         dac::{Dac0Output, Dac1Output, DacCode},
         hal,
         input_stamper::InputStamper,
+        output_stage::OutputStage,
         signal_generator,
-        timers::SamplingTimer,
+        timers::{CaptureResult, SamplingTimer, TimestampTimer},
         DigitalInput0, DigitalInput1, SerialTerminal, SystemTimer, Systick,
-        UsbDevice, AFE0, AFE1,
+        UsbDevice, Watchdog, AFE0, AFE1,
     },
     net::{
-        data_stream::{FrameGenerator, StreamFormat, StreamTarget},
+        data_stream::{
+            FrameGenerator, StreamFormat, StreamTarget, ALL_CHANNELS,
+            PROBE_CHANNEL,
+        },
         miniconf::Tree,
         serde::{Deserialize, Serialize},
         telemetry::{Telemetry, TelemetryBuffer},
@@ -71,13 +90,41 @@ const BATCH_SIZE: usize = 1 << BATCH_SIZE_LOG2;
 // period of 1.28 uS or 781.25 KHz.
 const SAMPLE_TICKS_LOG2: u32 = 7;
 const SAMPLE_TICKS: u32 = 1 << SAMPLE_TICKS_LOG2;
+const BATCH_PERIOD: f32 = (BATCH_SIZE as u32 * SAMPLE_TICKS) as f32
+    * hardware::design_parameters::TIMER_PERIOD;
+
+// The PLL is considered locked once its frequency estimate changed by less than 2^-12 relative
+// between batches for 64 consecutive batches, with a reference timestamp having been received
+// within the last 1024 batches (about 10 ms).
+const PLL_LOCK_TOLERANCE_LOG2: u32 = 12;
+const PLL_LOCK_COUNT: u32 = 64;
+const PLL_LOCK_TIMEOUT: u32 = 1024;
+
+// The maximum magnitude of the lockin harmonic. The reference frequency is at most the batch rate
+// (one reference period per batch, i.e. the internal reference), so the LO exceeds Nyquist for
+// larger harmonics.
+const MAX_HARMONIC: i32 = BATCH_SIZE as i32 / 2;
+
+// The maximum number of lockin low-pass sections.
+const LOCKIN_SECTIONS: usize = 4;
+
+// The number of harmonics of the reference streamed with `stream_harmonics`. Higher harmonics
+// exceed Nyquist, see [MAX_HARMONIC].
+const HARMONICS: usize = MAX_HARMONIC as usize;
+
+// The channel mask of the streamed harmonics.
+const HARMONIC_CHANNELS: u8 = (1 << HARMONICS) - 1;
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 enum Conf {
     /// Output the lockin magnitude.
     Magnitude,
+    /// Output the lockin amplitude, i.e. the square root of the magnitude.
+    Amplitude,
     /// Output the phase of the lockin
     Phase,
+    /// Output the unwrapped phase of the lockin with a full scale range of +-8 turns
+    UnwrappedPhase,
     /// Output the lockin reference frequency as a sinusoid
     ReferenceFrequency,
     /// Output the logarithmic power of the lockin
@@ -90,6 +137,22 @@ enum Conf {
     Modulation,
 }
 
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+enum Probe {
+    /// Stream the in-phase component of the lockin signal.
+    InPhase,
+    /// Stream the quadrature component of the lockin signal.
+    Quadrature,
+    /// Stream the phase of the lockin signal.
+    Phase,
+    /// Stream the reference phase of the batch.
+    ReferencePhase,
+    /// Stream the reference frequency.
+    ReferenceFrequency,
+    /// Stream the signal phase of the quadrature nulling loop, i.e. the negative LO rotation.
+    NullPhase,
+}
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
 enum LockinMode {
     /// Utilize an internally generated reference for demodulation
@@ -98,6 +161,15 @@ enum LockinMode {
     External,
 }
 
+// The paths of one-shot commands. They are not persisted in flash so that they do not fire again
+// at the next power-up.
+const ONE_SHOT_SETTINGS: &[&str] = &[
+    "/pll_reset",
+    "/stream_capture",
+    "/stream_trigger",
+    "/commit",
+];
+
 #[derive(Copy, Clone, Debug, Tree)]
 pub struct Settings {
     /// Configure the Analog Front End (AFE) gain.
@@ -112,6 +184,18 @@ pub struct Settings {
     #[tree]
     afe: [Gain; 2],
 
+    /// Configure the ADC offset and gain correction.
+    ///
+    /// # Path
+    /// `adc_calibration/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// See [AdcCalibration#miniconf]
+    #[tree]
+    adc_calibration: [AdcCalibration; 2],
+
     /// Specifies the operational mode of the lockin.
     ///
     /// # Path
@@ -121,33 +205,135 @@ pub struct Settings {
     /// One of the variants of [LockinMode] enclosed in double quotes.
     lockin_mode: LockinMode,
 
+    /// Specifies the input channel of the lockin.
+    ///
+    /// # Path
+    /// `input_channel`
+    ///
+    /// # Value
+    /// The index of the ADC demodulated by the lockin, either 0 or 1. Other values are rejected.
+    /// In dual-channel mode, it selects the channel of the quadrature nulling loop and the
+    /// stream probe.
+    input_channel: u8,
+
+    /// Specifies dual-channel demodulation.
+    ///
+    /// # Path
+    /// `lockin_dual`
+    ///
+    /// # Value
+    /// "true" to demodulate both ADC0 and ADC1 with the same LO, e.g. for balanced detection.
+    /// Each channel has its own lockin filter state. DAC0 and DAC1 then output the signal of ADC0
+    /// and ADC1 respectively. "false" demodulates `input_channel` only and both DACs output its
+    /// signal.
+    lockin_dual: bool,
+
     /// Specifis the PLL time constant.
     ///
     /// # Path
     /// `pll_tc/<n>`
     ///
-    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    /// * `<n>` specifies the frequency (0) or phase (1) settling time. `<n>` := [0, 1]
     ///
     /// # Value
-    /// The PLL time constant exponent (1-31).
+    /// The PLL time constant exponent (1-31). Out-of-range values are rejected.
+    ///
+    /// # Note
+    /// The time constant is expressed in timestamp timer ticks. A device specific sample rate
+    /// divider (see [stabilizer::hardware::setup::setup]) slows down the timestamp timer and thus
+    /// reduces the PLL bandwidth in Hz by the same factor.
     pll_tc: [u32; 2],
 
+    /// Specifies the plausible reference period band.
+    ///
+    /// # Path
+    /// `pll_period_limits`
+    ///
+    /// # Value
+    /// The minimum and maximum period of the external reference in timestamp timer ticks (10 ns
+    /// times the device sample rate divider).
+    /// Reference timestamps outside of this band are not used for the PLL and are counted in the
+    /// `pll_rejected` telemetry.
+    pll_period_limits: [u32; 2],
+
+    /// Forces the PLL to re-acquire lock.
+    ///
+    /// # Path
+    /// `pll_reset`
+    ///
+    /// # Value
+    /// Any value different from the current one resets the PLL and its lock detection to their
+    /// initial state, e.g. after a known reference interruption.
+    pll_reset: u32,
+
+    /// Replaces the external reference by a synthetic one for testing.
+    ///
+    /// # Path
+    /// `pll_test_period`
+    ///
+    /// # Value
+    /// The period of a synthetic reference in timestamp timer ticks. In external mode, the PLL is
+    /// then fed ideal timestamps of this period generated in firmware instead of the DI0
+    /// timestamps, e.g. to commission a device without reference hardware. The recovered
+    /// reference frequency can be compared to the injected one using the `ReferenceFrequency`
+    /// stream probe. Periods are at least one batch period. Zero uses the DI0 timestamps.
+    pll_test_period: u32,
+
     /// Specifies the lockin lowpass gains.
     ///
     /// # Path
     /// `lockin_k`
     ///
     /// # Value
-    /// The lockin low-pass coefficients. See [`idsp::Lowpass`] for determining them.
+    /// The lockin low-pass coefficients. See [`idsp::Lowpass`] for determining them. The
+    /// coefficients are relative to the batch rate, which is reduced by the device sample rate
+    /// divider.
     lockin_k: <Lowpass<2> as Filter>::Config,
 
+    /// Specifies the number of lockin low-pass sections.
+    ///
+    /// # Path
+    /// `lockin_sections`
+    ///
+    /// # Value
+    /// The number of second order low-pass sections configured by `lockin_k` that are applied in
+    /// series to I and Q, from 1 to [LOCKIN_SECTIONS]. Each section steepens the rolloff by 12 dB
+    /// per octave. Each additional section costs two [`idsp::Lowpass`] updates (I and Q) per
+    /// sample, about 20 CPU cycles or 50 ns per sample at 400 MHz.
+    lockin_sections: usize,
+
+    /// Specifies the lockin boxcar integration window.
+    ///
+    /// # Path
+    /// `lockin_boxcar`
+    ///
+    /// # Value
+    /// The number of reference periods over which the demodulated signal is averaged before it
+    /// is dumped (integrate-and-dump). The windows are aligned to the reference phase and the
+    /// output is updated once per window. This rejects all harmonics of the reference. Zero uses
+    /// the low-pass filter configured by `lockin_k` instead.
+    lockin_boxcar: u32,
+
+    /// Specifies the DC-block high-pass of the ADC inputs.
+    ///
+    /// # Path
+    /// `dc_block_tc`
+    ///
+    /// # Value
+    /// The log2 time constant of the DC estimate in samples, at most 31. The corner frequency of
+    /// the high-pass is about `f_s / (2 pi 2**dc_block_tc)`. Zero disables the DC-block.
+    dc_block_tc: u32,
+
     /// Specifies which harmonic to use for the lockin.
     ///
     /// # Path
     /// `lockin_harmonic`
     ///
     /// # Value
-    /// Harmonic index of the LO. -1 to _de_modulate the fundamental (complex conjugate)
+    /// Harmonic index of the LO. Negative values demodulate with the complex conjugate of the
+    /// reference, e.g. -1 to _de_modulate the fundamental. Zero is rejected. The magnitude is
+    /// clamped to [MAX_HARMONIC] beyond which the LO exceeds Nyquist for a reference at the batch
+    /// rate.
     lockin_harmonic: i32,
 
     /// Specifies the LO phase offset.
@@ -157,9 +343,29 @@ pub struct Settings {
     ///
     /// # Value
     /// Demodulation LO phase offset. Units are in terms of i32, where [i32::MIN] is equivalent to
-    /// -pi and [i32::MAX] is equivalent to +pi.
+    /// -pi and [i32::MAX] is equivalent to +pi, i.e. 360 / 2**32 degrees (about 8.4e-8 degrees)
+    /// per LSB.
+    ///
+    /// The offset rotates the demodulated complex signal by the negative offset. This can be used
+    /// to calibrate a fixed phase skew of the reference, e.g. to align the in-phase and quadrature
+    /// outputs on DAC0 and DAC1 with the signal. The rotation is part of the LO phase and costs
+    /// no processing time.
     lockin_phase: i32,
 
+    /// Specifies the time constant of the quadrature nulling loop.
+    ///
+    /// # Path
+    /// `lockin_null_tc`
+    ///
+    /// # Value
+    /// Zero disables the loop. Otherwise, an integrator rotates the LO phase each batch by the
+    /// phase of the demodulated signal divided by `2**lockin_null_tc`. This keeps the signal in
+    /// the in-phase component and nulls the quadrature component, e.g. for homodyne detection.
+    /// The accumulated rotation is added to `lockin_phase`. Its negative, the phase of the signal,
+    /// is reported as `lockin_null_phase` telemetry. The loop must be slower than the lockin low-pass
+    /// to be stable.
+    lockin_null_tc: u32,
+
     /// Specifies DAC output mode.
     ///
     /// # Path
@@ -172,6 +378,17 @@ pub struct Settings {
     #[tree]
     output_conf: [Conf; 2],
 
+    /// Specifies the gain of the reference frequency output.
+    ///
+    /// # Path
+    /// `discriminator_gain`
+    ///
+    /// # Value
+    /// The factor applied to the reference frequency before conversion to DAC codes. The output
+    /// saturates at the DAC limits. One DAC LSB corresponds to `f_s / (2^16 * gain)`, where `f_s`
+    /// is the sample rate, i.e. about 11.92 Hz for a gain of 1 at the default 781.25 kHz.
+    discriminator_gain: i32,
+
     /// Specifies the telemetry output period in seconds.
     ///
     /// # Path
@@ -181,6 +398,57 @@ pub struct Settings {
     /// Any non-zero value less than 65536.
     telemetry_period: u16,
 
+    /// Specifies the DAC output soft-start ramp.
+    ///
+    /// # Path
+    /// `soft_start`
+    ///
+    /// # Value
+    /// The number of batches over which the DAC outputs are ramped linearly from 0 V to their
+    /// computed value after the outputs are enabled at power-up. Zero disables the ramp. Changes
+    /// take effect at the next power-up.
+    soft_start: u32,
+
+    /// Specifies the DAC output limits.
+    ///
+    /// # Path
+    /// `dac_limit/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// The maximum output magnitude as a fraction of the DAC full scale [0, 1]. The DAC samples
+    /// are clamped to this range after all other processing, regardless of how they were computed.
+    #[tree]
+    dac_limit: [f32; 2],
+
+    /// Specifies the DAC output offsets.
+    ///
+    /// # Path
+    /// `dac_offset/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// A constant bias in volts added to the computed output before the soft-start ramp and
+    /// `dac_limit` are applied. Offsets beyond the DAC range saturate at full scale.
+    #[tree]
+    dac_offset: [f32; 2],
+
+    /// Specifies the DAC output slew-rate limits.
+    ///
+    /// # Path
+    /// `dac_slew_limit/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// The maximum change of the DAC output between consecutive samples in LSB. The limit bounds
+    /// the rate of change, not the level, and applies to the output after `dac_limit`. Zero
+    /// disables the limit.
+    #[tree]
+    dac_slew_limit: [u16; 2],
+
     /// Specifies the target for data livestreaming.
     ///
     /// # Path
@@ -189,26 +457,133 @@ pub struct Settings {
     /// # Value
     /// See [StreamTarget#miniconf]
     stream_target: StreamTarget,
+
+    /// Specifies whether data is streamed.
+    ///
+    /// # Path
+    /// `stream_enabled`
+    ///
+    /// # Value
+    /// "true" or "false". Data generated while the stream is disabled is discarded.
+    stream_enabled: bool,
+
+    /// Specifies the channels included in the data stream.
+    ///
+    /// # Path
+    /// `stream_channels`
+    ///
+    /// # Value
+    /// A bit mask of the streamed channels. Bits 0 through 3 correspond to ADC0, ADC1, DAC0, and
+    /// DAC1 respectively. Bit 4 adds the internal signal selected by `stream_probe`.
+    stream_channels: u8,
+
+    /// Specifies the internal signal streamed in the probe channel.
+    ///
+    /// # Path
+    /// `stream_probe`
+    ///
+    /// # Value
+    /// One of the variants of [Probe] enclosed in double quotes. The full `i32` value of the signal
+    /// at the end of each batch is streamed if bit 4 of `stream_channels` is set.
+    stream_probe: Probe,
+
+    /// Specifies whether the harmonic content of the input is streamed.
+    ///
+    /// # Path
+    /// `stream_harmonics`
+    ///
+    /// # Value
+    /// "true" to demodulate the first [HARMONICS] harmonics of the reference on the input channel
+    /// and stream their magnitudes in the [StreamFormat::LockinHarmonics] format instead of the
+    /// channels selected by `stream_channels`. The harmonics share the lockin low-pass filter and
+    /// are only demodulated while streamed. "false" streams the selected channels.
+    stream_harmonics: bool,
+
+    /// Specifies the number of batches in a one-shot stream capture.
+    ///
+    /// # Path
+    /// `stream_capture`
+    ///
+    /// # Value
+    /// The number of batches to stream after a capture is triggered, after which streaming stops.
+    /// Zero streams continuously. The remaining number of batches is reported as `stream_capture`
+    /// telemetry.
+    stream_capture: u32,
+
+    /// Triggers a one-shot stream capture.
+    ///
+    /// # Path
+    /// `stream_trigger`
+    ///
+    /// # Value
+    /// Any value different from the current one (re-)starts a capture of `stream_capture`
+    /// batches.
+    stream_trigger: u32,
+
+    /// Specifies the number of batches in each stream frame.
+    ///
+    /// # Path
+    /// `stream_frame_batches`
+    ///
+    /// # Value
+    /// The maximum number of batches sent in each UDP frame. Small values (e.g. 1) minimize the
+    /// stream latency, e.g. for real-time displays. Zero fills each frame completely, which
+    /// maximizes the throughput, e.g. for bulk captures.
+    stream_frame_batches: u8,
+
+    /// Persists the settings in flash.
+    ///
+    /// # Path
+    /// `commit`
+    ///
+    /// # Value
+    /// Any value different from the current one stores the current settings in flash. They are
+    /// restored at the next power-up. Storing may stall the network for several seconds while a
+    /// flash sector is erased.
+    commit: u32,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Self {
             afe: [Gain::G1; 2],
+            adc_calibration: [AdcCalibration::default(); 2],
 
             lockin_mode: LockinMode::External,
+            input_channel: 0,
+            lockin_dual: false,
 
             pll_tc: [21, 21], // frequency and phase settling time (log2 counter cycles)
+            pll_period_limits: [0, u32::MAX], // Accept all reference periods
+            pll_reset: 0,
+            pll_test_period: 0,
 
             lockin_k: [0x8_0000, -0x400_0000], // lockin lowpass gains
+            lockin_sections: 2,                // 4th order lowpass
+            lockin_boxcar: 0,                  // Use the lowpass
+            dc_block_tc: 0,                    // DC-block disabled
             lockin_harmonic: -1, // Harmonic index of the LO: -1 to _de_modulate the fundamental (complex conjugate)
             lockin_phase: 0,     // Demodulation LO phase offset
+            lockin_null_tc: 0,
 
             output_conf: [Conf::InPhase, Conf::Quadrature],
+            discriminator_gain: 1,
             // The default telemetry period in seconds.
             telemetry_period: 10,
+            soft_start: 0,
+            dac_limit: [1.; 2],
+            dac_offset: [0.; 2],
+            dac_slew_limit: [0; 2],
 
             stream_target: StreamTarget::default(),
+            stream_enabled: true,
+            stream_channels: ALL_CHANNELS,
+            stream_probe: Probe::InPhase,
+            stream_harmonics: false,
+            stream_capture: 0,
+            stream_trigger: 0,
+            stream_frame_batches: 0,
+            commit: 0,
         }
     }
 }
@@ -223,14 +598,16 @@ mod app {
     #[shared]
     struct Shared {
         usb: UsbDevice,
+        usb_terminal: SerialTerminal,
         network: NetworkUsers<Settings, Telemetry, 2>,
         settings: Settings,
         telemetry: TelemetryBuffer,
+        batch_timestamp: u32,
+        timestamp_timer: TimestampTimer,
     }
 
     #[local]
     struct Local {
-        usb_terminal: SerialTerminal,
         sampling_timer: SamplingTimer,
         digital_inputs: (DigitalInput0, DigitalInput1),
         timestamper: InputStamper,
@@ -238,10 +615,24 @@ mod app {
         adcs: (Adc0Input, Adc1Input),
         dacs: (Dac0Output, Dac1Output),
         pll: RPLL,
-        lockin: Lockin<Repeat<2, Lowpass<2>>>,
+        pll_lock: LockDetector,
+        reference_gate: ReferenceGate,
+        synthetic_reference: Option<SyntheticReference>,
+        pll_reset: u32,
+        lockin: [Lockin<Cascade<Lowpass<2>, LOCKIN_SECTIONS>>; 2],
+        harmonics: MultiLockin<Cascade<Lowpass<2>, LOCKIN_SECTIONS>, HARMONICS>,
+        lockin_k: CascadeConfig<<Lowpass<2> as Filter>::Config>,
+        boxcar: [Boxcar; 2],
+        dc_block: [DcBlock; 2],
+        phase_unwrapper: [PhaseUnwrapper; 2],
+        quadrature_null: QuadratureNull,
         signal_generator: signal_generator::SignalGenerator,
         generator: FrameGenerator,
+        capture: (u32, u32),
+        output_stage: OutputStage<2>,
+        batch_period: f32,
         cpu_temp_sensor: stabilizer::hardware::cpu_temp_sensor::CpuTempSensor,
+        watchdog: Watchdog,
     }
 
     #[init]
@@ -257,23 +648,44 @@ mod app {
             SAMPLE_TICKS,
         );
 
-        let settings = stabilizer.usb_serial.settings();
+        // Restore the run-time settings persisted in flash.
+        let mut settings = Settings::default();
+        stabilizer
+            .usb_serial
+            .platform_mut()
+            .load(env!("CARGO_BIN_NAME"), &mut settings);
+
+        // Fall back to no correction if a restored ADC calibration is invalid.
+        AdcCalibration::validate(
+            &mut settings.adc_calibration,
+            &[AdcCalibration::default(); 2],
+        );
+
+        let device = stabilizer.usb_serial.settings();
         let mut network = NetworkUsers::new(
             stabilizer.net.stack,
             stabilizer.net.phy,
             clock,
             env!("CARGO_BIN_NAME"),
-            &settings.broker,
-            &settings.id,
+            &device,
+            settings,
         );
+        let batch_period = BATCH_PERIOD * stabilizer.sample_divider as f32;
+        network
+            .telemetry
+            .set_sample_rate(BATCH_SIZE as f32 / batch_period);
 
-        let generator = network.configure_streaming(StreamFormat::AdcDacData);
+        let generator = network
+            .configure_streaming(StreamFormat::AdcDacTimestampData, BATCH_SIZE);
 
-        let shared = Shared {
+        let mut shared = Shared {
             network,
             usb: stabilizer.usb,
+            usb_terminal: stabilizer.usb_serial,
             telemetry: TelemetryBuffer::default(),
-            settings: Settings::default(),
+            settings,
+            batch_timestamp: 0,
+            timestamp_timer: stabilizer.timestamp_timer,
         };
 
         let signal_config = signal_generator::Config {
@@ -286,7 +698,6 @@ mod app {
         };
 
         let mut local = Local {
-            usb_terminal: stabilizer.usb_serial,
             sampling_timer: stabilizer.adc_dac_timer,
             digital_inputs: stabilizer.digital_inputs,
             afes: stabilizer.afes,
@@ -295,13 +706,34 @@ mod app {
             timestamper: stabilizer.timestamper,
 
             pll: RPLL::new(SAMPLE_TICKS_LOG2 + BATCH_SIZE_LOG2),
-            lockin: Lockin::default(),
+            pll_lock: LockDetector::new(
+                PLL_LOCK_TOLERANCE_LOG2,
+                PLL_LOCK_COUNT,
+                PLL_LOCK_TIMEOUT,
+            ),
+            reference_gate: ReferenceGate::new(settings.pll_period_limits),
+            synthetic_reference: None,
+            pll_reset: settings.pll_reset,
+            lockin: Default::default(),
+            harmonics: Default::default(),
+            lockin_k: CascadeConfig {
+                sections: settings.lockin_sections,
+                section: settings.lockin_k,
+            },
+            boxcar: Default::default(),
+            dc_block: [DcBlock::default(); 2],
+            phase_unwrapper: Default::default(),
+            quadrature_null: QuadratureNull::default(),
             signal_generator: signal_generator::SignalGenerator::new(
                 signal_config,
             ),
 
             generator,
+            capture: (0, 0),
+            output_stage: OutputStage::new(settings.soft_start),
+            batch_period,
             cpu_temp_sensor: stabilizer.temperature_sensor,
+            watchdog: stabilizer.watchdog,
         };
 
         // Enable ADC/DAC events
@@ -316,8 +748,13 @@ mod app {
         ethernet_link::spawn().unwrap();
         start::spawn_after(100.millis()).unwrap();
 
+        // Reset the device if the idle loop stalls.
+        local.watchdog.start(fugit::MillisDurationU32::millis(
+            hardware::WATCHDOG_TIMEOUT_MS,
+        ));
+
         // Start recording digital input timestamps.
-        stabilizer.timestamp_timer.start();
+        shared.timestamp_timer.start();
 
         // Enable the timestamper.
         local.timestamper.start();
@@ -325,10 +762,18 @@ mod app {
         (shared, local, init::Monotonics(stabilizer.systick))
     }
 
-    #[task(priority = 1, local=[sampling_timer])]
+    #[task(priority = 1, local=[sampling_timer], shared=[batch_timestamp, timestamp_timer])]
     fn start(c: start::Context) {
-        // Start sampling ADCs and DACs.
-        c.local.sampling_timer.start();
+        let sampling_timer = c.local.sampling_timer;
+
+        // Start sampling ADCs and DACs. The first batch starts with the first sampling period.
+        // Processing is locked out so that the timestamp is taken right before the start.
+        (c.shared.batch_timestamp, c.shared.timestamp_timer).lock(
+            |timestamp, timestamp_timer| {
+                *timestamp = timestamp_timer.get_counter();
+                sampling_timer.start();
+            },
+        );
     }
 
     /// Main DSP processing routine.
@@ -338,12 +783,15 @@ mod app {
     /// This is an implementation of a externally (DI0) referenced PLL lockin on the ADC0 signal.
     /// It outputs either I/Q or power/phase on DAC0/DAC1. Data is normalized to full scale.
     /// PLL bandwidth, filter bandwidth, slope, and x/y or power/phase post-filters are available.
-    #[task(binds=DMA1_STR4, shared=[settings, telemetry], local=[adcs, dacs, lockin, timestamper, pll, generator, signal_generator], priority=3)]
+    #[task(binds=DMA1_STR4, shared=[settings, telemetry, batch_timestamp], local=[adcs, dacs, lockin, harmonics, lockin_k, boxcar, dc_block, phase_unwrapper, quadrature_null, timestamper, pll, pll_lock, reference_gate, synthetic_reference, pll_reset, generator, signal_generator, capture, output_stage], priority=3)]
     #[link_section = ".itcm.process"]
     fn process(c: process::Context) {
+        let start = DWT::cycle_count();
+
         let process::SharedResources {
             settings,
             telemetry,
+            mut batch_timestamp,
         } = c.shared;
 
         let process::LocalResources {
@@ -351,66 +799,266 @@ mod app {
             adcs: (adc0, adc1),
             dacs: (dac0, dac1),
             pll,
+            pll_lock,
+            reference_gate,
+            synthetic_reference,
+            pll_reset,
             lockin,
+            harmonics,
+            lockin_k,
+            boxcar,
+            dc_block,
+            phase_unwrapper,
+            quadrature_null,
             signal_generator,
             generator,
+            capture,
+            output_stage,
         } = c.local;
 
+        // The timestamp of the first sampling period of the batch. Both the sampling and the
+        // timestamp timer count at the same rate and batches are acquired back to back (sampling
+        // restarts after ADC overruns are timestamped again), so the timestamp follows from the
+        // start of sampling. It does not depend on the interrupt latency.
+        let batch_timestamp = batch_timestamp.lock(|timestamp| {
+            let batch = *timestamp;
+            *timestamp = batch.wrapping_add(BATCH_SIZE as u32 * SAMPLE_TICKS);
+            batch
+        });
+
         (settings, telemetry).lock(|settings, telemetry| {
-            let (reference_phase, reference_frequency) =
-                match settings.lockin_mode {
-                    LockinMode::External => {
-                        let timestamp =
-                            timestamper.latest_timestamp().unwrap_or(None); // Ignore data from timer capture overflows.
-                        let (pll_phase, pll_frequency) = pll.update(
-                            timestamp.map(|t| t as i32),
-                            settings.pll_tc[0],
-                            settings.pll_tc[1],
-                        );
-                        (pll_phase, (pll_frequency >> BATCH_SIZE_LOG2) as i32)
-                    }
-                    LockinMode::Internal => {
-                        // Reference phase and frequency are known.
-                        (1i32 << 30, 1i32 << (32 - BATCH_SIZE_LOG2))
+            // Re-acquire the reference from scratch when requested.
+            if settings.pll_reset != *pll_reset {
+                *pll = RPLL::new(SAMPLE_TICKS_LOG2 + BATCH_SIZE_LOG2);
+                *pll_lock = LockDetector::new(
+                    PLL_LOCK_TOLERANCE_LOG2,
+                    PLL_LOCK_COUNT,
+                    PLL_LOCK_TIMEOUT,
+                );
+                *reference_gate =
+                    ReferenceGate::new(settings.pll_period_limits);
+                *pll_reset = settings.pll_reset;
+            }
+
+            let (reference_phase, reference_frequency) = match settings
+                .lockin_mode
+            {
+                LockinMode::External => {
+                    let timestamp = match settings.pll_test_period {
+                        0 => {
+                            *synthetic_reference = None;
+                            match timestamper.latest_timestamp_ext() {
+                                CaptureResult::Captured(t) => Some(t),
+                                // Ignore data from timer capture overflows.
+                                CaptureResult::Empty
+                                | CaptureResult::Overcapture(_) => None,
+                            }
+                        }
+                        // Generate ideal reference timestamps in firmware.
+                        period => {
+                            if synthetic_reference.map(|r| r.period())
+                                != Some(period)
+                            {
+                                *synthetic_reference =
+                                    Some(SyntheticReference::new(
+                                        period,
+                                        batch_timestamp,
+                                    ));
+                            }
+                            // Note(unwrap): The reference was created above.
+                            synthetic_reference.as_mut().unwrap().update(
+                                batch_timestamp.wrapping_add(
+                                    BATCH_SIZE as u32 * SAMPLE_TICKS,
+                                ),
+                            )
+                        }
+                    };
+
+                    // Reject timestamps of a glitching reference.
+                    reference_gate
+                        .set_period_limits(settings.pll_period_limits);
+                    let timestamp = reference_gate.update(timestamp);
+                    telemetry.pll_rejected = Some(reference_gate.rejected());
+                    telemetry.pll_overcaptures =
+                        Some(timestamper.overcaptures());
+
+                    // Characterize the reference interval jitter.
+                    let intervals = telemetry
+                        .pll_intervals
+                        .get_or_insert_with(Default::default);
+                    if let Some(period) = reference_gate.period() {
+                        intervals.update(period);
                     }
-                };
+
+                    let (pll_phase, pll_frequency) = pll.update(
+                        timestamp.map(|t| t as i32),
+                        settings.pll_tc[0],
+                        settings.pll_tc[1],
+                    );
+                    telemetry.pll_locked = Some(
+                        pll_lock.update(timestamp.is_some(), pll_frequency),
+                    );
+                    telemetry.pll_frequency = Some(pll_frequency);
+                    (pll_phase, (pll_frequency >> BATCH_SIZE_LOG2) as i32)
+                }
+                LockinMode::Internal => {
+                    telemetry.pll_locked = None;
+                    telemetry.pll_frequency = None;
+                    telemetry.pll_rejected = None;
+                    telemetry.pll_overcaptures = None;
+                    telemetry.pll_intervals = None;
+                    // Reference phase and frequency are known.
+                    (1i32 << 30, 1i32 << (32 - BATCH_SIZE_LOG2))
+                }
+            };
+
+            // Restart the lockin filters whenever the time constant or the order changes to avoid
+            // the transient of the stale filter state.
+            let lockin_config = CascadeConfig {
+                sections: settings.lockin_sections,
+                section: settings.lockin_k,
+            };
+            if lockin_config != *lockin_k {
+                *lockin = Default::default();
+                harmonics.reset();
+                *lockin_k = lockin_config;
+            }
 
             let sample_frequency =
                 reference_frequency.wrapping_mul(settings.lockin_harmonic);
-            let sample_phase = settings.lockin_phase.wrapping_add(
-                reference_phase.wrapping_mul(settings.lockin_harmonic),
-            );
+            let sample_phase = settings
+                .lockin_phase
+                .wrapping_add(quadrature_null.rotation())
+                .wrapping_add(
+                    reference_phase.wrapping_mul(settings.lockin_harmonic),
+                );
 
             (adc0, adc1, dac0, dac1).lock(|adc0, adc1, dac0, dac1| {
-                let adc_samples = [adc0, adc1];
+                let mut adc_samples = [adc0, adc1];
                 let mut dac_samples = [dac0, dac1];
 
                 // Preserve instruction and data ordering w.r.t. DMA flag access.
                 fence(Ordering::SeqCst);
 
-                let output: Complex<i32> = adc_samples[0]
-                    .iter()
-                    // Zip in the LO phase.
-                    .zip(Accu::new(sample_phase, sample_frequency))
-                    // Convert to signed, MSB align the ADC sample, update the Lockin (demodulate, filter)
-                    .map(|(&sample, phase)| {
-                        let s = (sample as i16 as i32) << 16;
-                        lockin.update(s, phase, &settings.lockin_k)
-                    })
-                    // Decimate
-                    .last()
-                    .unwrap()
-                    * 2; // Full scale assuming the 2f component is gone.
+                // Correct the ADC offset and gain errors.
+                for (samples, calibration) in
+                    adc_samples.iter_mut().zip(&settings.adc_calibration)
+                {
+                    calibration.apply(samples);
+                }
+
+                // Remove the DC component of the inputs.
+                if settings.dc_block_tc > 0 {
+                    for (samples, dc_block) in
+                        adc_samples.iter_mut().zip(dc_block.iter_mut())
+                    {
+                        for sample in samples.iter_mut() {
+                            *sample = dc_block
+                                .update(*sample as i16, settings.dc_block_tc)
+                                as u16;
+                        }
+                    }
+                }
+
+                // All channels are demodulated with the same LO.
+                let mut demodulate = |channel: usize| -> Complex<i32> {
+                    adc_samples[channel]
+                        .iter()
+                        // Zip in the LO phase and the reference phase.
+                        .zip(Accu::new(sample_phase, sample_frequency))
+                        .zip(Accu::new(reference_phase, reference_frequency))
+                        // Convert to signed, MSB align the ADC sample, update the Lockin
+                        // (demodulate, filter) or the boxcar (demodulate, integrate and dump)
+                        .map(|((&sample, phase), reference)| {
+                            let s = (sample as i16 as i32) << 16;
+                            match settings.lockin_boxcar {
+                                0 => lockin[channel].update(
+                                    s,
+                                    phase,
+                                    &lockin_config,
+                                ),
+                                periods => boxcar[channel]
+                                    .update(s, reference, phase, periods),
+                            }
+                        })
+                        // Decimate
+                        .last()
+                        .unwrap()
+                        * 2 // Full scale assuming the 2f component is gone.
+                };
+
+                let input = settings.input_channel as usize;
+                let output = demodulate(input);
+
+                // The output signal of each DAC channel.
+                let mut outputs = [output; 2];
+                if settings.lockin_dual {
+                    outputs[1 - input] = demodulate(1 - input);
+                }
+
+                // Demodulate the harmonics of the reference with the complex conjugate like the
+                // default `lockin_harmonic`.
+                let harmonic_magnitudes =
+                    settings.stream_harmonics.then(|| {
+                        adc_samples[input]
+                            .iter()
+                            .zip(Accu::new(
+                                reference_phase,
+                                reference_frequency,
+                            ))
+                            .map(|(&sample, reference)| {
+                                harmonics.update(
+                                    (sample as i16 as i32) << 16,
+                                    reference.wrapping_neg(),
+                                    &lockin_config,
+                                )
+                            })
+                            .last()
+                            .unwrap()
+                            .map(|output| (output * 2).abs_sqr())
+                    });
+
+                // Rotate the LO to null the quadrature component.
+                if settings.lockin_null_tc > 0 {
+                    quadrature_null.update(output, settings.lockin_null_tc);
+                    telemetry.lockin_null_phase = Some(quadrature_null.phase());
+                } else {
+                    *quadrature_null = Default::default();
+                    telemetry.lockin_null_phase = None;
+                }
+
+                // Track the phase continuously so that the unwrapped output is always valid.
+                let unwrapped_phase: [i64; 2] =
+                    core::array::from_fn(|channel| {
+                        phase_unwrapper[channel].update(outputs[channel].arg())
+                    });
+
+                // All outputs share the soft-start gain of the batch to stay consistent.
+                output_stage.update(
+                    &settings.dac_offset,
+                    &settings.dac_limit,
+                    &settings.dac_slew_limit,
+                );
 
                 // Convert to DAC data.
                 for (channel, samples) in dac_samples.iter_mut().enumerate() {
+                    let output = outputs[channel];
+                    let unwrapped_phase = unwrapped_phase[channel];
                     for sample in samples.iter_mut() {
                         let value = match settings.output_conf[channel] {
                             Conf::Magnitude => output.abs_sqr() as i32 >> 16,
+                            Conf::Amplitude => {
+                                (dsp::sqrt(output.abs_sqr() as u64) >> 1) as i32
+                            }
                             Conf::Phase => output.arg() >> 16,
+                            Conf::UnwrappedPhase => (unwrapped_phase >> 20)
+                                .clamp(i32::MIN as _, i32::MAX as _)
+                                as i32,
                             Conf::LogPower => output.log2() << 8,
                             Conf::ReferenceFrequency => {
-                                reference_frequency >> 16
+                                reference_frequency
+                                    .saturating_mul(settings.discriminator_gain)
+                                    >> 16
                             }
                             Conf::InPhase => output.re >> 16,
                             Conf::Quadrature => output.im >> 16,
@@ -420,36 +1068,127 @@ mod app {
                             }
                         };
 
+                        // Saturate to the DAC range rather than wrapping to the opposite rail
+                        // on transients.
+                        let value = value.clamp(i16::MIN as _, i16::MAX as _);
                         *sample = DacCode::from(value as i16).0;
                     }
+                    output_stage.apply(channel, &mut samples[..]);
+                }
+
+                generator.set_frame_batches(settings.stream_frame_batches);
+
+                // Start or stop a one-shot capture.
+                let request =
+                    (settings.stream_capture, settings.stream_trigger);
+                if request != *capture {
+                    generator.capture(
+                        (settings.stream_capture != 0)
+                            .then_some(settings.stream_capture),
+                    );
+                    *capture = request;
                 }
 
                 // Stream the data.
                 const N: usize = BATCH_SIZE * core::mem::size_of::<i16>()
                     / core::mem::size_of::<MaybeUninit<u8>>();
-                generator.add(|buf| {
-                    for (data, buf) in adc_samples
-                        .iter()
-                        .chain(dac_samples.iter())
-                        .zip(buf.chunks_exact_mut(N))
-                    {
-                        let data = unsafe {
-                            core::slice::from_raw_parts(
-                                data.as_ptr() as *const MaybeUninit<u8>,
-                                N,
+                // The lockin has no command channels.
+                let stream_channels =
+                    settings.stream_channels & (ALL_CHANNELS | PROBE_CHANNEL);
+                let probe = match settings.stream_probe {
+                    Probe::InPhase => output.re,
+                    Probe::Quadrature => output.im,
+                    Probe::Phase => output.arg(),
+                    Probe::ReferencePhase => reference_phase,
+                    Probe::ReferenceFrequency => reference_frequency,
+                    Probe::NullPhase => quadrature_null.phase(),
+                };
+                if let Some(magnitudes) = harmonic_magnitudes {
+                    generator.set_channels(HARMONIC_CHANNELS);
+                    generator.set_format(StreamFormat::LockinHarmonics);
+                    generator.add(|buf| {
+                        let len = 4 + HARMONICS * 4;
+
+                        // The generator drops a batch that exceeds the buffer.
+                        if buf.len() < len {
+                            return len;
+                        }
+
+                        for (byte, buf) in batch_timestamp
+                            .to_le_bytes()
+                            .into_iter()
+                            .chain(
+                                magnitudes.iter().flat_map(|m| m.to_le_bytes()),
                             )
+                            .zip(buf)
+                        {
+                            buf.write(byte);
+                        }
+                        len
+                    });
+                } else {
+                    generator.set_channels(stream_channels);
+                    generator.set_format(StreamFormat::AdcDacTimestampData);
+                    generator.add(|buf| {
+                        let channels = (stream_channels & ALL_CHANNELS)
+                            .count_ones()
+                            as usize;
+                        let probe_len = if stream_channels & PROBE_CHANNEL != 0
+                        {
+                            4
+                        } else {
+                            0
                         };
-                        buf.copy_from_slice(data)
-                    }
-                    N * 4
-                });
+                        let len = 4 + channels * N + probe_len;
+
+                        // The generator drops a batch that exceeds the buffer.
+                        if buf.len() < len {
+                            return len;
+                        }
+
+                        let (ts, buf) = buf.split_at_mut(4);
+                        for (byte, ts) in
+                            batch_timestamp.to_le_bytes().iter().zip(ts)
+                        {
+                            ts.write(*byte);
+                        }
+                        let (buf, probe_buf) = buf.split_at_mut(channels * N);
+                        for (data, buf) in adc_samples
+                            .iter()
+                            .chain(dac_samples.iter())
+                            .enumerate()
+                            .filter(|(i, _)| stream_channels & (1 << i) != 0)
+                            .map(|(_, data)| data)
+                            .zip(buf.chunks_exact_mut(N))
+                        {
+                            let data = unsafe {
+                                core::slice::from_raw_parts(
+                                    data.as_ptr() as *const MaybeUninit<u8>,
+                                    N,
+                                )
+                            };
+                            buf.copy_from_slice(data);
+                        }
+                        for (byte, buf) in probe
+                            .to_le_bytes()
+                            .iter()
+                            .zip(&mut probe_buf[..probe_len])
+                        {
+                            buf.write(*byte);
+                        }
+                        len
+                    });
+                }
 
                 // Update telemetry measurements.
-                telemetry.adcs =
-                    [AdcCode(adc_samples[0][0]), AdcCode(adc_samples[1][0])];
+                telemetry.stream_dropped = Some(generator.dropped());
+                telemetry.stream_capture = generator.capture_remaining();
+                telemetry.update_codes(&adc_samples, &dac_samples);
 
-                telemetry.dacs =
-                    [DacCode(dac_samples[0][0]), DacCode(dac_samples[1][0])];
+                // Track the processing time of the batch.
+                telemetry.update_process_cycles(
+                    DWT::cycle_count().wrapping_sub(start),
+                );
 
                 // Preserve instruction and data ordering w.r.t. DMA flag access.
                 fence(Ordering::SeqCst);
@@ -457,9 +1196,11 @@ mod app {
         });
     }
 
-    #[idle(shared=[network, usb])]
+    #[idle(shared=[network, usb], local=[watchdog])]
     fn idle(mut c: idle::Context) -> ! {
         loop {
+            c.local.watchdog.feed();
+
             match c.shared.network.lock(|net| net.update()) {
                 NetworkState::SettingsChanged(_path) => {
                     settings_update::spawn().unwrap()
@@ -478,22 +1219,111 @@ mod app {
         }
     }
 
-    #[task(priority = 1, local=[afes], shared=[network, settings])]
+    #[task(priority = 1, local=[afes], shared=[network, settings, usb_terminal])]
     fn settings_update(mut c: settings_update::Context) {
-        let settings = c.shared.network.lock(|net| *net.miniconf.settings());
-        c.shared.settings.lock(|current| *current = settings);
+        let mut settings =
+            c.shared.network.lock(|net| *net.miniconf.settings());
+
+        let commit = c.shared.settings.lock(|current| {
+            // The ADC gain corrections must be in range, keep the previous ones otherwise.
+            AdcCalibration::validate(
+                &mut settings.adc_calibration,
+                &current.adc_calibration,
+            );
+
+            // The PLL shifts must be in range, keep the previous ones otherwise.
+            if settings.pll_tc.iter().any(|tc| !(1..=31).contains(tc)) {
+                log::error!(
+                    "Invalid PLL time constants: {:?}",
+                    settings.pll_tc
+                );
+                settings.pll_tc = current.pll_tc;
+            }
+
+            // A zero harmonic does not demodulate, keep the previous one.
+            if settings.lockin_harmonic == 0 {
+                log::error!("Invalid lockin harmonic: 0");
+                settings.lockin_harmonic = current.lockin_harmonic;
+            }
+            settings.lockin_harmonic =
+                settings.lockin_harmonic.clamp(-MAX_HARMONIC, MAX_HARMONIC);
+
+            // The number of lockin low-pass sections must be in range, keep the previous one
+            // otherwise.
+            if !(1..=LOCKIN_SECTIONS).contains(&settings.lockin_sections) {
+                log::error!(
+                    "Invalid lockin low-pass section count: {}",
+                    settings.lockin_sections
+                );
+                settings.lockin_sections = current.lockin_sections;
+            }
+
+            // Only ADC0 and ADC1 exist, keep the previous input otherwise.
+            if settings.input_channel > 1 {
+                log::error!(
+                    "Invalid lockin input channel: {}",
+                    settings.input_channel
+                );
+                settings.input_channel = current.input_channel;
+            }
+
+            // The synthetic reference reports at most one edge per batch.
+            if settings.pll_test_period != 0 {
+                settings.pll_test_period = settings
+                    .pll_test_period
+                    .max(BATCH_SIZE as u32 * SAMPLE_TICKS);
+            }
+
+            let commit = settings.commit != current.commit;
+            *current = settings;
+            commit
+        });
 
         c.local.afes.0.set_gain(settings.afe[0]);
         c.local.afes.1.set_gain(settings.afe[1]);
 
         let target = settings.stream_target.into();
-        c.shared.network.lock(|net| net.direct_stream(target));
+        c.shared.network.lock(|net| {
+            net.direct_stream(target);
+            net.enable_stream(settings.stream_enabled);
+            if settings.stream_harmonics {
+                net.select_stream_channels(HARMONIC_CHANNELS);
+                net.select_stream_format(StreamFormat::LockinHarmonics);
+            } else {
+                net.select_stream_channels(
+                    settings.stream_channels & (ALL_CHANNELS | PROBE_CHANNEL),
+                );
+                net.select_stream_format(StreamFormat::AdcDacTimestampData);
+            }
+        });
+
+        // Persist the settings in flash on an explicit commit.
+        if commit {
+            c.shared.usb_terminal.lock(|terminal| {
+                terminal
+                    .platform_mut()
+                    .store(env!("CARGO_BIN_NAME"), &settings, ONE_SHOT_SETTINGS)
+                    .map_err(|e| {
+                        log::error!("Failed to persist settings: {:?}", e)
+                    })
+                    .ok();
+            });
+        }
     }
 
-    #[task(priority = 1, local=[digital_inputs, cpu_temp_sensor], shared=[network, settings, telemetry])]
+    #[task(priority = 1, local=[digital_inputs, batch_period, cpu_temp_sensor], shared=[network, settings, telemetry])]
     fn telemetry(mut c: telemetry::Context) {
+        // The reference interval statistics and the processing time cover a single telemetry
+        // period.
         let mut telemetry: TelemetryBuffer =
-            c.shared.telemetry.lock(|telemetry| *telemetry);
+            c.shared.telemetry.lock(|telemetry| {
+                let current = *telemetry;
+                if let Some(intervals) = telemetry.pll_intervals.as_mut() {
+                    *intervals = Default::default();
+                }
+                telemetry.process_cycles = None;
+                current
+            });
 
         telemetry.digital_inputs = [
             c.local.digital_inputs.0.is_high(),
@@ -505,12 +1335,20 @@ mod app {
             .settings
             .lock(|settings| (settings.afe, settings.telemetry_period));
 
+        // Convert the PLL frequency from turns per batch to Hz.
+        let pll_frequency = telemetry.pll_frequency.map(|frequency| {
+            frequency as f32 / (1u64 << 32) as f32 / *c.local.batch_period
+        });
+
         c.shared.network.lock(|net| {
-            net.telemetry.publish(&telemetry.finalize(
+            telemetry.streaming = Some(net.is_streaming());
+            let mut telemetry = telemetry.finalize(
                 gains[0],
                 gains[1],
                 c.local.cpu_temp_sensor.get_temperature().unwrap(),
-            ))
+            );
+            telemetry.pll_frequency = pll_frequency;
+            net.telemetry.publish(&telemetry)
         });
 
         // Schedule the telemetry task in the future.
@@ -518,14 +1356,16 @@ mod app {
             .unwrap();
     }
 
-    #[task(priority = 1, shared=[usb], local=[usb_terminal])]
+    #[task(priority = 1, shared=[usb, usb_terminal])]
     fn usb(mut c: usb::Context) {
         // Handle the USB serial terminal.
-        c.shared.usb.lock(|usb| {
-            usb.poll(&mut [c.local.usb_terminal.interface_mut().inner_mut()]);
-        });
+        c.shared.usb_terminal.lock(|terminal| {
+            c.shared.usb.lock(|usb| {
+                usb.poll(&mut [terminal.interface_mut().inner_mut()]);
+            });
 
-        c.local.usb_terminal.process().unwrap();
+            terminal.process().unwrap();
+        });
 
         // Schedule to run this task every 10 milliseconds.
         usb::spawn_after(10u64.millis()).unwrap();
@@ -541,4 +1381,29 @@ mod app {
     fn eth(_: eth::Context) {
         unsafe { hal::ethernet::interrupt_handler() }
     }
+
+    // The ADC error handlers run at the priority of `process()` so that an overrun recovery never
+    // preempts the processing of a batch. Sampling restarts at the end of the recovery, so the
+    // timestamp of the next batch is taken right after it.
+    #[task(binds = SPI2, priority = 3, shared=[batch_timestamp, timestamp_timer])]
+    fn spi2(c: spi2::Context) {
+        if Adc0Input::handle_error() {
+            (c.shared.batch_timestamp, c.shared.timestamp_timer).lock(
+                |timestamp, timestamp_timer| {
+                    *timestamp = timestamp_timer.get_counter();
+                },
+            );
+        }
+    }
+
+    #[task(binds = SPI3, priority = 3, shared=[batch_timestamp, timestamp_timer])]
+    fn spi3(c: spi3::Context) {
+        if Adc1Input::handle_error() {
+            (c.shared.batch_timestamp, c.shared.timestamp_timer).lock(
+                |timestamp, timestamp_timer| {
+                    *timestamp = timestamp_timer.get_counter();
+                },
+            );
+        }
+    }
 }
@@ -0,0 +1,109 @@
+//! # PWM Generator
+//!
+//! The `pwm-gen` application is a test bench for the timer PWM output mode. It continuously sweeps
+//! the duty cycle of the spare shadow sampling timer channels (TIM3 CH3 on PB0, TIM3 CH4 on PB1)
+//! from 0% up to one timer tick short of 100%.
+//!
+//! ## Features
+//! * PWM period of one ADC/DAC sample period
+//! * Complementary sweep: CH3 is active-high and CH4 is active-low
+//! * Full sweep in [SWEEP_STEPS] steps of [SWEEP_INTERVAL_MS] milliseconds
+#![deny(warnings)]
+#![no_std]
+#![no_main]
+
+use fugit::ExtU64;
+
+use stabilizer::hardware::{
+    self,
+    setup::PwmOutputs,
+    timers::{Polarity, SamplingTimer},
+    SystemTimer, Systick,
+};
+
+// The number of samples in each batch process
+const BATCH_SIZE: usize = 8;
+
+// The logarithm of the number of 100MHz timer ticks between each sample. With a value of 2^7 =
+// 128, there is 1.28uS per sample, corresponding to a PWM frequency of 781.25 KHz.
+const SAMPLE_TICKS_LOG2: u8 = 7;
+const SAMPLE_TICKS: u32 = 1 << SAMPLE_TICKS_LOG2;
+
+/// The number of duty cycle steps in a full sweep.
+pub const SWEEP_STEPS: u16 = SAMPLE_TICKS as u16;
+
+/// The time between duty cycle steps in milliseconds.
+pub const SWEEP_INTERVAL_MS: u64 = 10;
+
+#[rtic::app(device = stabilizer::hardware::hal::stm32, peripherals = true, dispatchers=[DCMI])]
+mod app {
+    use super::*;
+
+    #[monotonic(binds = SysTick, default = true, priority = 2)]
+    type Monotonic = Systick;
+
+    #[shared]
+    struct Shared {}
+
+    #[local]
+    struct Local {
+        sampling_timer: SamplingTimer,
+        pwm_outputs: PwmOutputs,
+        duty: u16,
+    }
+
+    #[init]
+    fn init(c: init::Context) -> (Shared, Local, init::Monotonics) {
+        let clock = SystemTimer::new(|| monotonics::now().ticks() as u32);
+
+        // Configure the microcontroller
+        let (stabilizer, _pounder) = hardware::setup::setup(
+            c.core,
+            c.device,
+            clock,
+            BATCH_SIZE,
+            SAMPLE_TICKS,
+        );
+
+        let local = Local {
+            sampling_timer: stabilizer.adc_dac_timer,
+            pwm_outputs: stabilizer.pwm_outputs,
+            duty: 0,
+        };
+
+        local.pwm_outputs.ch3.to_pwm(0, Polarity::ActiveHigh);
+        local.pwm_outputs.ch4.to_pwm(0, Polarity::ActiveLow);
+
+        start::spawn_after(100.millis()).unwrap();
+
+        (Shared {}, local, init::Monotonics(stabilizer.systick))
+    }
+
+    #[task(priority = 1, local=[sampling_timer])]
+    fn start(c: start::Context) {
+        // Start the sampling timer. The shadow sampling timer generating the PWM outputs is
+        // slaved to it.
+        c.local.sampling_timer.start();
+        sweep::spawn().unwrap();
+    }
+
+    #[task(priority = 1, local=[pwm_outputs, duty])]
+    fn sweep(c: sweep::Context) {
+        let duty = *c.local.duty;
+        c.local.pwm_outputs.ch3.to_pwm(duty, Polarity::ActiveHigh);
+        c.local.pwm_outputs.ch4.to_pwm(duty, Polarity::ActiveLow);
+
+        // The compare value ranges from 0 (always inactive) to the auto-reload value
+        // `SAMPLE_TICKS - 1`, which is active for all but the last tick of the period.
+        *c.local.duty = (duty + 1) % SWEEP_STEPS;
+
+        sweep::spawn_after(SWEEP_INTERVAL_MS.millis()).unwrap();
+    }
+
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::wfi();
+        }
+    }
+}
@@ -0,0 +1,545 @@
+//! # Signal Generator
+//!
+//! The Signal Generator application turns Stabilizer into a two-channel waveform generator. The
+//! ADC inputs are ignored and the DAC outputs are driven with the configured waveforms.
+//!
+//! ## Features
+//! * Two independent channels
+//! * Cosine, square, triangle/ramp and white noise waveforms
+//! * Run-time configuration of waveform, frequency, symmetry, amplitude, phase and offset
+//! * Frequency and amplitude control of the Pounder DDS outputs, if Pounder is present
+//!
+//! ## Settings
+//! Refer to the [Settings] structure for documentation of run-time configurable settings for this
+//! application.
+//!
+//! ## Telemetry
+//! Refer to [Telemetry] for information about telemetry reported by this application.
+#![deny(warnings)]
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{fence, Ordering};
+
+use fugit::ExtU64;
+use mutex_trait::prelude::*;
+
+use stabilizer::{
+    hardware::{
+        self,
+        adc::{Adc0Input, Adc1Input},
+        afe::Gain,
+        dac::{Dac0Output, Dac1Output, DacCode},
+        design_parameters, hal,
+        output_stage::OutputStage,
+        pounder::dds_output::DdsOutput,
+        signal_generator::{self, SignalGenerator},
+        timers::SamplingTimer,
+        DigitalInput0, DigitalInput1, SerialTerminal, SystemTimer, Systick,
+        UsbDevice, Watchdog,
+    },
+    net::{
+        miniconf::Tree,
+        telemetry::{Telemetry, TelemetryBuffer},
+        NetworkState, NetworkUsers,
+    },
+};
+
+// The number of samples in each batch process
+const BATCH_SIZE: usize = 8;
+
+// The logarithm of the number of 100MHz timer ticks between each sample. With a value of 2^7 =
+// 128, there is 1.28uS per sample, corresponding to a sampling frequency of 781.25 KHz.
+const SAMPLE_TICKS_LOG2: u8 = 7;
+const SAMPLE_TICKS: u32 = 1 << SAMPLE_TICKS_LOG2;
+const SAMPLE_PERIOD: f32 =
+    SAMPLE_TICKS as f32 * hardware::design_parameters::TIMER_PERIOD;
+
+// The paths of one-shot commands. They are not persisted in flash so that they do not fire again
+// at the next power-up.
+const ONE_SHOT_SETTINGS: &[&str] = &["/commit"];
+
+#[derive(Clone, Copy, Debug, Tree)]
+pub struct Settings {
+    /// Specifies the waveforms generated on DAC0/DAC1.
+    ///
+    /// # Path
+    /// `signal_generator/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// See [signal_generator::BasicConfig#miniconf]. A ramp is generated by a triangle with a
+    /// symmetry of 0 or 1.
+    #[tree(depth(2))]
+    signal_generator: [signal_generator::BasicConfig; 2],
+
+    /// Specifies the DC offset added to the DAC0/DAC1 waveforms.
+    ///
+    /// # Path
+    /// `offset/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// The offset in volts. Out-of-range values are rejected. The sum of the waveform and the
+    /// offset saturates at the DAC full scale.
+    #[tree]
+    offset: [f32; 2],
+
+    /// Specifies the telemetry output period in seconds.
+    ///
+    /// # Path
+    /// `telemetry_period`
+    ///
+    /// # Value
+    /// Any non-zero value less than 65536.
+    telemetry_period: u16,
+
+    /// Specifies the DAC output soft-start ramp.
+    ///
+    /// # Path
+    /// `soft_start`
+    ///
+    /// # Value
+    /// The number of batches over which the DAC outputs are ramped linearly from 0 V to their
+    /// computed value after the outputs are enabled at power-up. Zero disables the ramp. Changes
+    /// take effect at the next power-up.
+    soft_start: u32,
+
+    /// Specifies the DAC output limits.
+    ///
+    /// # Path
+    /// `dac_limit/<n>`
+    ///
+    /// * `<n>` specifies which channel to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// The maximum output magnitude as a fraction of the DAC full scale [0, 1]. The DAC samples
+    /// are clamped to this range after all other processing, regardless of how they were computed.
+    #[tree]
+    dac_limit: [f32; 2],
+
+    /// Specifies the frequency of the Pounder DDS outputs OUT0/OUT1.
+    ///
+    /// # Path
+    /// `pounder_freq/<n>`
+    ///
+    /// * `<n>` specifies which output to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// The output frequency in Hertz, up to the DDS system clock frequency (500 MHz). Ignored if
+    /// Pounder is not present.
+    #[tree]
+    pounder_freq: [f32; 2],
+
+    /// Specifies the amplitude of the Pounder DDS outputs OUT0/OUT1.
+    ///
+    /// # Path
+    /// `pounder_amplitude/<n>`
+    ///
+    /// * `<n>` specifies which output to configure. `<n>` := [0, 1]
+    ///
+    /// # Value
+    /// The amplitude normalized to the DDS full scale [0, 1]. The output power is further
+    /// reduced by the Pounder output attenuators. Ignored if Pounder is not present.
+    #[tree]
+    pounder_amplitude: [f32; 2],
+
+    /// Persists the settings in flash.
+    ///
+    /// # Path
+    /// `commit`
+    ///
+    /// # Value
+    /// Any value different from the current one stores the current settings in flash. They are
+    /// restored at the next power-up. Storing may stall the network for several seconds while a
+    /// flash sector is erased.
+    commit: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            signal_generator: [signal_generator::BasicConfig::default(); 2],
+            offset: [0.; 2],
+            // The default telemetry period in seconds.
+            telemetry_period: 10,
+            soft_start: 0,
+            dac_limit: [1.; 2],
+            pounder_freq: [10.0e6; 2],
+            pounder_amplitude: [0.; 2],
+            commit: 0,
+        }
+    }
+}
+
+#[rtic::app(device = stabilizer::hardware::hal::stm32, peripherals = true, dispatchers=[DCMI, JPEG, LTDC, SDMMC])]
+mod app {
+    use super::*;
+
+    #[monotonic(binds = SysTick, default = true, priority = 2)]
+    type Monotonic = Systick;
+
+    #[shared]
+    struct Shared {
+        usb: UsbDevice,
+        usb_terminal: SerialTerminal,
+        network: NetworkUsers<Settings, Telemetry, 3>,
+
+        settings: Settings,
+        telemetry: TelemetryBuffer,
+        signal_generator: [SignalGenerator; 2],
+    }
+
+    #[local]
+    struct Local {
+        sampling_timer: SamplingTimer,
+        digital_inputs: (DigitalInput0, DigitalInput1),
+        adcs: (Adc0Input, Adc1Input),
+        dacs: (Dac0Output, Dac1Output),
+        sample_period: f32,
+        dds_output: Option<DdsOutput>,
+        output_stage: OutputStage<2>,
+        cpu_temp_sensor: stabilizer::hardware::cpu_temp_sensor::CpuTempSensor,
+        watchdog: Watchdog,
+    }
+
+    #[init]
+    fn init(c: init::Context) -> (Shared, Local, init::Monotonics) {
+        let clock = SystemTimer::new(|| monotonics::now().ticks() as u32);
+
+        // Configure the microcontroller
+        let (mut stabilizer, pounder) = hardware::setup::setup(
+            c.core,
+            c.device,
+            clock,
+            BATCH_SIZE,
+            SAMPLE_TICKS,
+        );
+
+        // The sample period including the device specific sample rate divider.
+        let sample_period = SAMPLE_PERIOD * stabilizer.sample_divider as f32;
+
+        // Restore the run-time settings persisted in flash.
+        let mut settings = Settings::default();
+        stabilizer
+            .usb_serial
+            .platform_mut()
+            .load(env!("CARGO_BIN_NAME"), &mut settings);
+
+        let device = stabilizer.usb_serial.settings();
+        let mut network = NetworkUsers::new(
+            stabilizer.net.stack,
+            stabilizer.net.phy,
+            clock,
+            env!("CARGO_BIN_NAME"),
+            &device,
+            settings,
+        );
+        network.telemetry.set_sample_rate(1. / sample_period);
+
+        let shared = Shared {
+            usb: stabilizer.usb,
+            usb_terminal: stabilizer.usb_serial,
+            network,
+            // The initial settings update validates the restored settings against the defaults.
+            settings: Settings::default(),
+            telemetry: TelemetryBuffer::default(),
+            // The waveforms are configured by the initial settings update.
+            signal_generator: [
+                SignalGenerator::new(signal_generator::Config::default()),
+                SignalGenerator::new(signal_generator::Config::default()),
+            ],
+        };
+
+        let mut local = Local {
+            sampling_timer: stabilizer.adc_dac_timer,
+            digital_inputs: stabilizer.digital_inputs,
+            adcs: stabilizer.adcs,
+            dacs: stabilizer.dacs,
+            sample_period,
+            dds_output: pounder.map(|pounder| pounder.dds_output),
+            output_stage: OutputStage::new(settings.soft_start),
+            cpu_temp_sensor: stabilizer.temperature_sensor,
+            watchdog: stabilizer.watchdog,
+        };
+
+        // Enable ADC/DAC events. The ADC batches time the DAC updates.
+        local.adcs.0.start();
+        local.adcs.1.start();
+        local.dacs.0.start();
+        local.dacs.1.start();
+
+        // Spawn a settings update for default settings.
+        settings_update::spawn().unwrap();
+        telemetry::spawn().unwrap();
+        ethernet_link::spawn().unwrap();
+        usb::spawn().unwrap();
+        start::spawn_after(100.millis()).unwrap();
+
+        // Reset the device if the idle loop stalls.
+        local.watchdog.start(fugit::MillisDurationU32::millis(
+            hardware::WATCHDOG_TIMEOUT_MS,
+        ));
+
+        (shared, local, init::Monotonics(stabilizer.systick))
+    }
+
+    #[task(priority = 1, local=[sampling_timer])]
+    fn start(c: start::Context) {
+        // Start sampling ADCs and DACs.
+        c.local.sampling_timer.start();
+    }
+
+    /// Main DSP processing routine.
+    ///
+    /// # Note
+    /// The ADC samples are not used. The ADC batch completion only paces the generation of the
+    /// next DAC output batch, which must be completed before the DAC has finished the current one.
+    #[task(binds=DMA1_STR4, local=[digital_inputs, adcs, dacs, output_stage], shared=[settings, signal_generator, telemetry], priority=3)]
+    #[link_section = ".itcm.process"]
+    fn process(c: process::Context) {
+        let process::SharedResources {
+            settings,
+            signal_generator,
+            telemetry,
+        } = c.shared;
+
+        let process::LocalResources {
+            digital_inputs,
+            adcs: (adc0, adc1),
+            dacs: (dac0, dac1),
+            output_stage,
+        } = c.local;
+
+        (settings, signal_generator, telemetry).lock(
+            |settings, signal_generator, telemetry| {
+                telemetry.digital_inputs =
+                    [digital_inputs.0.is_high(), digital_inputs.1.is_high()];
+
+                (adc0, adc1, dac0, dac1).lock(|adc0, adc1, dac0, dac1| {
+                    let adc_samples = [adc0, adc1];
+                    let dac_samples = [dac0, dac1];
+
+                    // Preserve instruction and data ordering w.r.t. DMA flag access.
+                    fence(Ordering::SeqCst);
+
+                    // Both channels share the soft-start gain of the batch. The waveforms are
+                    // not slew-rate limited.
+                    output_stage.update(
+                        &settings.offset,
+                        &settings.dac_limit,
+                        &[0; 2],
+                    );
+
+                    for channel in 0..dac_samples.len() {
+                        dac_samples[channel]
+                            .iter_mut()
+                            .zip(&mut signal_generator[channel])
+                            .map(|(di, signal)| {
+                                *di = DacCode::from(signal).0;
+                            })
+                            .last();
+                        output_stage
+                            .apply(channel, &mut dac_samples[channel][..]);
+                    }
+
+                    // Update telemetry measurements.
+                    telemetry.update_codes(&adc_samples, &dac_samples);
+
+                    // Preserve instruction and data ordering w.r.t. DMA flag access.
+                    fence(Ordering::SeqCst);
+                });
+            },
+        );
+    }
+
+    #[idle(shared=[network, usb], local=[watchdog])]
+    fn idle(mut c: idle::Context) -> ! {
+        loop {
+            c.local.watchdog.feed();
+
+            match c.shared.network.lock(|net| net.update()) {
+                NetworkState::SettingsChanged(_path) => {
+                    settings_update::spawn().unwrap()
+                }
+                NetworkState::Updated => {}
+                NetworkState::NoChange => {
+                    // We can't sleep if USB is not in suspend.
+                    if c.shared.usb.lock(|usb| {
+                        usb.state()
+                            == usb_device::device::UsbDeviceState::Suspend
+                    }) {
+                        cortex_m::asm::wfi();
+                    }
+                }
+            }
+        }
+    }
+
+    #[task(priority = 1, local=[sample_period, dds_output], shared=[network, settings, usb_terminal, signal_generator])]
+    fn settings_update(mut c: settings_update::Context) {
+        let mut settings =
+            c.shared.network.lock(|net| *net.miniconf.settings());
+        let current = c.shared.settings.lock(|current| *current);
+
+        // Update the signal generators. Keep the current waveform if a new one is rejected so
+        // that it is not persisted.
+        for (i, config) in settings.signal_generator.iter_mut().enumerate() {
+            match config
+                .try_into_config(*c.local.sample_period, DacCode::FULL_SCALE)
+            {
+                Ok(config) => {
+                    c.shared
+                        .signal_generator
+                        .lock(|generator| generator[i].update_waveform(config));
+                }
+                Err(err) => {
+                    log::error!(
+                        "Failed to update signal generation on DAC{}: {:?}",
+                        i,
+                        err
+                    );
+                    *config = current.signal_generator[i];
+                }
+            }
+        }
+
+        // Keep the current offsets if the new ones are out of range.
+        for (i, volts) in settings.offset.iter_mut().enumerate() {
+            if DacCode::try_from(*volts).is_err() {
+                log::error!("Offset {} V on DAC{} out of range", volts, i);
+                *volts = current.offset[i];
+            }
+        }
+
+        // Update the Pounder DDS outputs. An invalid configuration is rejected even if Pounder is
+        // not present and the current one is kept.
+        let system_clock = design_parameters::DDS_SYSTEM_CLK.to_Hz() as f32;
+        let channels = [
+            hardware::pounder::Channel::Out0,
+            hardware::pounder::Channel::Out1,
+        ];
+        for (i, channel) in channels.into_iter().enumerate() {
+            let ftw = ad9959::frequency_to_ftw(
+                settings.pounder_freq[i],
+                system_clock,
+            );
+            let acr = ad9959::amplitude_to_acr(settings.pounder_amplitude[i]);
+            match (ftw, acr) {
+                (Ok(ftw), Ok(acr)) => {
+                    if let Some(dds_output) = c.local.dds_output {
+                        dds_output
+                            .builder()
+                            .update_channels(
+                                channel.into(),
+                                Some(ftw),
+                                None,
+                                Some(acr),
+                            )
+                            .write();
+                    }
+                }
+                _ => {
+                    log::error!(
+                        "Invalid Pounder DDS configuration on OUT{}",
+                        i
+                    );
+                    settings.pounder_freq[i] = current.pounder_freq[i];
+                    settings.pounder_amplitude[i] =
+                        current.pounder_amplitude[i];
+                }
+            }
+        }
+
+        let commit = c.shared.settings.lock(|current| {
+            let commit = settings.commit != current.commit;
+            *current = settings;
+            commit
+        });
+
+        // Persist the settings in flash on an explicit commit.
+        if commit {
+            c.shared.usb_terminal.lock(|terminal| {
+                terminal
+                    .platform_mut()
+                    .store(env!("CARGO_BIN_NAME"), &settings, ONE_SHOT_SETTINGS)
+                    .map_err(|e| {
+                        log::error!("Failed to persist settings: {:?}", e)
+                    })
+                    .ok();
+            });
+        }
+    }
+
+    #[task(priority = 1, shared=[network, settings, telemetry], local=[cpu_temp_sensor])]
+    fn telemetry(mut c: telemetry::Context) {
+        let telemetry: TelemetryBuffer =
+            c.shared.telemetry.lock(|telemetry| *telemetry);
+
+        let telemetry_period =
+            c.shared.settings.lock(|settings| settings.telemetry_period);
+
+        // The ADC inputs are not used and the AFE gains are not configured.
+        c.shared.network.lock(|net| {
+            net.telemetry.publish(&telemetry.finalize(
+                Gain::G1,
+                Gain::G1,
+                c.local.cpu_temp_sensor.get_temperature().unwrap(),
+            ))
+        });
+
+        // Schedule the telemetry task in the future.
+        telemetry::Monotonic::spawn_after((telemetry_period as u64).secs())
+            .unwrap();
+    }
+
+    #[task(priority = 1, shared=[usb, usb_terminal])]
+    fn usb(mut c: usb::Context) {
+        // Handle the USB serial terminal.
+        c.shared.usb_terminal.lock(|terminal| {
+            c.shared.usb.lock(|usb| {
+                usb.poll(&mut [terminal.interface_mut().inner_mut()]);
+            });
+
+            terminal.process().unwrap();
+        });
+
+        // Schedule to run this task every 10 milliseconds.
+        usb::spawn_after(10u64.millis()).unwrap();
+    }
+
+    #[task(priority = 1, shared=[network])]
+    fn ethernet_link(mut c: ethernet_link::Context) {
+        c.shared.network.lock(|net| net.processor.handle_link());
+        ethernet_link::Monotonic::spawn_after(1.secs()).unwrap();
+    }
+
+    #[task(binds = ETH, priority = 1)]
+    fn eth(_: eth::Context) {
+        unsafe { hal::ethernet::interrupt_handler() }
+    }
+
+    // The ADC error handlers run at the priority of `process()` so that an overrun recovery never
+    // preempts the processing of a batch.
+    #[task(binds = SPI2, priority = 3)]
+    fn spi2(_: spi2::Context) {
+        Adc0Input::handle_error();
+    }
+
+    #[task(binds = SPI3, priority = 3)]
+    fn spi3(_: spi3::Context) {
+        Adc1Input::handle_error();
+    }
+
+    #[task(binds = SPI4, priority = 4)]
+    fn spi4(_: spi4::Context) {
+        panic!("DAC0 SPI error");
+    }
+
+    #[task(binds = SPI5, priority = 4)]
+    fn spi5(_: spi5::Context) {
+        panic!("DAC1 SPI error");
+    }
+}
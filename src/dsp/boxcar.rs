@@ -0,0 +1,71 @@
+//! Boxcar (integrate-and-dump) demodulation
+//!
+//! # Design
+//! A boxcar demodulator mixes the input with the local oscillator, integrates the product over an
+//! integer number of reference periods and then dumps (resets) the integrator. Averaging over
+//! complete reference periods rejects all harmonics of the reference, including the `2f` mixing
+//! product, whereas a low-pass filter only attenuates them.
+//!
+//! The integration windows are aligned to the reference phase: a window ends whenever the
+//! reference phase has wrapped around the configured number of times. The average of the
+//! latest complete window is held until the next window completes.
+use idsp::Complex;
+
+/// Integrate-and-dump demodulator.
+#[derive(Copy, Clone, Default)]
+pub struct Boxcar {
+    sum: [i64; 2],
+    count: u32,
+    periods: u32,
+    reference: i32,
+    output: Complex<i32>,
+}
+
+impl Boxcar {
+    /// Update the boxcar with a new sample.
+    ///
+    /// # Note
+    /// The reference frequency must be positive, i.e. the reference phase must wrap from
+    /// `i32::MAX` to `i32::MIN` once per period.
+    ///
+    /// # Args
+    /// * `sample` - The input sample.
+    /// * `reference` - The reference phase of the sample.
+    /// * `phase` - The LO phase of the sample.
+    /// * `periods` - The number of reference periods in each integration window.
+    ///
+    /// # Returns
+    /// The average demodulated complex signal of the latest complete window.
+    pub fn update(
+        &mut self,
+        sample: i32,
+        reference: i32,
+        phase: i32,
+        periods: u32,
+    ) -> Complex<i32> {
+        // A reference period ends when the phase wraps. The current sample belongs to the next
+        // window.
+        let wrapped = (reference as u32) < (self.reference as u32);
+        self.reference = reference;
+        if wrapped {
+            self.periods += 1;
+            if self.periods >= periods && self.count > 0 {
+                let count = self.count as i64;
+                self.output = Complex {
+                    re: (self.sum[0] / count) as i32,
+                    im: (self.sum[1] / count) as i32,
+                };
+                self.sum = [0; 2];
+                self.count = 0;
+                self.periods = 0;
+            }
+        }
+
+        let (cos, sin) = idsp::cossin(phase);
+        self.sum[0] += (sample as i64 * cos as i64) >> 32;
+        self.sum[1] += (sample as i64 * sin as i64) >> 32;
+        self.count += 1;
+
+        self.output
+    }
+}
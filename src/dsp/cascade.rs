@@ -0,0 +1,53 @@
+//! Filter cascade of selectable order
+//!
+//! # Design
+//! [Cascade] holds `N` identical filter sections of which only the first `sections` are applied
+//! in series. All sections share the same configuration. This allows changing the order of the
+//! filter at run time, e.g. to trade a steeper rolloff for processing time, without changing the
+//! filter type. The inactive sections are skipped entirely and cost no processing time.
+use idsp::Filter;
+
+/// Configuration of a [Cascade].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CascadeConfig<C> {
+    /// The number of active sections. It is clamped to the range from 1 to the capacity of the
+    /// cascade.
+    pub sections: usize,
+    /// The configuration shared by all sections.
+    pub section: C,
+}
+
+/// A cascade of up to `N` identical filter sections.
+#[derive(Copy, Clone, Debug)]
+pub struct Cascade<T, const N: usize> {
+    sections: [T; N],
+    active: usize,
+}
+
+impl<T: Default, const N: usize> Default for Cascade<T, N> {
+    fn default() -> Self {
+        Self {
+            sections: core::array::from_fn(|_| T::default()),
+            active: 1,
+        }
+    }
+}
+
+impl<T: Filter, const N: usize> Filter for Cascade<T, N> {
+    type Config = CascadeConfig<T::Config>;
+
+    fn update(&mut self, x: i32, k: &Self::Config) -> i32 {
+        self.active = k.sections.clamp(1, N);
+        self.sections[..self.active]
+            .iter_mut()
+            .fold(x, |x, section| section.update(x, &k.section))
+    }
+
+    fn get(&self) -> i32 {
+        self.sections[self.active - 1].get()
+    }
+
+    fn set(&mut self, x: i32) {
+        self.sections.iter_mut().for_each(|section| section.set(x));
+    }
+}
@@ -0,0 +1,48 @@
+//! DC-blocking high-pass filter
+//!
+//! # Design
+//! The [DcBlock] removes the slowly varying DC component of a signal. The DC component is tracked
+//! by a first order low-pass (an exponential moving average) with a time constant of `2**k`
+//! samples and subtracted from the input. The result is a single pole high-pass with a corner
+//! frequency of about `f_s / (2 pi 2**k)`.
+//!
+//! The filter uses only integer shifts and additions per sample. The DC estimate keeps 32
+//! fractional bits so that even very long time constants track without a dead band.
+
+/// Single pole DC-blocking high-pass filter.
+#[derive(Copy, Clone, Default)]
+pub struct DcBlock {
+    dc: i64,
+}
+
+impl DcBlock {
+    /// Filter a sample.
+    ///
+    /// # Args
+    /// * `x` - The input sample.
+    /// * `k` - The log2 time constant of the DC estimate in samples. It is clamped to at most 31.
+    ///
+    /// # Returns
+    /// The input with the DC estimate removed, saturated to the `i16` range.
+    pub fn update(&mut self, x: i16, k: u32) -> i16 {
+        let x = (x as i64) << 32;
+        self.dc += (x - self.dc) >> k.min(31);
+        ((x - self.dc) >> 32).clamp(i16::MIN as i64, i16::MAX as i64) as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settles_to_zero() {
+        // A step passes initially, less `1/64` of the step for a time constant of 64 samples.
+        for (dc, step) in [(1000, 984), (-1000, -985), (i16::MIN, -32256)] {
+            let mut dc_block = DcBlock::default();
+            assert_eq!(dc_block.update(dc, 6), step);
+            let y = (0..1 << 12).fold(0, |_, _| dc_block.update(dc, 6));
+            assert_eq!(y, 0);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Averaging decimation
+//!
+//! # Design
+//! The [Decimator] reduces the sample rate of a channel by an integer factor. Each group of
+//! `factor` consecutive input samples is averaged into a single output sample, i.e. a boxcar
+//! filter followed by downsampling. Compared to plain downsampling this suppresses noise and
+//! aliasing of components above the decimated Nyquist frequency.
+//!
+//! The output samples are collected into batches of `N` samples so that decimated data can be
+//! handled like regular batches, e.g. for streaming. Decimators of several channels that are fed
+//! inputs of equal length stay aligned.
+
+/// The maximum decimation factor. The sum of a group of samples must not overflow.
+pub const MAX_FACTOR: u32 = 1 << 16;
+
+/// Averaging decimator collecting its output into batches.
+#[derive(Copy, Clone)]
+pub struct Decimator<const N: usize> {
+    factor: u32,
+    count: u32,
+    sum: i64,
+    index: usize,
+    output: [i32; N],
+}
+
+impl<const N: usize> Default for Decimator<N> {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+impl<const N: usize> Decimator<N> {
+    /// Construct a new decimator.
+    ///
+    /// # Args
+    /// * `factor` - The decimation factor. It is clamped to the range from 1 (no decimation) to
+    ///   [MAX_FACTOR].
+    pub fn new(factor: u32) -> Self {
+        Self {
+            factor: factor.clamp(1, MAX_FACTOR),
+            count: 0,
+            sum: 0,
+            index: 0,
+            output: [0; N],
+        }
+    }
+
+    /// Get the decimation factor.
+    pub fn factor(&self) -> u32 {
+        self.factor
+    }
+
+    /// Decimate input samples.
+    ///
+    /// # Args
+    /// * `input` - The input samples. At most `N * factor` samples should be provided at once,
+    ///   otherwise completed output batches are overwritten.
+    ///
+    /// # Returns
+    /// The batch of `N` decimated samples if it was completed by the input.
+    pub fn update(
+        &mut self,
+        input: impl IntoIterator<Item = i32>,
+    ) -> Option<&[i32; N]> {
+        let mut complete = false;
+        for x in input {
+            self.sum += x as i64;
+            self.count += 1;
+            if self.count == self.factor {
+                self.output[self.index] =
+                    (self.sum / self.factor as i64) as i32;
+                self.sum = 0;
+                self.count = 0;
+                self.index += 1;
+                if self.index == N {
+                    self.index = 0;
+                    complete = true;
+                }
+            }
+        }
+        complete.then_some(&self.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_mean() {
+        let mut decimator = Decimator::<4>::new(3);
+        let input: [i32; 12] = core::array::from_fn(|i| i as i32);
+        assert_eq!(decimator.update(input[..4].iter().copied()), None);
+        assert_eq!(decimator.update(input[4..8].iter().copied()), None);
+        assert_eq!(
+            decimator.update(input[8..].iter().copied()),
+            Some(&[1, 4, 7, 10])
+        );
+
+        // The mean is rounded towards zero and does not overflow.
+        let mut decimator = Decimator::<2>::new(2);
+        assert_eq!(
+            decimator.update([-1, -2, i32::MAX, i32::MAX]),
+            Some(&[-1, i32::MAX])
+        );
+    }
+
+    #[test]
+    fn factor_change() {
+        assert_eq!(Decimator::<4>::new(0).factor(), 1);
+        assert_eq!(Decimator::<4>::new(u32::MAX).factor(), MAX_FACTOR);
+
+        // Decimators of all channels are replaced when the factor changes. A partial group or
+        // batch of the previous factor is discarded and the channels stay aligned with the input
+        // batches.
+        let mut decimators = [Decimator::<4>::new(3); 2];
+        assert_eq!(decimators[0].update([1; 4]), None);
+        assert_eq!(decimators[1].update([-1; 4]), None);
+
+        decimators = [Decimator::new(2); 2];
+        assert_eq!(decimators[0].update([5; 4]), None);
+        assert_eq!(decimators[1].update([-5; 4]), None);
+        assert_eq!(decimators[0].update([2, 4, 6, 8]), Some(&[5, 5, 3, 7]));
+        assert_eq!(decimators[1].update([-5; 4]), Some(&[-5; 4]));
+    }
+}
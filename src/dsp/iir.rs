@@ -0,0 +1,356 @@
+//! Biquad IIR filter design
+//!
+//! # Design
+//! The functions in this module compute the coefficients of second order sections using the
+//! formulas from the [Audio EQ Cookbook](https://www.w3.org/TR/audio-eq-cookbook/) by R.
+//! Bristow-Johnson. The resulting coefficients can be assigned to [idsp::iir::IIR::ba].
+//!
+//! The coefficients are returned as an array `[b0, b1, b2, a1, a2]` normalized to `a0 = 1` such
+//! that the new output is computed as `y0 = a1*y1 + a2*y2 + b0*x0 + b1*x1 + b2*x2`. Note that the
+//! sign of the feedback coefficients is inverted with respect to the cookbook convention.
+//!
+//! All frequencies are normalized to the sample rate, i.e. `f0 = 0.5` corresponds to the Nyquist
+//! frequency.
+use idsp::iir::Vec5;
+use num_traits::{Float, FloatConst};
+
+/// Represents the errors that can occur when designing or validating filter coefficients.
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// A feedforward-only filter has nonzero feedback coefficients.
+    NonzeroFeedback,
+    /// The filter order requires more sections than the cascade provides.
+    Order,
+}
+
+/// Normalize the cookbook coefficients to `a0 = 1` and apply the gain.
+fn normalize<T: Float>(b: [T; 3], a: [T; 3], gain: T) -> Vec5<T> {
+    let g = gain / a[0];
+    [b[0] * g, b[1] * g, b[2] * g, -a[1] / a[0], -a[2] / a[0]]
+}
+
+/// Compute the cosine of the angular frequency and the cookbook `alpha` parameter.
+fn prewarp<T: Float + FloatConst>(f0: T, q: T) -> (T, T) {
+    let two = T::one() + T::one();
+    let w0 = two * T::PI() * f0;
+    let (sin, cos) = w0.sin_cos();
+    (cos, sin / (two * q))
+}
+
+/// Second order lowpass filter.
+///
+/// # Args
+/// * `f0` - The normalized corner frequency.
+/// * `q` - The quality factor. `1/sqrt(2)` results in a maximally flat passband.
+/// * `gain` - The passband (DC) gain.
+pub fn lowpass(f0: f32, q: f32, gain: f32) -> Vec5<f32> {
+    let (cos, alpha) = prewarp(f0, q);
+    let b = (1. - cos) / 2.;
+    normalize([b, 2. * b, b], [1. + alpha, -2. * cos, 1. - alpha], gain)
+}
+
+/// Second order highpass filter.
+///
+/// # Args
+/// * `f0` - The normalized corner frequency.
+/// * `q` - The quality factor. `1/sqrt(2)` results in a maximally flat passband.
+/// * `gain` - The passband (Nyquist) gain.
+pub fn highpass(f0: f32, q: f32, gain: f32) -> Vec5<f32> {
+    let (cos, alpha) = prewarp(f0, q);
+    let b = (1. + cos) / 2.;
+    normalize([b, -2. * b, b], [1. + alpha, -2. * cos, 1. - alpha], gain)
+}
+
+/// Second order bandpass filter.
+///
+/// # Args
+/// * `f0` - The normalized center frequency.
+/// * `q` - The quality factor, i.e. the ratio of the center frequency to the -3 dB bandwidth.
+/// * `gain` - The gain at the center frequency.
+pub fn bandpass(f0: f32, q: f32, gain: f32) -> Vec5<f32> {
+    let (cos, alpha) = prewarp(f0, q);
+    normalize(
+        [alpha, 0., -alpha],
+        [1. + alpha, -2. * cos, 1. - alpha],
+        gain,
+    )
+}
+
+/// Second order notch (band-stop) filter.
+///
+/// # Note
+/// The coefficients can be computed in `f64` for notches at very low normalized frequencies, see
+/// [super::notch_bank].
+///
+/// # Args
+/// * `f0` - The normalized notch frequency.
+/// * `q` - The quality factor, i.e. the ratio of the notch frequency to the -3 dB bandwidth.
+/// * `gain` - The gain away from the notch (at DC and Nyquist).
+pub fn notch<T: Float + FloatConst>(f0: T, q: T, gain: T) -> Vec5<T> {
+    let (cos, alpha) = prewarp(f0, q);
+    let (one, two) = (T::one(), T::one() + T::one());
+    normalize(
+        [one, -two * cos, one],
+        [one + alpha, -two * cos, one - alpha],
+        gain,
+    )
+}
+
+/// Proportional-integral-derivative (PID) controller.
+///
+/// # Note
+/// The controller is discretized using the backward Euler method: the integrator is
+/// `T/(1 - z^-1)` and the differentiator is `(1 - z^-1)/T`, where `T` is the sample period. The
+/// derivative term is not band-limited. Without integral gain, the integrator pole is omitted and
+/// the resulting filter has a finite impulse response.
+///
+/// # Args
+/// * `kp` - The proportional gain.
+/// * `ki` - The integral gain in units of inverse seconds.
+/// * `kd` - The derivative gain in units of seconds.
+/// * `sample_period` - The sample period in seconds.
+pub fn pid(kp: f32, ki: f32, kd: f32, sample_period: f32) -> Vec5<f32> {
+    let i = ki * sample_period;
+    let d = kd / sample_period;
+    if ki == 0. {
+        [kp + d, -d, 0., 0., 0.]
+    } else {
+        [kp + i + d, -kp - 2. * d, d, 1., 0.]
+    }
+}
+
+/// Evaluate the frequency response of a biquad.
+///
+/// # Args
+/// * `ba` - The filter coefficients `[b0, b1, b2, a1, a2]`.
+/// * `f` - The normalized frequency at which to evaluate the response. `0` is DC and `0.5` is
+///   the Nyquist frequency.
+///
+/// # Returns
+/// The magnitude and the phase (in radians) of the transfer function. At a pole on the unit
+/// circle (e.g. an integrator at DC), the magnitude is infinite.
+pub fn response(ba: &Vec5<f32>, f: f32) -> (f32, f32) {
+    let w = 2. * core::f32::consts::PI * f;
+    let (sin1, cos1) = w.sin_cos();
+    let (sin2, cos2) = (2. * w).sin_cos();
+
+    // Evaluate numerator and denominator at z = exp(iw), expanded in powers of z^-1.
+    let num = (
+        ba[0] + ba[1] * cos1 + ba[2] * cos2,
+        -ba[1] * sin1 - ba[2] * sin2,
+    );
+    let den = (
+        1. - ba[3] * cos1 - ba[4] * cos2,
+        ba[3] * sin1 + ba[4] * sin2,
+    );
+
+    let num_abs = num.0.hypot(num.1);
+    let den_abs = den.0.hypot(den.1);
+    let magnitude = if den_abs == 0. {
+        f32::INFINITY
+    } else {
+        num_abs / den_abs
+    };
+    let phase = num.1.atan2(num.0) - den.1.atan2(den.0);
+
+    (magnitude, phase)
+}
+
+/// Feedforward-only (FIR) filter.
+///
+/// # Note
+/// The feedback coefficients are zero, so the filter has no poles and is unconditionally stable.
+///
+/// # Args
+/// * `b` - The feedforward coefficients `[b0, b1, b2]`.
+pub fn fir(b: [f32; 3]) -> Vec5<f32> {
+    [b[0], b[1], b[2], 0., 0.]
+}
+
+/// Check that filter coefficients describe a feedforward-only (FIR) filter.
+///
+/// # Args
+/// * `ba` - The filter coefficients `[b0, b1, b2, a1, a2]`.
+pub fn check_fir(ba: &Vec5<f32>) -> Result<(), Error> {
+    if ba[3] != 0. || ba[4] != 0. {
+        return Err(Error::NonzeroFeedback);
+    }
+    Ok(())
+}
+
+/// Butterworth lowpass filter of arbitrary order as a cascade of biquads.
+///
+/// # Note
+/// An even order is realized by `order / 2` second order sections. For an odd order, the first
+/// section is a first order lowpass with `b2 = a2 = 0`. Any remaining sections of the cascade
+/// are unity gain pass-through sections.
+///
+/// # Args
+/// * `order` - The filter order, at most `2 * N`.
+/// * `f0` - The normalized -3 dB corner frequency.
+///
+/// # Returns
+/// The coefficients of the `N` cascaded sections, each with unity DC gain, or [Error::Order] if
+/// the order exceeds `2 * N`.
+pub fn butterworth_lowpass<const N: usize>(
+    order: usize,
+    f0: f32,
+) -> Result<[Vec5<f32>; N], Error> {
+    if (order + 1) / 2 > N {
+        return Err(Error::Order);
+    }
+    let mut cascade = [[1., 0., 0., 0., 0.]; N];
+    let mut sections = cascade.iter_mut();
+
+    if order % 2 == 1 {
+        // Bilinear transform of the first order section with a prewarped corner frequency.
+        let k = (core::f32::consts::PI * f0).tan();
+        let b = k / (1. + k);
+        *sections.next().unwrap() = [b, b, 0., (1. - k) / (1. + k), 0.];
+    }
+
+    for (i, section) in sections.take(order / 2).enumerate() {
+        let theta =
+            core::f32::consts::PI * (2 * i + 1) as f32 / (2 * order) as f32;
+        *section = lowpass(f0, 1. / (2. * theta.sin()), 1.);
+    }
+
+    Ok(cascade)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::FRAC_1_SQRT_2;
+
+    // Check the magnitude of the response at a normalized frequency and that the phase is defined.
+    fn assert_magnitude(ba: &Vec5<f32>, f: f32, magnitude: f32) {
+        let (m, phase) = response(ba, f);
+        assert!(
+            (m - magnitude).abs() < 1e-4,
+            "|H({f})| = {m}, expected {magnitude}"
+        );
+        assert!(!phase.is_nan(), "arg H({f}) is NaN");
+    }
+
+    #[test]
+    fn lowpass_highpass_gain() {
+        for f0 in [0.01, 0.1, 0.3] {
+            // The magnitude at the corner frequency is `q` times the gain.
+            let ba = lowpass(f0, FRAC_1_SQRT_2, 2.);
+            assert_magnitude(&ba, 0., 2.);
+            assert_magnitude(&ba, f0, 2. * FRAC_1_SQRT_2);
+            assert_magnitude(&ba, 0.5, 0.);
+
+            let ba = highpass(f0, 2., 0.5);
+            assert_magnitude(&ba, 0., 0.);
+            assert_magnitude(&ba, f0, 1.);
+            assert_magnitude(&ba, 0.5, 0.5);
+        }
+    }
+
+    #[test]
+    fn bandpass_notch_gain() {
+        for f0 in [0.01, 0.1, 0.3] {
+            let ba = bandpass(f0, 5., 2.);
+            assert_magnitude(&ba, 0., 0.);
+            assert_magnitude(&ba, f0, 2.);
+            assert_magnitude(&ba, 0.5, 0.);
+        }
+
+        // The `f32` zeros of notches at lower frequencies are displaced, see the `notch_bank`
+        // module.
+        for f0 in [0.1, 0.3] {
+            let ba = notch(f0, 5., 2.);
+            assert_magnitude(&ba, 0., 2.);
+            assert_magnitude(&ba, f0, 0.);
+            assert_magnitude(&ba, 0.5, 2.);
+        }
+    }
+
+    #[test]
+    fn pid_coefficients() {
+        // `i = ki * T = 0.5` and `d = kd / T = 2`
+        assert_eq!(pid(1., 2., 0.5, 0.25), [3.5, -5., 2., 1., 0.]);
+        // Without integral gain, the filter is `kp + d - d z^-1`.
+        assert_eq!(pid(1., 0., 0.5, 0.25), [3., -2., 0., 0., 0.]);
+        assert_eq!(pid(1., 0., 0., 0.25), [1., 0., 0., 0., 0.]);
+    }
+
+    #[test]
+    fn response_band_edges() {
+        assert_eq!(response(&[1., 0., 0., 0., 0.], 0.), (1., 0.));
+        assert_magnitude(&[1., 0., 0., 0., 0.], 0.5, 1.);
+
+        // The integrator pole at DC.
+        let ba = pid(1., 2., 0.5, 0.25);
+        let (magnitude, phase) = response(&ba, 0.);
+        assert!(magnitude.is_infinite());
+        assert!(!phase.is_nan());
+        // `kp + i / 2 + 2 d` at Nyquist.
+        assert_magnitude(&ba, 0.5, 5.25);
+
+        for ba in [
+            lowpass(0.1, FRAC_1_SQRT_2, 1.),
+            highpass(0.1, FRAC_1_SQRT_2, 1.),
+            bandpass(0.1, 1., 1.),
+            notch(0.1, 1., 1.),
+            pid(1., 0., 0.5, 0.25),
+        ] {
+            for f in [0., 0.5] {
+                let (magnitude, phase) = response(&ba, f);
+                assert!(!magnitude.is_nan() && !phase.is_nan());
+            }
+        }
+    }
+
+    #[test]
+    fn fir_validation() {
+        let ba = fir([0.25, 0.5, 0.25]);
+        assert!(check_fir(&ba).is_ok());
+        assert_magnitude(&ba, 0., 1.);
+        assert_magnitude(&ba, 0.5, 0.);
+
+        assert!(check_fir(&pid(1., 0., 0.5, 0.25)).is_ok());
+        assert!(matches!(
+            check_fir(&lowpass(0.1, FRAC_1_SQRT_2, 1.)),
+            Err(Error::NonzeroFeedback)
+        ));
+    }
+
+    #[test]
+    fn butterworth_sections() {
+        // The section Qs of the fourth order filter are `1/(2 sin(pi/8))` and
+        // `1/(2 sin(3 pi/8))`. The magnitude of each section at the corner frequency is its Q.
+        let cascade: [_; 2] = butterworth_lowpass(4, 0.1).unwrap();
+        assert_magnitude(&cascade[0], 0.1, 1.306_563);
+        assert_magnitude(&cascade[1], 0.1, 0.541_196);
+
+        // A first order section followed by a section with Q = 1 and a pass-through section.
+        let cascade: [_; 3] = butterworth_lowpass(3, 0.1).unwrap();
+        assert_eq!((cascade[0][2], cascade[0][4]), (0., 0.));
+        assert_magnitude(&cascade[0], 0.1, FRAC_1_SQRT_2);
+        assert_magnitude(&cascade[1], 0.1, 1.);
+        assert_eq!(cascade[2], [1., 0., 0., 0., 0.]);
+    }
+
+    #[test]
+    fn butterworth_response() {
+        for order in 1..=6 {
+            let cascade: [_; 3] = butterworth_lowpass(order, 0.1).unwrap();
+            for (f, expected) in [(0., 1.), (0.1, FRAC_1_SQRT_2), (0.5, 0.)] {
+                let magnitude: f32 =
+                    cascade.iter().map(|ba| response(ba, f).0).product();
+                assert!(
+                    (magnitude - expected).abs() < 1e-4,
+                    "order {order}: |H({f})| = {magnitude}"
+                );
+            }
+        }
+
+        assert!(matches!(
+            butterworth_lowpass::<2>(5, 0.1),
+            Err(Error::Order)
+        ));
+    }
+}
@@ -0,0 +1,108 @@
+//! Interval statistics
+//!
+//! # Design
+//! The quality of an external reference is characterized by the spread of the intervals between
+//! its timestamps. [IntervalStatistics] accumulates the minimum, maximum, mean and variance of
+//! intervals incrementally using Welford's online algorithm. Each update is constant time and
+//! numerically stable, so it fits into the DSP processing budget and the statistics can be
+//! gathered over arbitrarily long windows.
+
+/// Incremental statistics of timestamp intervals.
+#[derive(Copy, Clone, Debug)]
+pub struct IntervalStatistics {
+    count: u32,
+    min: u32,
+    max: u32,
+    mean: f32,
+    m2: f32,
+}
+
+impl Default for IntervalStatistics {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            min: u32::MAX,
+            max: 0,
+            mean: 0.,
+            m2: 0.,
+        }
+    }
+}
+
+impl IntervalStatistics {
+    /// Add an interval to the statistics.
+    ///
+    /// # Args
+    /// * `interval` - The interval between two timestamps in timer ticks.
+    pub fn update(&mut self, interval: u32) {
+        self.count = self.count.saturating_add(1);
+        self.min = self.min.min(interval);
+        self.max = self.max.max(interval);
+
+        let x = interval as f32;
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    /// Get the number of accumulated intervals.
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Get the minimum interval or zero if there are no intervals.
+    pub fn min(&self) -> u32 {
+        if self.count > 0 {
+            self.min
+        } else {
+            0
+        }
+    }
+
+    /// Get the maximum interval or zero if there are no intervals.
+    pub fn max(&self) -> u32 {
+        self.max
+    }
+
+    /// Get the mean interval.
+    pub fn mean(&self) -> f32 {
+        self.mean
+    }
+
+    /// Get the sample variance of the intervals.
+    ///
+    /// # Returns
+    /// The variance in squared timer ticks or zero if there are fewer than two intervals.
+    pub fn variance(&self) -> f32 {
+        if self.count > 1 {
+            self.m2 / (self.count - 1) as f32
+        } else {
+            0.
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mean_and_variance() {
+        let stats = IntervalStatistics::default();
+        assert_eq!((stats.count(), stats.min(), stats.max()), (0, 0, 0));
+        assert_eq!(stats.variance(), 0.);
+
+        // The sum of squared deviations of the intervals from their mean is 8. A large offset
+        // does not reduce the precision, in contrast to a sum of squares in `f32`.
+        for offset in [0, 1_000_000] {
+            let mut stats = IntervalStatistics::default();
+            for interval in [8, 12, 10, 10] {
+                stats.update(offset + interval);
+            }
+            assert_eq!(stats.count(), 4);
+            assert_eq!((stats.min(), stats.max()), (offset + 8, offset + 12));
+            assert_eq!(stats.mean(), (offset + 10) as f32);
+            assert_eq!(stats.variance(), 8. / 3.);
+        }
+    }
+}
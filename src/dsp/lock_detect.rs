@@ -0,0 +1,72 @@
+//! PLL lock detection
+//!
+//! # Design
+//! The [LockDetector] considers a PLL to be locked to its reference when the reference is present
+//! (i.e. timestamps have been received recently) and the frequency estimate of the PLL has been
+//! stable for a number of consecutive updates. A converged PLL tracks a steady reference with a
+//! frequency estimate that only fluctuates by a small fraction between updates, while a missing
+//! or changing reference causes the estimate to slew.
+
+/// Frequency stability based PLL lock detector.
+#[derive(Copy, Clone)]
+pub struct LockDetector {
+    tolerance_log2: u32,
+    count: u32,
+    timeout: u32,
+    frequency: u32,
+    stable: u32,
+    missing: u32,
+}
+
+impl LockDetector {
+    /// Construct a new lock detector.
+    ///
+    /// # Args
+    /// * `tolerance_log2` - The maximum relative frequency change between updates for the PLL to
+    ///   be considered stable, as a power of two, i.e. `10` corresponds to about 1e-3.
+    /// * `count` - The number of consecutive stable updates required to declare lock.
+    /// * `timeout` - The maximum number of consecutive updates without a reference timestamp
+    ///   before the reference is considered missing.
+    pub fn new(tolerance_log2: u32, count: u32, timeout: u32) -> Self {
+        Self {
+            tolerance_log2,
+            count,
+            timeout,
+            frequency: 0,
+            stable: 0,
+            missing: u32::MAX,
+        }
+    }
+
+    /// Update the lock detector.
+    ///
+    /// # Args
+    /// * `reference` - Whether a reference timestamp was received for this update.
+    /// * `frequency` - The current PLL frequency estimate.
+    ///
+    /// # Returns
+    /// Whether the PLL is locked.
+    pub fn update(&mut self, reference: bool, frequency: u32) -> bool {
+        self.missing = if reference {
+            0
+        } else {
+            self.missing.saturating_add(1)
+        };
+
+        let delta =
+            (frequency.wrapping_sub(self.frequency) as i32).unsigned_abs();
+        self.frequency = frequency;
+        self.stable = if delta <= frequency >> self.tolerance_log2 {
+            self.stable.saturating_add(1)
+        } else {
+            0
+        };
+
+        self.is_locked()
+    }
+
+    /// Check whether the PLL is locked.
+    pub fn is_locked(&self) -> bool {
+        self.missing <= self.timeout && self.stable >= self.count
+    }
+}
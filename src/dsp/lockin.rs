@@ -0,0 +1,63 @@
+//! Multi-harmonic lock-in detection
+//!
+//! # Design
+//! A single reference phase (e.g. as generated by an [idsp::Accu] or recovered by an
+//! [idsp::RPLL]) is used to demodulate several harmonics of the reference simultaneously. Each
+//! harmonic is demodulated and filtered by an independent [Lockin] with a shared low-pass
+//! configuration. The number of harmonics is fixed at compile time so that no heap allocation is
+//! required.
+use idsp::{Complex, Filter, Lockin};
+
+/// A lock-in amplifier demodulating the first `N` harmonics of a reference in parallel.
+#[derive(Copy, Clone)]
+pub struct MultiLockin<C, const N: usize> {
+    lockins: [Lockin<C>; N],
+}
+
+impl<C, const N: usize> Default for MultiLockin<C, N>
+where
+    Lockin<C>: Default,
+{
+    fn default() -> Self {
+        Self {
+            lockins: core::array::from_fn(|_| Lockin::default()),
+        }
+    }
+}
+
+impl<C, const N: usize> MultiLockin<C, N>
+where
+    Lockin<C>: Default,
+{
+    /// Reset the filter state of all harmonics.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+impl<C: Filter, const N: usize> MultiLockin<C, N> {
+    /// Update the lock-in amplifiers with a new sample.
+    ///
+    /// # Args
+    /// * `sample` - The input sample.
+    /// * `phase` - The LO phase of the fundamental. Harmonic `n` (starting at 1) is demodulated
+    ///   using `n * phase`.
+    /// * `k` - The low-pass filter configuration shared by all harmonics.
+    ///
+    /// # Returns
+    /// The demodulated and filtered complex signal of each harmonic, starting with the
+    /// fundamental.
+    pub fn update(
+        &mut self,
+        sample: i32,
+        phase: i32,
+        k: &C::Config,
+    ) -> [Complex<i32>; N] {
+        let mut harmonic_phase = 0i32;
+        let mut lockins = self.lockins.iter_mut();
+        core::array::from_fn(|_| {
+            harmonic_phase = harmonic_phase.wrapping_add(phase);
+            lockins.next().unwrap().update(sample, harmonic_phase, k)
+        })
+    }
+}
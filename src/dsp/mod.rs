@@ -0,0 +1,25 @@
+//! Stabilizer signal processing extensions
+//!
+//! # Design
+//! General purpose signal processing algorithms are provided by the `idsp` crate. This module
+//! contains the additional building blocks used by the Stabilizer applications that are built on
+//! top of those primitives.
+pub mod boxcar;
+pub mod cascade;
+pub mod dc_block;
+pub mod decimate;
+pub mod iir;
+pub mod interval_stats;
+pub mod lock_detect;
+pub mod lockin;
+pub mod notch_bank;
+pub mod quadrature_null;
+pub mod reference_gate;
+pub mod rms;
+pub mod slew;
+pub mod soft_start;
+pub mod synthetic_reference;
+pub mod unwrap;
+
+mod math;
+pub use math::{atan2_precise, log2, sqrt, timestamp_phase};
@@ -0,0 +1,67 @@
+//! Mains harmonic notch filter bank
+//!
+//! # Design
+//! Pickup of the mains frequency and its harmonics is suppressed by a cascade of second order
+//! notch filters at the first `N` harmonics of the mains fundamental. The notch coefficients are
+//! those of [super::iir::notch] with unity gain.
+//!
+//! Mains frequencies are tiny compared to the Stabilizer sample rates. At 781.25 kHz, a 50 Hz notch
+//! is at 6.4e-5 of the sample rate and `1 - cos(w0)` is 8.1e-8, about one `f32` LSB below 1. In
+//! `f32`, the zeros of the notch would land at either 43 Hz or 61 Hz. The poles are within
+//! `w0 / (2 q)` of the unit circle and amplify the rounding errors of an `f32` filter state by
+//! orders of magnitude. The bank therefore computes the coefficients and the filter in `f64`, which
+//! the Cortex-M7 FPU supports in hardware, albeit at a higher cost per sample than `f32`.
+use super::iir;
+
+/// The state of a single notch: `[x1, x2, y1, y2]`.
+pub type NotchState = [f64; 4];
+
+/// A cascade of notch filters at the harmonics of a fundamental frequency.
+#[derive(Copy, Clone, Debug)]
+pub struct NotchBank<const N: usize> {
+    ba: [[f64; 5]; N],
+}
+
+impl<const N: usize> NotchBank<N> {
+    /// Construct a notch filter bank.
+    ///
+    /// # Note
+    /// Harmonics at or above the Nyquist frequency are passed unfiltered.
+    ///
+    /// # Args
+    /// * `f0` - The normalized fundamental frequency.
+    /// * `q` - The quality factor of the notch at each harmonic, starting with the fundamental.
+    pub fn new(f0: f64, q: [f64; N]) -> Self {
+        let mut ba = [[1., 0., 0., 0., 0.]; N];
+        for (i, (ba, q)) in ba.iter_mut().zip(q).enumerate() {
+            let f = f0 * (i + 1) as f64;
+            if f < 0.5 {
+                *ba = iir::notch(f, q, 1.);
+            }
+        }
+        Self { ba }
+    }
+
+    /// Filter a sample.
+    ///
+    /// # Args
+    /// * `state` - The state of each notch.
+    /// * `x` - The input sample.
+    ///
+    /// # Returns
+    /// The filtered sample.
+    pub fn update(&self, state: &mut [NotchState; N], x: f32) -> f32 {
+        self.ba
+            .iter()
+            .zip(state.iter_mut())
+            .fold(x as f64, |x, (ba, s)| {
+                let y = ba[0] * x
+                    + ba[1] * s[0]
+                    + ba[2] * s[1]
+                    + ba[3] * s[2]
+                    + ba[4] * s[3];
+                *s = [x, s[0], y, s[2]];
+                y
+            }) as f32
+    }
+}
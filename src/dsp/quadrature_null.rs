@@ -0,0 +1,77 @@
+//! Lock-in quadrature nulling
+//!
+//! # Design
+//! The [QuadratureNull] integrates the phase of the demodulated signal into a rotation of the
+//! local oscillator (LO). The [idsp::Lockin] mixes the input with `exp(i phase)`, so rotating the
+//! LO by an angle rotates the demodulated signal by the same angle. The integrator therefore
+//! subtracts the signal phase. Once settled, the signal is in the in-phase component and the
+//! negative LO rotation is the phase of the signal.
+use idsp::{Complex, ComplexExt};
+
+/// Integrator rotating the LO phase to null the quadrature component of a lock-in.
+#[derive(Copy, Clone, Default)]
+pub struct QuadratureNull {
+    rotation: i32,
+}
+
+impl QuadratureNull {
+    /// Update the LO rotation with a demodulated signal.
+    ///
+    /// # Args
+    /// * `signal` - The demodulated signal obtained with the current LO rotation.
+    /// * `shift` - The integrator time constant as a power of two of the number of updates.
+    ///
+    /// # Returns
+    /// The LO rotation to add to the LO phase of the next demodulation.
+    pub fn update(&mut self, signal: Complex<i32>, shift: u32) -> i32 {
+        self.rotation =
+            self.rotation.wrapping_sub(signal.arg() >> shift.min(31));
+        self.rotation
+    }
+
+    /// Get the current LO rotation.
+    pub fn rotation(&self) -> i32 {
+        self.rotation
+    }
+
+    /// Get the phase of the signal, i.e. the negative LO rotation.
+    pub fn phase(&self) -> i32 {
+        self.rotation.wrapping_neg()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use idsp::{Lockin, Lowpass};
+
+    #[test]
+    fn closed_loop() {
+        // The reference frequency in turns per sample.
+        const FREQUENCY: i32 = 1 << 28;
+        // The default lockin low-pass of the lockin application.
+        let k = [0x8_0000, -0x400_0000];
+
+        for phase in [0, 1 << 29, -(3 << 29), i32::MAX / 3] {
+            let mut lockin = Lockin::<Lowpass<2>>::default();
+            let mut null = QuadratureNull::default();
+            let mut reference = 0i32;
+            let mut signal = Complex::default();
+
+            for _ in 0..5000 {
+                for _ in 0..8 {
+                    let sample = idsp::cossin(reference.wrapping_add(phase)).0;
+                    // Demodulate the fundamental, i.e. with the negative reference phase.
+                    let lo = null.rotation().wrapping_sub(reference);
+                    signal = lockin.update(sample >> 1, lo, &k);
+                    reference = reference.wrapping_add(FREQUENCY);
+                }
+                null.update(signal, 8);
+            }
+
+            assert!(signal.re > 0);
+            assert!(signal.im.unsigned_abs() < signal.re.unsigned_abs() >> 5);
+            assert!(null.phase().wrapping_sub(phase).unsigned_abs() < 1 << 24);
+        }
+    }
+}
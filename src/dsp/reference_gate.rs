@@ -0,0 +1,119 @@
+//! Reference timestamp validation
+//!
+//! # Design
+//! Glitches or dropouts of an external reference produce timestamps that are inconsistent with
+//! the reference frequency. Feeding those into a PLL causes large frequency excursions that take
+//! a long time to recover from. The [ReferenceGate] checks the period between consecutive
+//! timestamps against a plausible band and rejects timestamps outside of it.
+//!
+//! The gate always re-synchronizes to the latest timestamp. After a dropout, the first
+//! timestamp is rejected and the following ones are accepted again once the period is
+//! consistent. A rejected glitch also becomes the latest timestamp, so the next regular timestamp
+//! is rejected as well.
+
+/// Reference timestamp gate rejecting implausible reference periods.
+#[derive(Copy, Clone)]
+pub struct ReferenceGate {
+    period_limits: [u32; 2],
+    last: Option<u32>,
+    period: Option<u32>,
+    rejected: u32,
+}
+
+impl ReferenceGate {
+    /// Construct a new reference gate.
+    ///
+    /// # Args
+    /// * `period_limits` - The minimum and maximum reference period in timestamp ticks.
+    pub fn new(period_limits: [u32; 2]) -> Self {
+        Self {
+            period_limits,
+            last: None,
+            period: None,
+            rejected: 0,
+        }
+    }
+
+    /// Update the plausible reference period band.
+    ///
+    /// # Args
+    /// * `period_limits` - The minimum and maximum reference period in timestamp ticks.
+    pub fn set_period_limits(&mut self, period_limits: [u32; 2]) {
+        self.period_limits = period_limits;
+    }
+
+    /// Validate a timestamp.
+    ///
+    /// # Args
+    /// * `timestamp` - The latest reference timestamp, if any.
+    ///
+    /// # Returns
+    /// The timestamp if it is consistent with the previous one, `None` otherwise.
+    pub fn update(&mut self, timestamp: Option<u32>) -> Option<u32> {
+        self.period = None;
+        let timestamp = timestamp?;
+        let last = self.last.replace(timestamp);
+
+        match last {
+            Some(last) => {
+                let period = timestamp.wrapping_sub(last);
+                if (self.period_limits[0]..=self.period_limits[1])
+                    .contains(&period)
+                {
+                    self.period = Some(period);
+                    Some(timestamp)
+                } else {
+                    self.rejected = self.rejected.wrapping_add(1);
+                    None
+                }
+            }
+            // Without a previous timestamp, the period is unknown.
+            None => Some(timestamp),
+        }
+    }
+
+    /// Get the reference period measured by the latest update.
+    ///
+    /// # Returns
+    /// The period in timestamp ticks if the latest update accepted a timestamp following a
+    /// previous one, `None` otherwise.
+    pub fn period(&self) -> Option<u32> {
+        self.period
+    }
+
+    /// Get the number of rejected timestamps.
+    pub fn rejected(&self) -> u32 {
+        self.rejected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejection() {
+        let mut gate = ReferenceGate::new([90, 110]);
+        assert_eq!(gate.update(Some(0)), Some(0));
+        assert_eq!(gate.period(), None);
+        assert_eq!(gate.update(Some(100)), Some(100));
+        assert_eq!(gate.period(), Some(100));
+        assert_eq!(gate.update(None), None);
+        assert_eq!(gate.period(), None);
+
+        // A glitch is rejected. It also becomes the last timestamp, so the next good timestamp
+        // is rejected as well.
+        assert_eq!(gate.update(Some(130)), None);
+        assert_eq!(gate.update(Some(200)), None);
+        assert_eq!(gate.rejected(), 2);
+        assert_eq!(gate.update(Some(300)), Some(300));
+        assert_eq!(gate.period(), Some(100));
+
+        // The period is measured across the wrap of the timestamp counter.
+        let mut gate = ReferenceGate::new([90, 110]);
+        assert_eq!(gate.update(Some(u32::MAX - 49)), Some(u32::MAX - 49));
+        assert_eq!(gate.update(Some(50)), Some(50));
+        assert_eq!(gate.period(), Some(100));
+        assert_eq!(gate.rejected(), 0);
+    }
+}
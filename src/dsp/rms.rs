@@ -0,0 +1,86 @@
+//! Windowed RMS estimator
+//!
+//! # Design
+//! The lock quality of a servo is summarized by the RMS of its error signal. The [Rms] estimator
+//! accumulates the squared samples over a window of a configurable number of samples. At the end
+//! of each window the root of the mean square is computed with [super::sqrt] and held until the
+//! next window completes. Accumulation is a single integer multiply-add per sample, so the
+//! estimator fits into the DSP processing budget.
+
+/// Windowed root-mean-square estimator.
+#[derive(Copy, Clone, Default)]
+pub struct Rms {
+    sum: u64,
+    count: u32,
+    rms: u32,
+}
+
+impl Rms {
+    /// Add a sample to the current window.
+    ///
+    /// # Args
+    /// * `x` - The sample.
+    /// * `window` - The number of samples in each window. Zero is treated as one.
+    pub fn update(&mut self, x: i32, window: u32) {
+        self.sum = self
+            .sum
+            .saturating_add(x.unsigned_abs() as u64 * x.unsigned_abs() as u64);
+        self.count += 1;
+        if self.count >= window {
+            self.rms = super::sqrt(self.sum / self.count as u64);
+            self.sum = 0;
+            self.count = 0;
+        }
+    }
+
+    /// Add the errors of a batch of samples w.r.t. a setpoint to the current window.
+    ///
+    /// # Args
+    /// * `samples` - The samples.
+    /// * `setpoint` - The setpoint of the samples.
+    /// * `window` - The number of samples in each window. Zero is treated as one.
+    pub fn update_errors(
+        &mut self,
+        samples: impl IntoIterator<Item = i16>,
+        setpoint: i16,
+        window: u32,
+    ) {
+        for x in samples {
+            self.update(x as i32 - setpoint as i32, window);
+        }
+    }
+
+    /// Get the RMS of the latest complete window.
+    pub fn get(&self) -> u32 {
+        self.rms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window() {
+        let mut rms = Rms::default();
+        // The mean square of the window is `(9 + 16 + 9 + 16) / 4 = 12`, rounded down.
+        for x in [3, -4, 3] {
+            rms.update(x, 4);
+        }
+        assert_eq!(rms.get(), 0);
+        rms.update(-4, 4);
+        assert_eq!(rms.get(), 3);
+
+        // The RMS is held until the next window completes.
+        rms.update_errors([110, 90, 110], 100, 4);
+        assert_eq!(rms.get(), 3);
+        rms.update_errors([90], 100, 4);
+        assert_eq!(rms.get(), 10);
+
+        // A zero window is treated as one sample.
+        rms.update(-7, 0);
+        assert_eq!(rms.get(), 7);
+        rms.update(i32::MIN, 1);
+        assert_eq!(rms.get(), 1 << 31);
+    }
+}
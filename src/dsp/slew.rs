@@ -0,0 +1,63 @@
+//! DAC output slew-rate limiter
+//!
+//! # Design
+//! Some actuators (e.g. piezos with large capacitive loads or fragile mechanical mounts) are
+//! damaged by fast output steps even if the output level stays within its limits. The
+//! [SlewLimiter] bounds the change of the output between consecutive samples. A larger change is
+//! spread over as many samples as required, at the maximum rate. The limited output is kept as
+//! state so that the limit also holds across batch boundaries.
+
+/// Per-sample output rate limiter.
+#[derive(Copy, Clone, Default)]
+pub struct SlewLimiter {
+    y: i16,
+}
+
+impl SlewLimiter {
+    /// Limit the rate of change of a sample.
+    ///
+    /// # Args
+    /// * `x` - The unlimited sample.
+    /// * `max` - The maximum change per sample in LSB. Zero disables the limit.
+    ///
+    /// # Returns
+    /// The output after approaching `x` by at most `max` from the previous output.
+    pub fn update(&mut self, x: i16, max: u16) -> i16 {
+        self.y = if max == 0 {
+            x
+        } else {
+            let max = max as i32;
+            let delta = (x as i32 - self.y as i32).clamp(-max, max);
+            (self.y as i32 + delta) as i16
+        };
+        self.y
+    }
+
+    /// Set the previous output, e.g. after the output was overridden.
+    pub fn set(&mut self, y: i16) {
+        self.y = y;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_across_batches() {
+        let mut slew = SlewLimiter::default();
+        let mut batch =
+            |x| -> [i16; 4] { core::array::from_fn(|_| slew.update(x, 10)) };
+        assert_eq!(batch(35), [10, 20, 30, 35]);
+        assert_eq!(batch(-25), [25, 15, 5, -5]);
+        assert_eq!(batch(-25), [-15, -25, -25, -25]);
+
+        // Zero disables the limit and full scale steps do not overflow.
+        assert_eq!(slew.update(i16::MAX, 0), i16::MAX);
+        assert_eq!(slew.update(i16::MIN, u16::MAX), i16::MIN);
+
+        // An overridden output is the starting point of the next update.
+        slew.set(100);
+        assert_eq!(slew.update(0, 30), 70);
+    }
+}
@@ -0,0 +1,62 @@
+//! DAC output soft-start
+//!
+//! # Design
+//! When the outputs are enabled, the DACs jump from mid-scale (0 V) to the first computed value.
+//! This can kick sensitive actuators like piezos or laser current drivers. The [SoftStart] ramps
+//! an output gain linearly from 0 to 1 over a number of batches. The same gain is applied to all
+//! samples and channels of a batch so that related outputs (e.g. power and phase) stay
+//! consistent during the ramp.
+
+/// Linear output gain ramp.
+#[derive(Copy, Clone, Default)]
+pub struct SoftStart {
+    length: u32,
+    elapsed: u32,
+}
+
+impl SoftStart {
+    /// The gain at the end of the ramp.
+    pub const UNITY: i32 = 1 << 16;
+
+    /// Construct a new soft-start ramp.
+    ///
+    /// # Args
+    /// * `length` - The number of batches to ramp over. Zero disables the ramp.
+    pub fn new(length: u32) -> Self {
+        Self { length, elapsed: 0 }
+    }
+
+    /// Get the gain for the current batch and advance the ramp.
+    ///
+    /// # Returns
+    /// The gain with 16 fractional bits, ranging from 0 to [SoftStart::UNITY].
+    pub fn update(&mut self) -> i32 {
+        if self.elapsed >= self.length {
+            return Self::UNITY;
+        }
+
+        let gain = ((self.elapsed as u64) << 16) / self.length as u64;
+        self.elapsed += 1;
+        gain as i32
+    }
+
+    /// Scale an output value by a gain.
+    ///
+    /// # Args
+    /// * `gain` - The gain as returned by [SoftStart::update].
+    /// * `value` - The output value to scale.
+    #[inline]
+    pub fn apply(gain: i32, value: i16) -> i16 {
+        ((value as i32 * gain) >> 16) as i16
+    }
+
+    /// Scale a wide output value by a gain.
+    ///
+    /// # Args
+    /// * `gain` - The gain as returned by [SoftStart::update].
+    /// * `value` - The output value to scale, e.g. before saturation.
+    #[inline]
+    pub fn apply_wide(gain: i32, value: i32) -> i32 {
+        ((value as i64 * gain as i64) >> 16) as i32
+    }
+}
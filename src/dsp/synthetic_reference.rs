@@ -0,0 +1,51 @@
+//! Synthetic reference timestamps
+//!
+//! # Design
+//! To validate the PLL and the processing downstream of the timestamper without external
+//! hardware, a [SyntheticReference] generates the timestamps of an ideal reference with a known
+//! period. Each batch it reports the latest reference edge within the batch, just like the input
+//! capture of the timestamper would for a real reference. The recovered frequency can then be
+//! compared to the injected one.
+
+/// A deterministic ramp of reference timestamps.
+#[derive(Copy, Clone, Debug)]
+pub struct SyntheticReference {
+    period: u32,
+    next: u32,
+}
+
+impl SyntheticReference {
+    /// Construct a synthetic reference.
+    ///
+    /// # Args
+    /// * `period` - The reference period in timestamp timer ticks. It must be at least one batch
+    ///   period.
+    /// * `start` - The timestamp of the first reference edge.
+    pub fn new(period: u32, start: u32) -> Self {
+        Self {
+            period,
+            next: start,
+        }
+    }
+
+    /// Get the reference period in timestamp timer ticks.
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+
+    /// Get the latest reference edge of a batch.
+    ///
+    /// # Args
+    /// * `end` - The timestamp of the end of the batch.
+    ///
+    /// # Returns
+    /// The timestamp of the latest reference edge before `end` that has not been reported yet.
+    pub fn update(&mut self, end: u32) -> Option<u32> {
+        let mut latest = None;
+        while (end.wrapping_sub(self.next) as i32) > 0 {
+            latest = Some(self.next);
+            self.next = self.next.wrapping_add(self.period);
+        }
+        latest
+    }
+}
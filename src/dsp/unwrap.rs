@@ -0,0 +1,73 @@
+//! Phase unwrapping
+//!
+//! # Design
+//! Phases represented as `i32` wrap at `+-pi` (`i32::MIN`/`i32::MAX`). The [PhaseUnwrapper]
+//! tracks the previous phase and accumulates the wrapped phase differences into a wider
+//! accumulator, thereby removing the `2 pi` discontinuities.
+//!
+//! # Limitations
+//! The phase difference between two consecutive updates is always interpreted as the shortest
+//! difference in `[-pi, pi)`. Phase slews of more than `pi` between updates are genuinely
+//! ambiguous and are unwrapped incorrectly.
+
+/// Phase unwrapper accumulating the phase of consecutive updates.
+#[derive(Copy, Clone, Default)]
+pub struct PhaseUnwrapper {
+    previous: i32,
+    phase: i64,
+}
+
+impl PhaseUnwrapper {
+    /// Update the unwrapper with a new wrapped phase.
+    ///
+    /// # Args
+    /// * `phase` - The wrapped phase, where `i32::MIN` corresponds to `-pi`.
+    ///
+    /// # Returns
+    /// The unwrapped phase in the same units, i.e. one turn is `1 << 32`.
+    pub fn update(&mut self, phase: i32) -> i64 {
+        let delta = phase.wrapping_sub(self.previous);
+        self.previous = phase;
+        self.phase = self.phase.wrapping_add(delta as i64);
+        self.phase
+    }
+
+    /// Get the current unwrapped phase.
+    pub fn get(&self) -> i64 {
+        self.phase
+    }
+
+    /// Reset the unwrapped phase to the provided wrapped phase.
+    pub fn reset(&mut self, phase: i32) {
+        self.previous = phase;
+        self.phase = phase as i64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiple_turns() {
+        let mut unwrapper = PhaseUnwrapper::default();
+        let mut phase = 0i32;
+
+        // Three turns forward in quarter turns, crossing +pi three times.
+        for i in 1..=12 {
+            phase = phase.wrapping_add(1 << 30);
+            assert_eq!(unwrapper.update(phase), i << 30);
+        }
+
+        // Five turns backward in steps of 3/8 turn, crossing -pi five times.
+        for i in 1..=40 / 3 {
+            phase = phase.wrapping_sub(3 << 29);
+            assert_eq!(unwrapper.update(phase), (12 << 30) - (i * 3 << 29));
+        }
+        assert_eq!(unwrapper.get(), (12 << 30) - (13 * 3 << 29));
+
+        // A difference of exactly half a turn is interpreted as -pi.
+        unwrapper.reset(0);
+        assert_eq!(unwrapper.update(i32::MIN), -(1 << 31));
+    }
+}
@@ -65,9 +65,40 @@
 //! In this implementation, double buffer mode DMA transfers are used because the SPI RX FIFOs
 //! have finite depth, FIFO access is slower than AXISRAM access, and because the single
 //! buffer mode DMA disable/enable and buffer update sequence is slow.
+//!
+//! ## Overruns
+//!
+//! If the RX FIFO overflows, the SPI error interrupt is raised. The application calls
+//! `handle_error()` of the respective ADC from its interrupt handler. Samples lost during an
+//! overrun would permanently misalign the batches of the ADCs with each other and with the DAC
+//! batches, so the sample transfers are resynchronized:
+//!
+//! * The sampling timers are stopped, so no further samples are triggered.
+//! * The ADC and DAC sample DMA transfers are stopped.
+//! * The overrun flags are cleared and the RX FIFOs of both ADCs are flushed.
+//! * All sample transfers are rewound to the start of their first buffer and the sampling timers
+//!   are restarted, so the ADCs and DACs resume together at a batch boundary.
+//!
+//! The partially acquired batch is discarded and the DAC outputs hold their last value while
+//! sampling is stopped. Overruns are counted per ADC and reported by [overruns()]. The handler
+//! must run at the priority of the processing task so that it never preempts the processing of a
+//! batch, which accesses the inactive DMA buffers.
+//!
+//! With the `strict_overrun` feature, overruns panic instead, e.g. for debugging the processing
+//! time. The panic stops feeding the independent watchdog, which then resets the device. Other SPI
+//! errors always panic.
+//!
+//! The RX FIFO threshold is one sample: every sample requests a DMA transfer as soon as it is
+//! received, which gives the lowest latency. The FIFO depth of the ADC SPIs (16 bytes, i.e. 8
+//! samples) is fixed in hardware and only bridges stalls of the DMA on the bus matrix. A higher
+//! threshold would delay the samples without adding margin. The margin for the processing time is
+//! given by the double buffer, i.e. by the batch size.
+use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+
 use stm32h7xx_hal as hal;
 
 use mutex_trait::Mutex;
+use serde::{Deserialize, Serialize};
 
 use super::design_parameters::{SampleBuffer, MAX_SAMPLE_BUFFER_SIZE};
 use super::timers;
@@ -89,9 +120,9 @@ pub struct AdcCode(pub u16);
 impl AdcCode {
     // The ADC has a differential input with a range of +/- 4.096 V and 16-bit resolution.
     // The gain into the two inputs is 1/5.
-    const FULL_SCALE: f32 = 5.0 / 2.0 * 4.096;
-    const VOLT_PER_LSB: f32 = -Self::FULL_SCALE / i16::MIN as f32;
-    const LSB_PER_VOLT: f32 = 1. / Self::VOLT_PER_LSB;
+    pub const FULL_SCALE: f32 = 5.0 / 2.0 * 4.096;
+    pub const VOLT_PER_LSB: f32 = -Self::FULL_SCALE / i16::MIN as f32;
+    pub const LSB_PER_VOLT: f32 = 1. / Self::VOLT_PER_LSB;
 }
 
 impl From<u16> for AdcCode {
@@ -145,6 +176,111 @@ impl TryFrom<f32> for AdcCode {
     }
 }
 
+/// ADC offset and gain correction.
+///
+/// A corrected code is computed from the raw code as `(code - offset) * gain`.
+///
+/// # Miniconf
+/// `{"offset": <offset>, "gain": <gain>}`
+///
+/// * `<offset>` is the raw code read with zero input.
+/// * `<gain>` is the factor correcting the slope of the ADC transfer function. It must be within
+///   [AdcCalibration::GAIN_RANGE].
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct AdcCalibration {
+    pub offset: i16,
+    pub gain: f32,
+}
+
+impl Default for AdcCalibration {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            gain: 1.,
+        }
+    }
+}
+
+impl AdcCalibration {
+    /// The range of valid gain corrections.
+    pub const GAIN_RANGE: core::ops::RangeInclusive<f32> = 0.5..=2.;
+
+    /// Check whether the gain correction is finite and within [AdcCalibration::GAIN_RANGE].
+    pub fn is_valid(&self) -> bool {
+        Self::GAIN_RANGE.contains(&self.gain)
+    }
+
+    /// Replace invalid calibrations of a set of ADCs.
+    ///
+    /// # Args
+    /// * `calibrations` - The calibrations to check, indexed by ADC.
+    /// * `fallback` - The calibrations used in place of the invalid ones.
+    pub fn validate(calibrations: &mut [Self], fallback: &[Self]) {
+        for (i, (calibration, fallback)) in
+            calibrations.iter_mut().zip(fallback).enumerate()
+        {
+            if !calibration.is_valid() {
+                log::error!(
+                    "Invalid ADC{} calibration gain: {}",
+                    i,
+                    calibration.gain
+                );
+                *calibration = *fallback;
+            }
+        }
+    }
+
+    /// Compute the calibration from measurements with two known inputs.
+    ///
+    /// # Note
+    /// A known input is e.g. a grounded input for zero and a reference voltage converted with
+    /// [AdcCode::try_from] for the other point.
+    ///
+    /// # Args
+    /// * `zero` - The mean raw code read with zero input.
+    /// * `measured` - The mean raw code read with the known input.
+    /// * `expected` - The ideal code of the known input.
+    ///
+    /// # Returns
+    /// The calibration or `None` if the measurements do not determine a valid gain.
+    pub fn from_measurement(
+        zero: f32,
+        measured: f32,
+        expected: i16,
+    ) -> Option<Self> {
+        let span = measured - zero;
+        if span == 0. || !span.is_finite() || expected == 0 {
+            return None;
+        }
+        let offset =
+            zero.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        // The gain is the ratio of the slopes and does not depend on the rounding of the offset.
+        Some(Self {
+            offset,
+            gain: expected as f32 / span,
+        })
+        .filter(Self::is_valid)
+    }
+
+    /// Correct a batch of ADC samples in place.
+    ///
+    /// # Note
+    /// The gain is converted to fixed point once per batch and the samples are corrected with
+    /// integer arithmetic. Corrected codes saturate at the limits of the ADC range.
+    ///
+    /// # Args
+    /// * `samples` - The raw ADC samples to correct.
+    pub fn apply(&self, samples: &mut [u16]) {
+        const SHIFT: u32 = 16;
+        let gain = (self.gain * (1 << SHIFT) as f32) as i64;
+        let offset = self.offset as i64;
+        for sample in samples.iter_mut() {
+            let x = ((*sample as i16 as i64 - offset) * gain) >> SHIFT;
+            *sample = x.clamp(i16::MIN as i64, i16::MAX as i64) as i16 as u16;
+        }
+    }
+}
+
 // The following data is written by the timer ADC sample trigger into the SPI CR1 to start the
 // transfer. Data in AXI SRAM is not initialized on boot, so the contents are random. This value is
 // initialized during setup.
@@ -157,6 +293,17 @@ static mut SPI_START: [u32; 1] = [0x00; 1];
 #[link_section = ".axisram.buffers"]
 static mut SPI_EOT_CLEAR: [u32; 1] = [0x00];
 
+// The number of RX FIFO overruns of each ADC.
+static OVERRUNS: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+// The number of samples in a batch. The ADCs and DACs use the same batch size.
+static BATCH_SIZE: AtomicU16 = AtomicU16::new(0);
+
+// The DMA1 streams of the ADC and DAC sample transfers, see the `adc_input!()` and `dac_output!()`
+// invocations.
+#[cfg(not(feature = "strict_overrun"))]
+const SAMPLE_STREAMS: [usize; 4] = [1, 4, 6, 7];
+
 // The following global buffers are used for the ADC sample DMA transfers. Two buffers are used for
 // each transfer in a ping-pong buffer configuration (one is being acquired while the other is being
 // processed). Note that the contents of AXI SRAM is uninitialized, so the buffer contents on
@@ -386,6 +533,8 @@ macro_rules! adc_input {
                             data_config,
                         );
 
+                    BATCH_SIZE.store(batch_size as u16, Ordering::Relaxed);
+
                     Self {
                         transfer: data_transfer,
                         trigger_transfer,
@@ -419,6 +568,74 @@ macro_rules! adc_input {
                 {
                     unsafe { self.transfer.next_dbm_transfer_with(|buf, _current| f(buf)) }
                 }
+
+                /// Handle a SPI error interrupt.
+                ///
+                /// # Note
+                /// This is intended to be called from the SPI error interrupt handler at the
+                /// priority of the processing task. RX FIFO overruns resynchronize the sample
+                /// transfers of all ADCs and DACs unless the `strict_overrun` feature is enabled.
+                /// All other errors panic. Refer to the module documentation.
+                ///
+                /// # Returns
+                /// Whether sampling was restarted. The next batch starts with the return.
+                pub fn handle_error() -> bool {
+                    // Note(unsafe): The SPI is owned by the data transfer. Only the status
+                    // register is read here.
+                    let regs = unsafe { &*hal::stm32::$spi::ptr() };
+                    let sr = regs.sr.read();
+
+                    if sr.ovr().bit_is_clear() {
+                        // An overrun of the other ADC may have already been handled together
+                        // with this one.
+                        if sr.udr().bit_is_set()
+                            || sr.modf().bit_is_set()
+                            || sr.crce().bit_is_set()
+                            || sr.tifre().bit_is_set()
+                        {
+                            panic!("{} SPI error", stringify!($name));
+                        }
+                        return false;
+                    }
+
+                    #[cfg(feature = "strict_overrun")]
+                    panic!("{} overrun", stringify!($name));
+
+                    #[cfg(not(feature = "strict_overrun"))]
+                    {
+                        resynchronize();
+                        true
+                    }
+                }
+
+                /// Abort the ongoing transfer and discard all received samples.
+                ///
+                /// # Note
+                /// The sampling timer and the data transfer must be stopped.
+                ///
+                /// # Returns
+                /// Whether the RX FIFO has overrun.
+                #[cfg(not(feature = "strict_overrun"))]
+                fn flush() -> bool {
+                    // Note(unsafe): The data transfer is stopped and does not access the SPI.
+                    let regs = unsafe { &*hal::stm32::$spi::ptr() };
+                    let overrun = regs.sr.read().ovr().bit_is_set();
+
+                    // Disabling the SPI aborts the ongoing transfer.
+                    regs.cr1.modify(|_, w| w.spe().clear_bit());
+                    while regs.sr.read().rxp().bit_is_set() {
+                        // Note(unsafe): The RX FIFO is read with the configured 16-bit word size.
+                        unsafe {
+                            core::ptr::read_volatile(
+                                &regs.rxdr as *const _ as *const u16,
+                            )
+                        };
+                    }
+                    regs.ifcr.write(|w| w.ovrc().set_bit().eotc().set_bit());
+                    regs.cr1.modify(|_, w| w.spe().set_bit());
+
+                    overrun
+                }
             }
 
             // This is not actually a Mutex. It only re-uses the semantics and macros of mutex-trait
@@ -441,3 +658,125 @@ adc_input!(
     Adc1Input, 1, Stream3, Stream4, Stream5, SPI3, Channel2, Tim2Ch2, Channel2,
     Tim3Ch2
 );
+
+/// Get the number of RX FIFO overruns of each ADC since power-up.
+pub fn overruns() -> [u32; 2] {
+    [
+        OVERRUNS[0].load(Ordering::Relaxed),
+        OVERRUNS[1].load(Ordering::Relaxed),
+    ]
+}
+
+// Resynchronize the ADC and DAC sample transfers after an RX FIFO overrun. Refer to the module
+// documentation.
+#[cfg(not(feature = "strict_overrun"))]
+fn resynchronize() {
+    // Note(unsafe): This runs at the priority of the processing task, which is the only other
+    // user of the sample transfers. The sampling timers are only started once at startup, which
+    // has completed.
+    let tim2 = unsafe { &*hal::stm32::TIM2::ptr() };
+    let tim3 = unsafe { &*hal::stm32::TIM3::ptr() };
+    let dma = unsafe { &*hal::stm32::DMA1::ptr() };
+
+    tim2.cr1.modify(|_, w| w.cen().clear_bit());
+    tim3.cr1.modify(|_, w| w.cen().clear_bit());
+
+    for stream in SAMPLE_STREAMS {
+        let cr = &dma.st[stream].cr;
+        cr.modify(|_, w| w.en().clear_bit());
+        while cr.read().en().bit_is_set() {}
+    }
+
+    for (adc, overrun) in [Adc0Input::flush(), Adc1Input::flush()]
+        .into_iter()
+        .enumerate()
+    {
+        if overrun {
+            OVERRUNS[adc].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let batch_size = BATCH_SIZE.load(Ordering::Relaxed);
+    for stream in SAMPLE_STREAMS {
+        // Clear all flags of the stream. Streams 0 to 3 use LIFCR, streams 4 to 7 HIFCR, with the
+        // same flag offsets.
+        let flags = 0b11_1101 << [0, 6, 16, 22][stream % 4];
+        if stream < 4 {
+            dma.lifcr.write(|w| unsafe { w.bits(flags) });
+        } else {
+            dma.hifcr.write(|w| unsafe { w.bits(flags) });
+        }
+
+        let st = &dma.st[stream];
+        st.ndtr.write(|w| w.ndt().bits(batch_size));
+        st.cr.modify(|_, w| w.ct().clear_bit().en().set_bit());
+    }
+
+    // The batch completion flag of ADC1 was cleared above, drop its pending interrupt.
+    cortex_m::peripheral::NVIC::unpend(hal::stm32::Interrupt::DMA1_STR4);
+
+    // Restart like `SamplingTimer::start()`. The update event starts the shadow sampling timer.
+    tim3.cnt.reset();
+    tim2.egr.write(|w| w.ug().set_bit());
+    tim2.cnt.reset();
+    tim2.cr1.modify(|_, w| w.cen().set_bit());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibration_from_measurement() {
+        // The gain does not depend on the rounding of the offset.
+        let calibration =
+            AdcCalibration::from_measurement(0.25, 2000.25, 2000).unwrap();
+        assert_eq!(calibration.offset, 0);
+        assert_eq!(calibration.gain, 1.);
+
+        let calibration =
+            AdcCalibration::from_measurement(-10., 1590., 1600).unwrap();
+        assert_eq!(calibration.offset, -10);
+        assert_eq!(calibration.gain, 1.);
+
+        let mut samples = [AdcCode::from(1590).0];
+        calibration.apply(&mut samples);
+        assert_eq!(i16::from(AdcCode(samples[0])), 1600);
+
+        // Degenerate and out of range measurements.
+        assert!(AdcCalibration::from_measurement(5., 5., 1000).is_none());
+        assert!(AdcCalibration::from_measurement(0., 100., 1000).is_none());
+        assert!(AdcCalibration::from_measurement(0., f32::NAN, 1000).is_none());
+    }
+
+    #[test]
+    fn calibration_validity() {
+        let valid = |gain| AdcCalibration { offset: 0, gain }.is_valid();
+        assert!(valid(1.));
+        assert!(valid(0.5));
+        assert!(valid(2.));
+        assert!(!valid(0.));
+        assert!(!valid(-1.));
+        assert!(!valid(2.5));
+        assert!(!valid(f32::NAN));
+        assert!(!valid(f32::INFINITY));
+
+        let mut calibrations = [
+            AdcCalibration {
+                offset: 3,
+                gain: 1.5,
+            },
+            AdcCalibration {
+                offset: 3,
+                gain: f32::NAN,
+            },
+        ];
+        AdcCalibration::validate(
+            &mut calibrations,
+            &[AdcCalibration::default(); 2],
+        );
+        assert_eq!(calibrations[0].gain, 1.5);
+        assert_eq!(calibrations[1].offset, 0);
+        assert_eq!(calibrations[1].gain, 1.);
+    }
+}
@@ -30,6 +30,7 @@ use super::{hal, timers};
 pub struct InputStamper {
     _di0_trigger: hal::gpio::gpioa::PA3<hal::gpio::Alternate<2>>,
     capture_channel: timers::tim5::Channel4InputCapture,
+    overcaptures: u32,
 }
 
 impl InputStamper {
@@ -54,11 +55,11 @@ impl InputStamper {
         Self {
             capture_channel: input_capture,
             _di0_trigger: trigger,
+            overcaptures: 0,
         }
     }
 
     /// Start to capture timestamps on DI0.
-    #[allow(dead_code)]
     pub fn start(&mut self) {
         self.capture_channel.enable();
     }
@@ -70,8 +71,33 @@ impl InputStamper {
     /// If an over-capture event occurs, this function will clear the overflow,
     /// and return a new timestamp of unknown recency an `Err()`.
     /// Note that this indicates at least one timestamp was inadvertently dropped.
-    #[allow(dead_code)]
     pub fn latest_timestamp(&mut self) -> Result<Option<u32>, Option<u32>> {
-        self.capture_channel.latest_capture()
+        let result = self.capture_channel.latest_capture();
+        if result.is_err() {
+            self.overcaptures = self.overcaptures.wrapping_add(1);
+        }
+        result
+    }
+
+    /// Get the latest timestamp that has occurred.
+    ///
+    /// # Note
+    /// This function must be called at least as often as timestamps arrive. In contrast to
+    /// `latest_timestamp()`, an over-capture is reported explicitly.
+    pub fn latest_timestamp_ext(&mut self) -> timers::CaptureResult<u32> {
+        let result = self.capture_channel.latest_capture_ext();
+        if let timers::CaptureResult::Overcapture(_) = result {
+            self.overcaptures = self.overcaptures.wrapping_add(1);
+        }
+        result
+    }
+
+    /// Get the number of over-capture events.
+    ///
+    /// # Note
+    /// Each over-capture indicates that at least one timestamp was lost, e.g. because the
+    /// reference is faster than the timestamps are read. The count wraps around on overflow.
+    pub fn overcaptures(&self) -> u32 {
+        self.overcaptures
     }
 }
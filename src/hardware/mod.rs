@@ -12,6 +12,7 @@ pub mod design_parameters;
 mod eeprom;
 pub mod flash;
 pub mod input_stamper;
+pub mod output_stage;
 pub mod platform;
 pub mod pounder;
 pub mod setup;
@@ -79,6 +80,16 @@ pub const MONOTONIC_FREQUENCY: u32 = 1_000;
 pub type Systick = systick_monotonic::Systick<MONOTONIC_FREQUENCY>;
 pub type SystemTimer = mono_clock::MonoClock<u32, MONOTONIC_FREQUENCY>;
 
+/// Independent watchdog timeout in milliseconds. The watchdog resets the device if it is not fed
+/// from the idle loop within this time, e.g. because a higher priority task hangs.
+///
+/// # Note
+/// Committing settings to flash blocks the idle loop. Storing a settings item may require a
+/// flash sector erase, which takes up to 4 s (see the STM32H743 datasheet). The timeout covers
+/// two of them so that the device is not reset in the middle of writing its settings.
+pub const WATCHDOG_TIMEOUT_MS: u32 = 10_000;
+pub type Watchdog = hal::independent_watchdog::IndependentWatchdog;
+
 pub type I2c1 = hal::i2c::I2c<hal::stm32::I2C1>;
 pub type I2c1Proxy =
     shared_bus::I2cProxy<'static, shared_bus::AtomicCheckMutex<I2c1>>;
@@ -0,0 +1,208 @@
+//! DAC output stage
+//!
+//! # Design
+//! All applications process their DAC outputs in the same final stage: the output offset is
+//! added, the soft-start gain is applied, the output is clamped to its limit and finally
+//! slew-rate limited before it is written to the DAC. The [OutputStage] keeps the soft-start and
+//! slew-rate state of all channels. The soft-start gain is common to all channels of a batch so
+//! that related outputs (e.g. power and phase) stay consistent during the ramp.
+//!
+//! The stage operates in place on the DAC codes of a batch. Applications write their saturated
+//! outputs to the DAC buffers and then apply the stage.
+use super::dac::DacCode;
+use crate::dsp::{slew::SlewLimiter, soft_start::SoftStart};
+
+// The configuration of an output channel.
+#[derive(Copy, Clone, Debug)]
+struct Config {
+    offset: i16,
+    limit: i16,
+    slew_limit: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            limit: i16::MAX,
+            slew_limit: 0,
+        }
+    }
+}
+
+/// The final processing stage of `N` DAC output channels.
+#[derive(Copy, Clone)]
+pub struct OutputStage<const N: usize> {
+    soft_start: SoftStart,
+    gain: i32,
+    config: [Config; N],
+    slew: [SlewLimiter; N],
+}
+
+impl<const N: usize> OutputStage<N> {
+    /// Construct a new output stage.
+    ///
+    /// # Args
+    /// * `soft_start` - The number of batches to ramp the outputs up over. Zero disables the
+    ///   ramp.
+    pub fn new(soft_start: u32) -> Self {
+        Self {
+            soft_start: SoftStart::new(soft_start),
+            gain: 0,
+            config: [Config::default(); N],
+            slew: [SlewLimiter::default(); N],
+        }
+    }
+
+    /// Advance to the next batch.
+    ///
+    /// # Note
+    /// This must be called once at the start of every batch before the outputs are processed.
+    ///
+    /// # Args
+    /// * `offset` - The output offsets in volts. Offsets beyond the DAC range saturate.
+    /// * `limit` - The maximum output magnitudes as a fraction of the DAC full scale.
+    /// * `slew_limit` - The maximum output changes per sample in LSB. Zero disables the limit.
+    pub fn update(
+        &mut self,
+        offset: &[f32; N],
+        limit: &[f32; N],
+        slew_limit: &[u16; N],
+    ) {
+        self.gain = self.soft_start.update();
+        self.config = core::array::from_fn(|channel| Config {
+            offset: (offset[channel] * DacCode::LSB_PER_VOLT) as i16,
+            limit: (limit[channel].max(0.) * i16::MAX as f32) as i16,
+            slew_limit: slew_limit[channel],
+        });
+    }
+
+    /// Get the commanded output before saturation.
+    ///
+    /// # Args
+    /// * `channel` - The output channel.
+    /// * `x` - The output of the application in DAC LSB.
+    ///
+    /// # Returns
+    /// The output including the offset and the soft-start gain, but not saturated, clamped or
+    /// slew-rate limited.
+    pub fn command(&self, channel: usize, x: i32) -> i32 {
+        SoftStart::apply_wide(
+            self.gain,
+            x.saturating_add(self.config[channel].offset as i32),
+        )
+    }
+
+    /// Add the offset and apply the soft-start gain to a batch of DAC codes.
+    ///
+    /// # Args
+    /// * `channel` - The output channel.
+    /// * `samples` - The DAC codes to process in place.
+    pub fn scale(&self, channel: usize, samples: &mut [u16]) {
+        let offset = self.config[channel].offset;
+        for sample in samples.iter_mut() {
+            let y = i16::from(DacCode(*sample)).saturating_add(offset);
+            *sample = DacCode::from(SoftStart::apply(self.gain, y)).0;
+        }
+    }
+
+    /// Clamp a batch of DAC codes to the output limit and limit their rate of change.
+    ///
+    /// # Args
+    /// * `channel` - The output channel.
+    /// * `samples` - The DAC codes to process in place.
+    pub fn limit(&mut self, channel: usize, samples: &mut [u16]) {
+        let Config {
+            limit, slew_limit, ..
+        } = self.config[channel];
+        for sample in samples.iter_mut() {
+            let y = i16::from(DacCode(*sample)).clamp(-limit, limit);
+            *sample = DacCode::from(self.slew[channel].update(y, slew_limit)).0;
+        }
+    }
+
+    /// Process a batch of DAC codes with the complete output stage.
+    ///
+    /// # Args
+    /// * `channel` - The output channel.
+    /// * `samples` - The DAC codes to process in place.
+    pub fn apply(&mut self, channel: usize, samples: &mut [u16]) {
+        self.scale(channel, samples);
+        self.limit(channel, samples);
+    }
+
+    /// Set the previous output of a channel, e.g. after the output was overridden.
+    ///
+    /// # Args
+    /// * `channel` - The output channel.
+    /// * `code` - The DAC code the slew-rate limit continues from.
+    pub fn set(&mut self, channel: usize, code: u16) {
+        self.slew[channel].set(DacCode(code).into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes<const M: usize>(values: [i16; M]) -> [u16; M] {
+        values.map(|value| DacCode::from(value).0)
+    }
+
+    #[test]
+    fn offset_limit_slew() {
+        let mut output = OutputStage::<2>::new(0);
+        output.update(&[0.; 2], &[1.; 2], &[0; 2]);
+        output.config = [
+            Config {
+                offset: 100,
+                limit: 1000,
+                slew_limit: 0,
+            },
+            Config {
+                offset: 0,
+                limit: i16::MAX,
+                slew_limit: 300,
+            },
+        ];
+
+        // The offset saturates before it is clamped to the limit.
+        let mut samples = codes([0, 950, i16::MAX, -2000]);
+        output.apply(0, &mut samples);
+        assert_eq!(samples, codes([100, 1000, 1000, -1000]));
+
+        let mut samples = codes([1000; 4]);
+        output.apply(1, &mut samples);
+        assert_eq!(samples, codes([300, 600, 900, 1000]));
+
+        // The slew-rate limit continues from an overridden output.
+        output.set(1, DacCode::from(0).0);
+        let mut samples = codes([1000]);
+        output.limit(1, &mut samples);
+        assert_eq!(samples, codes([300]));
+
+        assert_eq!(output.command(0, i16::MAX as i32), i16::MAX as i32 + 100);
+    }
+
+    #[test]
+    fn soft_start() {
+        let mut output = OutputStage::<2>::new(4);
+        let outputs: [[i16; 2]; 5] = core::array::from_fn(|_| {
+            output.update(&[0.; 2], &[1.; 2], &[0; 2]);
+            let mut samples = codes([4000, -4000]);
+            output.scale(0, &mut samples[..1]);
+            output.scale(1, &mut samples[1..]);
+            samples.map(|code| DacCode(code).into())
+        });
+        assert_eq!(
+            outputs,
+            [
+                [0, 0],
+                [1000, -1000],
+                [2000, -2000],
+                [3000, -3000],
+                [4000, -4000]
+            ]
+        );
+    }
+}
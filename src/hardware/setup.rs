@@ -18,11 +18,13 @@ use super::{
     pounder::dds_output::DdsOutput, shared_adc::SharedAdc, timers,
     DigitalInput0, DigitalInput1, EemDigitalInput0, EemDigitalInput1,
     EemDigitalOutput0, EemDigitalOutput1, EthernetPhy, NetworkStack,
-    SerialTerminal, SystemTimer, Systick, UsbBus, UsbDevice, AFE0, AFE1,
+    SerialTerminal, SystemTimer, Systick, UsbBus, UsbDevice, Watchdog, AFE0,
+    AFE1,
 };
 
 const NUM_TCP_SOCKETS: usize = 4;
-const NUM_UDP_SOCKETS: usize = 1;
+// One UDP socket for each of the primary and the split data stream.
+const NUM_UDP_SOCKETS: usize = 2;
 const NUM_SOCKETS: usize = NUM_UDP_SOCKETS + NUM_TCP_SOCKETS;
 
 pub struct NetStorage {
@@ -104,6 +106,22 @@ pub struct EemGpioDevices {
     pub lvds7: EemDigitalOutput1,
 }
 
+/// The spare output-compare channels of the shadow sampling timer (TIM3).
+///
+/// # Note
+/// The shadow sampling timer is locked to the ADC/DAC sample period, so any PWM waveform generated
+/// on these channels has a period of one sample.
+pub struct PwmOutputs {
+    /// TIM3 channel 3, routed to PB0.
+    pub ch3: timers::tim3::Channel3,
+    /// TIM3 channel 4, routed to PB1.
+    pub ch4: timers::tim3::Channel4,
+    _pins: (
+        hal::gpio::gpiob::PB0<hal::gpio::Alternate<2>>,
+        hal::gpio::gpiob::PB1<hal::gpio::Alternate<2>>,
+    ),
+}
+
 /// The available hardware interfaces on Stabilizer.
 pub struct StabilizerDevices {
     pub systick: Systick,
@@ -114,11 +132,16 @@ pub struct StabilizerDevices {
     pub timestamper: InputStamper,
     pub adc_dac_timer: timers::SamplingTimer,
     pub timestamp_timer: timers::TimestampTimer,
+    /// The divider applied to the sampling and timestamp timer clocks.
+    pub sample_divider: u16,
     pub net: NetworkDevices,
     pub digital_inputs: (DigitalInput0, DigitalInput1),
     pub eem_gpio: EemGpioDevices,
+    pub pwm_outputs: PwmOutputs,
     pub usb_serial: SerialTerminal,
     pub usb: UsbDevice,
+    /// The independent watchdog. It is not started by `setup()`.
+    pub watchdog: Watchdog,
 }
 
 /// The available Pounder-specific hardware interfaces.
@@ -186,6 +209,14 @@ fn load_itcm() {
 /// # Note
 /// Refer to [design_parameters::TIMER_FREQUENCY] to determine the frequency of the sampling timer.
 ///
+/// The sampling and timestamp timers are additionally slowed down by the `sample_divider` of the
+/// device settings stored in flash. This allows running the same firmware at different sample
+/// rates on different devices. The effective divider is reported in
+/// [StabilizerDevices::sample_divider]. All quantities expressed in sample periods or timestamp
+/// timer ticks (e.g. PLL time constants, IIR/lockin filter coefficients, reference period limits)
+/// scale accordingly: their bandwidth in Hz is reduced by the divider. The PWM outputs on TIM3 are
+/// slowed down as well.
+///
 /// # Args
 /// * `core` - The cortex-m peripherals.
 /// * `device` - The microcontroller peripherals to be configured.
@@ -295,6 +326,10 @@ pub fn setup(
     // After ITCM loading.
     core.SCB.enable_icache();
 
+    // The cycle counter is used to measure the execution time of the DSP processing.
+    core.DCB.enable_trace();
+    core.DWT.enable_cycle_counter();
+
     let mut delay = delay::AsmDelay::new(ccdr.clocks.c_ck().to_Hz());
 
     let gpioa = device.GPIOA.split(ccdr.peripheral.GPIOA);
@@ -362,10 +397,7 @@ pub fn setup(
         // mode".
         // For TIM3, TIM2 can be made the internal trigger connection using ITR1. Thus, the
         // SamplingTimer start now gates the start of the ShadowSamplingTimer.
-        shadow_sampling_timer.set_slave_mode(
-            timers::TriggerSource::Trigger1,
-            timers::SlaveMode::Trigger,
-        );
+        shadow_sampling_timer.synchronize_to(timers::TriggerSource::Trigger1);
 
         shadow_sampling_timer
     };
@@ -555,6 +587,28 @@ pub fn setup(
         (dac0, dac1)
     };
 
+    // A stream can not be assigned twice because the streams are owned by their transfers. The
+    // request lines, however, are hard-coded by the drivers. Verify that no two configured
+    // streams are triggered by the same request, which would silently corrupt the sample
+    // transfers. Request 0 indicates an unused stream without a request.
+    {
+        // Note(unsafe): The DMAMUX configuration is only read.
+        let dmamux = unsafe { &*hal::stm32::DMAMUX1::ptr() };
+        let requests: [u8; 8] =
+            core::array::from_fn(|i| dmamux.ccr[i].read().dmareq_id().bits());
+        for (i, request) in requests.iter().enumerate() {
+            if *request == 0 {
+                continue;
+            }
+            if let Some(j) = requests[..i].iter().position(|r| r == request) {
+                panic!(
+                    "DMA1 streams {} and {} share DMA request line {}",
+                    j, i, request
+                );
+            }
+        }
+    }
+
     let afes = {
         // AFE_PWR_ON on hardware revision v1.3.2
         gpioe.pe1.into_push_pull_output().set_high();
@@ -1015,6 +1069,12 @@ pub fn setup(
         lvds7: gpiod.pd4.into_push_pull_output(),
     };
 
+    let pwm_outputs = PwmOutputs {
+        ch3: shadow_sampling_timer_channels.ch3,
+        ch4: shadow_sampling_timer_channels.ch4,
+        _pins: (gpiob.pb0.into_alternate(), gpiob.pb1.into_alternate()),
+    };
+
     let (usb_device, usb_serial) = {
         let usb_bus = cortex_m::singleton!(: Option<usb_device::bus::UsbBusAllocator<UsbBus>> = None).unwrap();
         let endpoint_memory =
@@ -1078,7 +1138,7 @@ pub fn setup(
         (usb_device, serial)
     };
 
-    let usb_serial = {
+    let (usb_serial, sample_divider) = {
         let (_, flash_bank2) = device.FLASH.split();
 
         let input_buffer =
@@ -1091,7 +1151,22 @@ pub fn setup(
             crate::settings::Settings::new(network_devices.mac_address);
         settings.reload(&mut storage);
 
-        serial_settings::Runner::new(
+        // Validate the sample rate divider. The batch period must not exceed the RTIC Monotonic
+        // timer period.
+        let sample_divider = settings.sample_divider;
+        let batch_period = (batch_size as u32 * sample_ticks) as f32
+            * sample_divider as f32
+            * design_parameters::TIMER_PERIOD;
+        let sample_divider = if sample_divider == 0
+            || batch_period * (super::MONOTONIC_FREQUENCY as f32) >= 1.
+        {
+            log::warn!("Invalid sample divider {}, using 1", sample_divider);
+            1
+        } else {
+            sample_divider
+        };
+
+        let runner = serial_settings::Runner::new(
             crate::settings::SerialSettingsPlatform {
                 interface: serial_settings::BestEffortInterface::new(
                     usb_serial,
@@ -1102,9 +1177,25 @@ pub fn setup(
             input_buffer,
             serialize_buffer,
         )
-        .unwrap()
+        .unwrap();
+
+        (runner, sample_divider)
     };
 
+    // Slow down the sampling and timestamp timers. All timers count at the same rate, so the
+    // sampling and timestamp timer ticks stay commensurate.
+    {
+        let prescaler = (sampling_timer.get_prescaler() as u32 + 1)
+            * sample_divider as u32
+            - 1;
+        // Note(unwrap): The divider was validated against the monotonic timer period above,
+        // which is far lower than the prescaler limit.
+        let prescaler = u16::try_from(prescaler).unwrap();
+        sampling_timer.set_prescaler(prescaler);
+        shadow_sampling_timer.set_prescaler(prescaler);
+        timestamp_timer.set_prescaler(prescaler);
+    }
+
     let stabilizer = StabilizerDevices {
         systick,
         afes,
@@ -1117,10 +1208,13 @@ pub fn setup(
         net: network_devices,
         adc_dac_timer: sampling_timer,
         timestamp_timer,
+        sample_divider,
         digital_inputs,
         eem_gpio,
+        pwm_outputs,
         usb: usb_device,
         usb_serial,
+        watchdog: Watchdog::new(device.IWDG),
     };
 
     // info!("Version {} {}", build_info::PKG_VERSION, build_info::GIT_VERSION.unwrap());
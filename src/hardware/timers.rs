@@ -51,6 +51,54 @@ pub enum SlaveMode {
     Trigger = 0b0110,
 }
 
+/// Quadrature encoder interface modes of a timer.
+#[allow(dead_code)]
+pub enum EncoderMode {
+    /// Count on TI1 edges only, depending on the level of TI2.
+    Ti1 = 0b001,
+    /// Count on TI2 edges only, depending on the level of TI1.
+    Ti2 = 0b010,
+    /// Count on both TI1 and TI2 edges.
+    Ti1Ti2 = 0b011,
+}
+
+/// The counting direction of a timer.
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// The first register transferred in a DMA burst, as an offset in words from `CR1`.
+#[allow(dead_code)]
+pub enum DmaBase {
+    Cnt = 9,
+    Ccr1 = 13,
+    Ccr2 = 14,
+    Ccr3 = 15,
+    Ccr4 = 16,
+}
+
+/// The active level of a timer output channel.
+#[allow(dead_code)]
+pub enum Polarity {
+    ActiveHigh = 0,
+    ActiveLow = 1,
+}
+
+/// The outcome of reading an input capture channel.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CaptureResult<T> {
+    /// No capture has occurred since the last read.
+    Empty,
+    /// A single capture has occurred since the last read.
+    Captured(T),
+    /// More than one capture has occurred since the last read and at least one was lost. The
+    /// latest capture value is provided if available.
+    Overcapture(Option<T>),
+}
+
 /// Optional input capture preconditioning filter configurations.
 #[allow(dead_code)]
 pub enum InputFilter {
@@ -106,6 +154,20 @@ macro_rules! timer_channels {
                     regs.arr.read().arr().bits()
                 }
 
+                /// Get the prescaler of the timer.
+                #[allow(dead_code)]
+                pub fn get_prescaler(&self) -> u16 {
+                    let regs = unsafe { &*hal::stm32::$TY::ptr() };
+                    regs.psc.read().psc().bits()
+                }
+
+                /// Get the current value of the timer counter.
+                #[allow(dead_code)]
+                pub fn get_counter(&self) -> $size {
+                    let regs = unsafe { &*hal::stm32::$TY::ptr() };
+                    regs.cnt.read().cnt().bits()
+                }
+
                 /// Manually set the period of the timer.
                 #[allow(dead_code)]
                 pub fn set_period_ticks(&mut self, period: $size) {
@@ -116,6 +178,25 @@ macro_rules! timer_channels {
                     self.timer.apply_freq();
                 }
 
+                /// Manually set the prescaler of the timer.
+                ///
+                /// # Note
+                /// The prescaler divides the timer input clock before it reaches the counter. The
+                /// resulting timer period is `(psc + 1) * (period + 1)` input clock ticks, where
+                /// `period` is the value configured with `set_period_ticks()`. Both settings are
+                /// independent of each other.
+                ///
+                /// # Args
+                /// * `psc` - The prescaler value. The input clock is divided by `psc + 1`.
+                #[allow(dead_code)]
+                pub fn set_prescaler(&mut self, psc: u16) {
+                    let regs = unsafe { &*hal::stm32::$TY::ptr() };
+                    regs.psc.write(|w| w.psc().bits(psc));
+
+                    // Force the new prescaler to take effect immediately.
+                    self.timer.apply_freq();
+                }
+
                 /// Clock the timer from an external source.
                 ///
                 /// # Note:
@@ -169,6 +250,162 @@ macro_rules! timer_channels {
                     // that they are always in range.
                     regs.smcr.modify(|_, w| unsafe { w.sms().bits(mode as u8).ts().bits(source as u8) } );
                 }
+
+                /// Configure the timer as a master timer generating a trigger output on the
+                /// provided event.
+                ///
+                /// # Args
+                /// * `event` - The event that generates the trigger output (TRGO).
+                #[allow(dead_code)]
+                pub fn as_master(&mut self, event: TriggerGenerator) {
+                    self.generate_trigger(event);
+                }
+
+                /// Synchronize the timer to a master timer.
+                ///
+                /// # Note
+                /// The timer is operated in trigger slave mode, so the counter starts when the
+                /// master timer generates a trigger. Refer to the reference manual for the internal
+                /// trigger connections (ITRx) between the timers.
+                ///
+                /// # Args
+                /// * `master_trigger` - The internal trigger input connected to the master timer.
+                #[allow(dead_code)]
+                pub fn synchronize_to(&mut self, master_trigger: TriggerSource) {
+                    self.set_slave_mode(master_trigger, SlaveMode::Trigger);
+                }
+
+                /// Configure the timer to generate a single output pulse on channel 1 when fired.
+                ///
+                /// # Note
+                /// Channel 1 is operated in PWM mode 2 with the timer in one-pulse mode, so the
+                /// timer channels must not have been taken. The timer stops automatically at the
+                /// end of the pulse and remains armed for the next call to `fire()`.
+                ///
+                /// Advanced-control timers (TIM8) additionally require the main output enable to be
+                /// set before the output is driven.
+                ///
+                /// # Args
+                /// * `delay` - The number of timer ticks between firing and the start of the pulse.
+                /// * `width` - The number of timer ticks the pulse is active for.
+                #[allow(dead_code)]
+                pub fn configure_one_pulse(&mut self, delay: $size, width: $size) {
+                    assert!(self.channels.is_some());
+                    assert!(width > 0);
+                    let period = delay.checked_add(width - 1).unwrap();
+
+                    self.timer.pause();
+                    self.timer.reset_counter();
+
+                    let regs = unsafe { &*hal::stm32::$TY::ptr() };
+                    regs.cr1.modify(|_, w| w.opm().set_bit());
+                    regs.arr.write(|w| w.arr().bits(period));
+                    regs.ccr[0].write(|w| w.ccr().bits(delay));
+
+                    // Note(unsafe): 0b111 (PWM mode 2) is a valid output compare mode.
+                    #[allow(unused_unsafe)]
+                    regs.ccmr1_output().modify(|_, w| unsafe { w.cc1s().bits(0).oc1m().bits(0b111).oc1pe().clear_bit() });
+                    regs.ccer.modify(|_, w| w.cc1p().clear_bit().cc1e().set_bit());
+                }
+
+                /// Fire a single pulse previously configured with `configure_one_pulse()`.
+                ///
+                /// # Note
+                /// Any pulse that is still in progress is aborted and the pulse is restarted from
+                /// the beginning of the delay.
+                #[allow(dead_code)]
+                pub fn fire(&mut self) {
+                    // Ensure the counter is disabled before re-arming. In one-pulse mode, CEN is
+                    // cleared by hardware at the end of the pulse, but it may still be set if the
+                    // previous pulse has not completed yet.
+                    self.timer.pause();
+                    self.timer.reset_counter();
+                    self.timer.resume();
+                }
+
+                /// Configure DMA burst transfers of consecutive timer registers.
+                ///
+                /// # Note
+                /// Once configured, each DMA request transfers `length` consecutive registers
+                /// starting from `base`. The DMA stream must use the burst address returned by
+                /// `dma_burst_address()` as its peripheral address.
+                ///
+                /// # Args
+                /// * `base` - The first register to transfer.
+                /// * `length` - The number of registers to transfer for each DMA request (1 to 18).
+                #[allow(dead_code)]
+                pub fn configure_dma_burst(&mut self, base: DmaBase, length: u8) {
+                    assert!((1..=18).contains(&length));
+                    let regs = unsafe { &*hal::stm32::$TY::ptr() };
+
+                    // Note(unsafe): The DmaBase enumeration is specified such that this is always
+                    // in range and the burst length is checked above.
+                    #[allow(unused_unsafe)]
+                    regs.dcr.write(|w| unsafe { w.dba().bits(base as u8).dbl().bits(length - 1) });
+                }
+
+                /// Get the address of the DMA burst register for use as a DMA peripheral address.
+                #[allow(dead_code)]
+                pub fn dma_burst_address(&self) -> usize {
+                    let regs = unsafe { &*hal::stm32::$TY::ptr() };
+                    &regs.dmar as *const _ as usize
+                }
+
+                /// Operate the timer as a quadrature encoder interface.
+                ///
+                /// # Note
+                /// Channels 1 and 2 are used as the encoder inputs (TI1 and TI2), so the timer
+                /// channels must not have been taken. The counter wraps at the full range of the
+                /// timer and starts counting immediately.
+                ///
+                /// # Args
+                /// * `mode` - The encoder mode selecting which input edges are counted.
+                #[allow(dead_code)]
+                pub fn into_encoder(mut self, mode: EncoderMode) -> [< $name Encoder >] {
+                    assert!(self.channels.is_some());
+                    self.timer.pause();
+
+                    let regs = unsafe { &*hal::stm32::$TY::ptr() };
+
+                    // Note(unsafe): 0b01 maps IC1 to TI1 and IC2 to TI2.
+                    #[allow(unused_unsafe)]
+                    regs.ccmr1_input().modify(|_, w| unsafe { w.cc1s().bits(0b01).cc2s().bits(0b01) });
+
+                    // Note(unsafe): The EncoderMode enumeration is specified such that this is
+                    // always in range.
+                    regs.smcr.modify(|_, w| unsafe { w.sms().bits(mode as u8) });
+
+                    regs.arr.write(|w| w.arr().bits(<$size>::MAX));
+                    self.timer.reset_counter();
+                    self.timer.resume();
+
+                    [< $name Encoder >] { _timer: self.timer }
+                }
+            }
+
+            /// A timer operating as a quadrature encoder interface.
+            pub struct [< $name Encoder >] {
+                _timer: hal::timer::Timer<hal::stm32::[< $TY >]>,
+            }
+
+            impl [< $name Encoder >] {
+                /// Get the current encoder count.
+                #[allow(dead_code)]
+                pub fn count(&self) -> $size {
+                    let regs = unsafe { &*hal::stm32::$TY::ptr() };
+                    regs.cnt.read().cnt().bits()
+                }
+
+                /// Get the direction of the most recent encoder count.
+                #[allow(dead_code)]
+                pub fn direction(&self) -> Direction {
+                    let regs = unsafe { &*hal::stm32::$TY::ptr() };
+                    if regs.cr1.read().dir().bit_is_set() {
+                        Direction::Down
+                    } else {
+                        Direction::Up
+                    }
+                }
             }
 
             pub mod [< $TY:lower >] {
@@ -277,6 +514,62 @@ macro_rules! timer_channels {
                         .modify(|_, w| unsafe { w.[< cc $index s >]().bits(0) });
                 }
 
+                /// Operate the channel as a PWM output.
+                ///
+                /// # Note
+                /// The channel is configured in PWM mode 1: the output is active while the counter
+                /// is less than `value` and inactive otherwise. The PWM period is the period of the
+                /// timer. The compare register is preloaded, so updates to `value` take effect on
+                /// the next update event without glitching the current period.
+                ///
+                /// Advanced-control timers (TIM8) additionally require the main output enable to be
+                /// set before the output is driven.
+                ///
+                /// # Args
+                /// * `value` - The number of timer ticks the output is active for each period.
+                /// * `polarity` - The active level of the output.
+                #[allow(dead_code)]
+                pub fn to_pwm(&self, value: $size, polarity: super::Polarity) {
+                    let regs = unsafe { &*<$TY>::ptr() };
+                    let arr = regs.arr.read().bits() as $size;
+                    assert!(value <= arr);
+                    regs.ccr[$index - 1].write(|w| w.ccr().bits(value));
+
+                    // Note(unsafe): 0b110 (PWM mode 1) is a valid output compare mode.
+                    #[allow(unused_unsafe)]
+                    regs.[< $ccmrx _output >]().modify(|_, w| unsafe {
+                        w.[< cc $index s >]()
+                            .bits(0)
+                            .[< oc $index m >]()
+                            .bits(0b110)
+                            .[< oc $index pe >]()
+                            .set_bit()
+                    });
+
+                    regs.ccer.modify(|_, w| {
+                        w.[< cc $index p >]()
+                            .bit(matches!(polarity, super::Polarity::ActiveLow))
+                            .[< cc $index e >]()
+                            .set_bit()
+                    });
+                }
+
+                /// Configure the input capture pre-filter before converting the channel to an
+                /// input capture.
+                ///
+                /// # Note
+                /// The filter configuration shares its bitfield with the output-compare mode, so it
+                /// only takes effect once the channel is converted using `into_input_capture()`.
+                ///
+                /// # Args
+                /// * `filter` - The desired input filter stage configuration.
+                #[allow(dead_code)]
+                pub fn with_filter(self, filter: super::InputFilter) -> Self {
+                    let regs = unsafe { &*<$TY>::ptr() };
+                    regs.[< $ccmrx _input >]().modify(|_, w| w.[< ic $index f >]().bits(filter as u8));
+                    self
+                }
+
                 /// Operate the channel in input-capture mode.
                 ///
                 /// # Args
@@ -319,6 +612,20 @@ macro_rules! timer_channels {
                     }
                 }
 
+                /// Get the latest capture from the channel.
+                ///
+                /// # Note
+                /// This is equivalent to `latest_capture()`, but distinguishes the over-capture
+                /// case explicitly.
+                #[allow(dead_code)]
+                pub fn latest_capture_ext(&mut self) -> super::CaptureResult<$size> {
+                    match self.latest_capture() {
+                        Ok(None) => super::CaptureResult::Empty,
+                        Ok(Some(value)) => super::CaptureResult::Captured(value),
+                        Err(value) => super::CaptureResult::Overcapture(value),
+                    }
+                }
+
                 /// Allow the channel to generate DMA requests.
                 #[allow(dead_code)]
                 pub fn listen_dma(&self) {
@@ -1,6 +1,7 @@
 #![no_std]
 #![cfg_attr(feature = "nightly", feature(core_intrinsics))]
 
+pub mod dsp;
 pub mod hardware;
 pub mod net;
 pub mod settings;
@@ -19,6 +19,42 @@
 //! * **Sequence Number** (u32): an the sequence number of the first batch in the frame.
 //!   This can be used to determine if and how many stream batches are lost.
 //!
+//! ## Frame Checksum
+//! If the `stream_crc` feature is enabled, every data frame is followed by a CRC-32 (u32) over the
+//! header and all batches of the frame. The checksum is the common IEEE 802.3 CRC-32 (reflected
+//! polynomial 0xEDB88320, initial value and final XOR of 0xFFFFFFFF) as e.g. computed by Python's
+//! `zlib.crc32()`. Descriptor frames carry no checksum.
+//!
+//! ## Split Streams
+//! Applications may additionally stream a subset of their channels to a second, independent
+//! remote (e.g. ADC inputs to one host and DAC outputs to another). The split stream uses its own
+//! socket and frame buffers, so a slow receiver does not cause data loss on the other stream.
+//! Both streams use the frame format described here and carry their own sequence numbers and
+//! descriptors.
+//!
+//! ## Multiple Receivers
+//! The stream is connectionless and is never back-pressured by its receivers. To deliver the
+//! same stream to several receivers (e.g. a live display and an archiver), direct it to a
+//! broadcast or multicast address instead of a single host. Every receiver gets every frame and
+//! a slow receiver only loses its own packets.
+//!
+//! ## Stream Descriptor
+//! Whenever the stream is opened or its layout changes, a single descriptor frame is sent before
+//! any further data frames. The descriptor frame uses the common frame header with the reserved
+//! [DESCRIPTOR_FORMAT] format code, a batch count of zero and a sequence number of zero. The header
+//! is followed by the [StreamDescriptor], all in little-endian:
+//!
+//! * **Version** (u8): the version of the descriptor layout, currently [DESCRIPTOR_VERSION].
+//! * **Format Code** (u8): the format code of the subsequent data frames.
+//! * **Batch Size** (u16): the number of samples of each channel in a batch.
+//! * **Channel Mask** (u8): a bit mask of the channels present in each batch. For the ADC/DAC
+//!   formats, bits 0 through 3 correspond to ADC0, ADC1, DAC0, and DAC1 respectively. Bit 4
+//!   indicates the probe channel ([PROBE_CHANNEL]). Bits 5 and 6 correspond to the commanded
+//!   outputs CMD0 and CMD1 ([COMMAND_CHANNELS]). Refer to [StreamFormat] for other formats.
+//! * **Decimation** (u32): the number of consecutive samples averaged into each streamed sample,
+//!   1 if the data is not decimated. The sample period of the streamed data is the sample period
+//!   of the application times the decimation.
+//!
 //! # Example
 //! A sample Python script is available in `scripts/stream_throughput.py` to demonstrate reception
 //! of livestreamed data.
@@ -27,11 +63,18 @@ use heapless::{
     pool::{Box, Init, Pool, Uninit},
     spsc::{Consumer, Producer, Queue},
 };
+use minimq::embedded_time::Clock;
 use num_enum::IntoPrimitive;
 use serde::{Deserialize, Serialize};
 use smoltcp_nal::embedded_nal::{IpAddr, Ipv4Addr, SocketAddr, UdpClientStack};
 
 use super::NetworkReference;
+use crate::dsp::decimate::Decimator;
+use crate::hardware::{adc::AdcCode, dac::DacCode, SystemTimer};
+
+// The initial and maximum spacing of failed stream connection attempts in milliseconds.
+const MIN_RETRY_MS: u32 = 10;
+const MAX_RETRY_MS: u32 = 1_000;
 
 // Magic first bytes indicating a UDP frame of straming data
 const MAGIC: u16 = 0x057B;
@@ -41,23 +84,66 @@ const MAGIC: u16 = 0x057B;
 // number, which corresponds to 8 bytes.
 const HEADER_SIZE: usize = 8;
 
-// The number of frames that can be buffered.
+/// The format code reserved for stream descriptor frames.
+pub const DESCRIPTOR_FORMAT: u8 = 0xFF;
+
+/// The version of the stream descriptor layout.
+pub const DESCRIPTOR_VERSION: u8 = 2;
+
+// The size of the stream descriptor frame: the header followed by the version, format code,
+// batch size, channel mask and decimation.
+const DESCRIPTOR_SIZE: usize = HEADER_SIZE + 9;
+
+/// The channel mask indicating that all ADC and DAC channels are streamed.
+pub const ALL_CHANNELS: u8 = 0b1111;
+
+/// The channel mask of the ADC0 and ADC1 channels.
+pub const ADC_CHANNELS: u8 = 0b0011;
+
+/// The channel mask of the DAC0 and DAC1 channels.
+pub const DAC_CHANNELS: u8 = 0b1100;
+
+/// The channel mask of the probe channel, an application-selected internal signal.
+pub const PROBE_CHANNEL: u8 = 0b1_0000;
+
+/// The channel mask of the CMD0 and CMD1 channels, the commanded outputs before saturation.
+pub const COMMAND_CHANNELS: u8 = 0b110_0000;
+
+// The size of the checksum appended to every data frame.
+#[cfg(feature = "stream_crc")]
+const CRC_SIZE: usize = 4;
+#[cfg(not(feature = "stream_crc"))]
+const CRC_SIZE: usize = 0;
+
+// The number of frames that are buffered by default.
 const FRAME_COUNT: usize = 4;
 
-// The size of each frame in bytes.
+// The maximum number of frames that can be buffered, including any additional frame buffers
+// provided by the application.
+const MAX_FRAME_COUNT: usize = 32;
+
+/// The size of each frame in bytes.
+///
+/// # Note
+/// Every buffered frame occupies this amount of RAM.
 // Ensure the resulting ethernet frame is within the MTU:
 // 1500 MTU - 40 IP6 header - 8 UDP header
-const FRAME_SIZE: usize = 1500 - 40 - 8;
+pub const FRAME_SIZE: usize = 1500 - 40 - 8;
 
 // The size of the frame queue must be at least as large as the number of frame buffers. Every
 // allocated frame buffer should fit in the queue.
-const FRAME_QUEUE_SIZE: usize = FRAME_COUNT * 2;
+const FRAME_QUEUE_SIZE: usize = MAX_FRAME_COUNT * 2;
 
 // Static storage used for a heapless::Pool of frame buffers.
 static mut FRAME_DATA: [u8; core::mem::size_of::<u8>()
     * FRAME_SIZE
     * FRAME_COUNT] = [0; core::mem::size_of::<u8>() * FRAME_SIZE * FRAME_COUNT];
 
+// Static storage used for the frame buffers of the split stream.
+static mut SPLIT_FRAME_DATA: [u8; core::mem::size_of::<u8>()
+    * FRAME_SIZE
+    * FRAME_COUNT] = [0; core::mem::size_of::<u8>() * FRAME_SIZE * FRAME_COUNT];
+
 type Frame = [MaybeUninit<u8>; FRAME_SIZE];
 
 /// Represents the destination for the UDP stream to send data to.
@@ -76,6 +162,22 @@ pub struct StreamTarget {
     pub port: u16,
 }
 
+impl StreamTarget {
+    /// Check whether the target address is unspecified (`0.0.0.0`).
+    pub fn is_unspecified(&self) -> bool {
+        self.ip == [0; 4]
+    }
+}
+
+/// Errors that can occur while generating stream data.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// No frame buffer was available and the batch was dropped.
+    Overrun,
+    /// The batch did not fit into the remaining space of the frame and was dropped.
+    Overflow,
+}
+
 /// Specifies the format of streamed data
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq, IntoPrimitive)]
@@ -85,16 +187,115 @@ pub enum StreamFormat {
 
     /// Streamed data contains ADC0, ADC1, DAC0, and DAC1 sequentially in little-endian format.
     ///
+    /// # Note
+    /// Only the channels selected in the channel mask of the stream descriptor are present. The
+    /// commanded outputs CMD0 and CMD1 ([COMMAND_CHANNELS]) follow as `i32` values in DAC LSB
+    /// where 0 corresponds to 0 V. Unlike the DAC codes they are not saturated.
+    ///
     /// # Example
     /// With a batch size of 2, the serialization would take the following form:
     /// ```
-    /// <ADC0[0]> <ADC0[1]> <ADC1[0]> <ADC1[1]> <DAC0[0]> <DAC0[1]> <DAC1[0]> <DAC1[1]>
+    /// <ADC0[0]> <ADC0[1]> <ADC1[0]> <ADC1[1]> <DAC0[0]> <DAC0[1]> <DAC1[0]> <DAC1[1]> [<CMD0[0]> <CMD0[1]> <CMD1[0]> <CMD1[1]>]
     /// ```
     AdcDacData = 1,
 
     /// Streamed data in FLS (fiber length stabilization) format. See the FLS application for
     /// detailed definition.
     Fls = 2,
+
+    /// Streamed data contains a timestamp followed by ADC0, ADC1, DAC0, and DAC1 sequentially in
+    /// little-endian format.
+    ///
+    /// # Note
+    /// The timestamp (u32) is the value of the timestamp timer (the timebase of the DI0 input
+    /// capture) at the start of the sampling period of the first sample of the batch. It is
+    /// derived from the start of sampling and the batch index, not from the processing time of the
+    /// batch. Only the channels selected in the channel mask of the stream descriptor are present. If the [PROBE_CHANNEL] is selected, the
+    /// batch ends with a single little-endian `i32` value of an application-selected internal
+    /// signal of the batch, e.g. the demodulated lockin signal.
+    ///
+    /// # Example
+    /// With a batch size of 2, the serialization would take the following form:
+    /// ```
+    /// <TS> <ADC0[0]> <ADC0[1]> <ADC1[0]> <ADC1[1]> <DAC0[0]> <DAC0[1]> <DAC1[0]> <DAC1[1]> [<PROBE>]
+    /// ```
+    AdcDacTimestampData = 3,
+
+    /// Streamed data contains ADC0, ADC1, DAC0, and DAC1 sequentially as little-endian `f32`
+    /// values in volts.
+    ///
+    /// # Note
+    /// ADC values are referred to the AFE input, i.e. they account for the programmable AFE gain.
+    /// Only the channels selected in the channel mask of the stream descriptor are present. The
+    /// commanded outputs CMD0 and CMD1 ([COMMAND_CHANNELS]) follow and are not saturated.
+    ///
+    /// # Example
+    /// With a batch size of 2, the serialization would take the following form:
+    /// ```
+    /// <ADC0[0]> <ADC0[1]> <ADC1[0]> <ADC1[1]> <DAC0[0]> <DAC0[1]> <DAC1[0]> <DAC1[1]> [<CMD0[0]> <CMD0[1]> <CMD1[0]> <CMD1[1]>]
+    /// ```
+    AdcDacVolts = 4,
+
+    /// Streamed data contains a timestamp followed by the magnitudes of the harmonics of the lockin
+    /// reference in little-endian format.
+    ///
+    /// # Note
+    /// The timestamp (u32) is the same as in [StreamFormat::AdcDacTimestampData]. It is followed
+    /// by one squared magnitude (u32) per selected harmonic at the end of the batch, starting with
+    /// the fundamental. Bit `n` of the channel mask of the stream descriptor selects harmonic
+    /// `n + 1`.
+    ///
+    /// # Example
+    /// With four harmonics, the serialization would take the following form:
+    /// ```
+    /// <TS> <H1> <H2> <H3> <H4>
+    /// ```
+    LockinHarmonics = 5,
+}
+
+/// A description of the layout of the streamed data.
+///
+/// # Note
+/// Refer to the module documentation for the serialization of the descriptor frame.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StreamDescriptor {
+    /// The format code of the data frames.
+    pub format: u8,
+
+    /// The number of samples of each channel in a batch.
+    pub batch_size: u16,
+
+    /// A bit mask of the channels present in each batch.
+    pub channels: u8,
+
+    /// The number of consecutive samples averaged into each streamed sample.
+    pub decimation: u32,
+}
+
+impl Default for StreamDescriptor {
+    fn default() -> Self {
+        Self {
+            format: StreamFormat::Unknown.into(),
+            batch_size: 0,
+            channels: ALL_CHANNELS,
+            decimation: 1,
+        }
+    }
+}
+
+impl StreamDescriptor {
+    fn serialize(&self) -> [u8; DESCRIPTOR_SIZE] {
+        let mut buf = [0; DESCRIPTOR_SIZE];
+        buf[..2].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[2] = DESCRIPTOR_FORMAT;
+        buf[HEADER_SIZE] = DESCRIPTOR_VERSION;
+        buf[HEADER_SIZE + 1] = self.format;
+        buf[HEADER_SIZE + 2..HEADER_SIZE + 4]
+            .copy_from_slice(&self.batch_size.to_le_bytes());
+        buf[HEADER_SIZE + 4] = self.channels;
+        buf[HEADER_SIZE + 5..].copy_from_slice(&self.decimation.to_le_bytes());
+        buf
+    }
 }
 
 impl From<StreamTarget> for SocketAddr {
@@ -115,12 +316,14 @@ impl From<StreamTarget> for SocketAddr {
 ///
 /// # Args
 /// * `stack` - A reference to the shared network stack.
+/// * `clock` - The system timer used to space out connection attempts.
 ///
 /// # Returns
 /// (generator, stream) where `generator` can be used to enqueue "batches" for transmission. The
 /// `stream` is the logically consumer (UDP transmitter) of the enqueued data.
 pub fn setup_streaming(
     stack: NetworkReference,
+    clock: SystemTimer,
 ) -> (FrameGenerator, DataStream) {
     // The queue needs to be at least as large as the frame count to ensure that every allocated
     // frame can potentially be enqueued for transmission.
@@ -133,11 +336,47 @@ pub fn setup_streaming(
 
     // Note(unsafe): We guarantee that FRAME_DATA is only accessed once in this function.
     let memory = unsafe { &mut FRAME_DATA };
-    frame_pool.grow(memory);
+    let frame_count = frame_pool.grow(memory);
 
     let generator = FrameGenerator::new(producer, frame_pool);
 
-    let stream = DataStream::new(stack, consumer, frame_pool);
+    let stream =
+        DataStream::new(stack, clock, consumer, frame_pool, frame_count);
+
+    (generator, stream)
+}
+
+/// Configure the split stream on a device.
+///
+/// # Note
+/// The split stream is independent of the stream configured by [setup_streaming]. It uses its
+/// own queue, frame buffers and socket.
+///
+/// # Args
+/// * `stack` - A reference to the shared network stack.
+/// * `clock` - The system timer used to space out connection attempts.
+///
+/// # Returns
+/// (generator, stream) of the split stream. Refer to [setup_streaming].
+pub fn setup_split_streaming(
+    stack: NetworkReference,
+    clock: SystemTimer,
+) -> (FrameGenerator, DataStream) {
+    let queue =
+        cortex_m::singleton!(: Queue<StreamFrame, FRAME_QUEUE_SIZE> = Queue::new())
+            .unwrap();
+    let (producer, consumer) = queue.split();
+
+    let frame_pool = cortex_m::singleton!(: Pool<Frame> = Pool::new()).unwrap();
+
+    // Note(unsafe): We guarantee that SPLIT_FRAME_DATA is only accessed once in this function.
+    let memory = unsafe { &mut SPLIT_FRAME_DATA };
+    let frame_count = frame_pool.grow(memory);
+
+    let generator = FrameGenerator::new(producer, frame_pool);
+
+    let stream =
+        DataStream::new(stack, clock, consumer, frame_pool, frame_count);
 
     (generator, stream)
 }
@@ -174,26 +413,63 @@ impl StreamFrame {
         }
     }
 
-    pub fn add_batch<F>(&mut self, mut f: F) -> usize
+    pub fn add_batch<F>(&mut self, mut f: F) -> Option<usize>
     where
         F: FnMut(&mut [MaybeUninit<u8>]) -> usize,
     {
         let len = f(&mut self.buffer[self.offset..]);
+
+        // A batch exceeding the remaining space (including the checksum) is incomplete.
+        if self.is_full(len) {
+            return None;
+        }
+
         self.offset += len;
         self.batches += 1;
-        len
+        Some(len)
     }
 
     pub fn is_full(&self, len: usize) -> bool {
-        self.offset + len > self.buffer.len()
+        self.offset + len + CRC_SIZE > self.buffer.len()
     }
 
     pub fn finish(&mut self) -> &[MaybeUninit<u8>] {
         self.buffer[3].write(self.batches);
+
+        #[cfg(feature = "stream_crc")]
+        {
+            // Note(unsafe): The header and all batches up to the offset have been written.
+            let data = unsafe {
+                core::slice::from_raw_parts(
+                    self.buffer.as_ptr() as *const u8,
+                    self.offset,
+                )
+            };
+            let crc = crc32(data);
+            for (byte, buf) in crc
+                .to_le_bytes()
+                .iter()
+                .zip(&mut self.buffer[self.offset..])
+            {
+                buf.write(*byte);
+            }
+            self.offset += CRC_SIZE;
+        }
+
         &self.buffer[..self.offset]
     }
 }
 
+/// Compute the IEEE 802.3 CRC-32 of the provided data.
+#[cfg(feature = "stream_crc")]
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
 /// The data generator for a stream.
 pub struct FrameGenerator {
     queue: Producer<'static, StreamFrame, FRAME_QUEUE_SIZE>,
@@ -201,6 +477,10 @@ pub struct FrameGenerator {
     current_frame: Option<StreamFrame>,
     sequence_number: u32,
     format: u8,
+    dropped: u32,
+    capture: Option<u32>,
+    frame_batches: u8,
+    channels: u8,
 }
 
 impl FrameGenerator {
@@ -214,9 +494,57 @@ impl FrameGenerator {
             format: StreamFormat::Unknown.into(),
             current_frame: None,
             sequence_number: 0,
+            dropped: 0,
+            capture: None,
+            frame_batches: 0,
+            channels: ALL_CHANNELS,
         }
     }
 
+    /// Configure the number of batches in each frame.
+    ///
+    /// # Note
+    /// Frames are transmitted once they contain the configured number of batches or once they are
+    /// full. Fewer batches per frame reduce the latency of the stream at the expense of more
+    /// packets and a larger header overhead, i.e. a lower maximum throughput.
+    ///
+    /// # Args
+    /// * `batches` - The maximum number of batches in a frame. Zero fills frames completely.
+    pub fn set_frame_batches(&mut self, batches: u8) {
+        self.frame_batches = batches;
+    }
+
+    /// Configure the number of batches to stream.
+    ///
+    /// # Note
+    /// A one-shot capture overrides continuous streaming while it is configured. Once the
+    /// requested number of batches has been added, the current frame is enqueued for transmission
+    /// and all further batches are discarded until another capture is started or continuous
+    /// streaming is resumed.
+    ///
+    /// # Args
+    /// * `batches` - The number of batches to capture, or `None` to stream continuously.
+    pub fn capture(&mut self, batches: Option<u32>) {
+        self.capture = batches;
+    }
+
+    /// Get the number of batches remaining in the current capture.
+    ///
+    /// # Returns
+    /// The number of remaining batches or `None` if streaming continuously.
+    pub fn capture_remaining(&self) -> Option<u32> {
+        self.capture
+    }
+
+    /// Get the number of batches dropped because no frame buffer was available or because they
+    /// did not fit into a frame.
+    ///
+    /// # Note
+    /// The count wraps around on overflow.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
     /// Configure the format of the stream.
     ///
     /// # Note:
@@ -229,15 +557,91 @@ impl FrameGenerator {
         self.format = format.into();
     }
 
+    /// Change the format of the stream.
+    ///
+    /// # Note
+    /// A partially filled frame of the previous format is enqueued for transmission. The stream
+    /// descriptor must be updated separately, see [DataStream::set_format].
+    ///
+    /// # Args
+    /// * `format` - The desired format of the stream.
+    pub fn set_format(&mut self, format: impl Into<u8>) {
+        let format = format.into();
+        if format != self.format {
+            self.flush();
+            self.format = format;
+        }
+    }
+
+    /// Change the channels present in the stream.
+    ///
+    /// # Note
+    /// A partially filled frame with the previous channels is enqueued for transmission, so that
+    /// all batches of a frame have the same size. The stream descriptor must be updated
+    /// separately, see [DataStream::set_channels].
+    ///
+    /// # Args
+    /// * `channels` - A bit mask of the channels present in each batch.
+    pub fn set_channels(&mut self, channels: u8) {
+        if channels != self.channels {
+            self.flush();
+            self.channels = channels;
+        }
+    }
+
+    // Enqueue the current frame for transmission. A frame without batches is discarded.
+    fn flush(&mut self) {
+        if let Some(frame) = self.current_frame.take() {
+            if frame.batches == 0 {
+                self.pool.free(frame.buffer);
+            } else {
+                // Note(unwrap): The queue is designed to be at least as large as the frame
+                // buffer count, so this enqueue should always succeed.
+                self.queue.enqueue(frame).unwrap();
+            }
+        }
+    }
+
     /// Add a batch to the current stream frame.
     ///
+    /// # Note
+    /// The batch is silently dropped if no frame buffer is available. Use [FrameGenerator::try_add]
+    /// to detect dropped batches.
+    ///
     /// # Args
     /// * `f` - A closure that will be provided the buffer to write batch data into.
-    ///         Returns the number of bytes written.
+    ///         Returns the size of the batch in bytes, even if the buffer is too small to hold it.
     pub fn add<F>(&mut self, f: F)
     where
         F: FnMut(&mut [MaybeUninit<u8>]) -> usize,
     {
+        self.try_add(f).ok();
+    }
+
+    /// Add a batch to the current stream frame, reporting if it had to be dropped.
+    ///
+    /// # Note
+    /// The batch is dropped if no frame buffer is available, i.e. if the network can not keep up
+    /// with the data rate, or if it does not fit into the remaining space of the current frame.
+    /// The sequence number still advances so that the loss is also visible to the receiver.
+    ///
+    /// # Args
+    /// * `f` - A closure that will be provided the buffer to write batch data into.
+    ///         Returns the size of the batch in bytes, even if the buffer is too small to hold it.
+    ///
+    /// # Returns
+    /// [Error::Overrun] or [Error::Overflow] if the batch was dropped.
+    pub fn try_add<F>(&mut self, f: F) -> Result<(), Error>
+    where
+        F: FnMut(&mut [MaybeUninit<u8>]) -> usize,
+    {
+        match self.capture.as_mut() {
+            // The capture has completed, discard the batch.
+            Some(0) => return Ok(()),
+            Some(remaining) => *remaining -= 1,
+            None => {}
+        }
+
         let sequence_number = self.sequence_number;
         self.sequence_number = self.sequence_number.wrapping_add(1);
 
@@ -249,35 +653,294 @@ impl FrameGenerator {
                     sequence_number,
                 ));
             } else {
-                return;
+                self.dropped = self.dropped.wrapping_add(1);
+                return Err(Error::Overrun);
             }
         }
 
         // Note(unwrap): We ensure the frame is present above.
         let current_frame = self.current_frame.as_mut().unwrap();
 
-        let len = current_frame.add_batch(f);
+        let Some(len) = current_frame.add_batch(f) else {
+            self.flush();
+            self.dropped = self.dropped.wrapping_add(1);
+            return Err(Error::Overflow);
+        };
+
+        // Transmit the frame when full, when it has the configured number of batches or once the
+        // capture is complete.
+        if current_frame.is_full(len)
+            || (self.frame_batches != 0
+                && current_frame.batches >= self.frame_batches)
+            || self.capture == Some(0)
+        {
+            self.flush();
+        }
+
+        Ok(())
+    }
+}
+
+/// The layout of the data streamed by an [AdcDacStream].
+#[derive(Copy, Clone, Debug)]
+pub struct AdcDacLayout {
+    /// The decimation factor of the streamed data, see [Decimator].
+    pub decimation: u32,
+
+    /// A bit mask of the streamed channels.
+    pub channels: u8,
+
+    /// Stream the DAC and command channels on the split stream instead of the primary stream.
+    pub split: bool,
+
+    /// The AFE gains to refer the ADC samples to the AFE input in [StreamFormat::AdcDacVolts]
+    /// format, or `None` to stream the codes in [StreamFormat::AdcDacData] format.
+    pub volts: Option<[f32; 2]>,
+}
+
+/// Decimation and serialization of the ADC, DAC and command channels of an application.
+///
+/// # Note
+/// All channels are decimated alike and stay aligned. Nothing is streamed until a decimated batch
+/// of `N` samples is complete.
+#[derive(Copy, Clone)]
+pub struct AdcDacStream<const N: usize> {
+    decimators: [Decimator<N>; 6],
+}
+
+impl<const N: usize> Default for AdcDacStream<N> {
+    fn default() -> Self {
+        Self {
+            decimators: [Decimator::default(); 6],
+        }
+    }
+}
+
+impl<const N: usize> AdcDacStream<N> {
+    /// Decimate a batch of samples and add complete batches to the streams.
+    ///
+    /// # Args
+    /// * `generators` - The primary and the split stream generators.
+    /// * `adcs` - The ADC codes of the batch.
+    /// * `dacs` - The DAC codes of the batch.
+    /// * `commands` - The commanded outputs of the batch in DAC LSB before saturation.
+    /// * `layout` - The layout of the streamed data.
+    pub fn add(
+        &mut self,
+        generators: [&mut FrameGenerator; 2],
+        adcs: [&[u16]; 2],
+        dacs: [&[u16]; 2],
+        commands: [&[i32]; 2],
+        layout: &AdcDacLayout,
+    ) {
+        if self.decimators[0].factor() != layout.decimation {
+            self.decimators = [Decimator::new(layout.decimation); 6];
+        }
+
+        let (code_decimators, command_decimators) =
+            self.decimators.split_at_mut(4);
+
+        let mut codes = [[0u16; N]; 4];
+        let mut complete = false;
+        for (i, ((data, decimator), output)) in adcs
+            .into_iter()
+            .chain(dacs)
+            .zip(code_decimators.iter_mut())
+            .zip(codes.iter_mut())
+            .enumerate()
+        {
+            let x = data.iter().map(|x| {
+                if i < 2 {
+                    i16::from(AdcCode(*x)) as i32
+                } else {
+                    i16::from(DacCode(*x)) as i32
+                }
+            });
+            if let Some(y) = decimator.update(x) {
+                // The mean of `i16` samples is within range.
+                for (code, y) in output.iter_mut().zip(y) {
+                    *code = if i < 2 {
+                        AdcCode::from(*y as i16).0
+                    } else {
+                        DacCode::from(*y as i16).0
+                    };
+                }
+                complete = true;
+            }
+        }
+
+        let mut decimated = [[0i32; N]; 2];
+        for ((data, decimator), output) in commands
+            .into_iter()
+            .zip(command_decimators.iter_mut())
+            .zip(decimated.iter_mut())
+        {
+            if let Some(y) = decimator.update(data.iter().copied()) {
+                *output = *y;
+            }
+        }
+        let commands = decimated;
 
-        if current_frame.is_full(len) {
-            // Note(unwrap): The queue is designed to be at least as large as the frame buffer
-            // count, so this enqueue should always succeed.
-            self.queue
-                .enqueue(self.current_frame.take().unwrap())
-                .unwrap();
+        if !complete {
+            return;
+        }
+
+        let channels = if layout.split {
+            [
+                layout.channels & ADC_CHANNELS,
+                layout.channels & (DAC_CHANNELS | COMMAND_CHANNELS),
+            ]
+        } else {
+            [layout.channels, 0]
+        };
+
+        for (generator, channels) in generators
+            .into_iter()
+            .zip(channels)
+            .take(1 + layout.split as usize)
+        {
+            generator.set_channels(channels);
+            match layout.volts {
+                Some(gains) => {
+                    generator.set_format(StreamFormat::AdcDacVolts);
+                    generator.add(|buf| {
+                        serialize_volts(buf, &codes, &commands, channels, gains)
+                    });
+                }
+                None => {
+                    generator.set_format(StreamFormat::AdcDacData);
+                    generator.add(|buf| {
+                        serialize_codes(buf, &codes, &commands, channels)
+                    });
+                }
+            }
         }
     }
 }
 
+// The channel mask bit of the command channel CMD0 or CMD1.
+fn command_channel(index: usize) -> u8 {
+    COMMAND_CHANNELS & (1 << (5 + index))
+}
+
+// Serialize the selected channels of a batch in [StreamFormat::AdcDacData] format. Returns the
+// size of the batch, even if it exceeds the buffer.
+fn serialize_codes<const N: usize>(
+    buf: &mut [MaybeUninit<u8>],
+    codes: &[[u16; N]; 4],
+    commands: &[[i32; N]; 2],
+    channels: u8,
+) -> usize {
+    const CODES: usize = core::mem::size_of::<u16>();
+    const COMMANDS: usize = core::mem::size_of::<i32>();
+    let (codes_len, commands_len) = (
+        N * CODES * (channels & ALL_CHANNELS).count_ones() as usize,
+        N * COMMANDS * (channels & COMMAND_CHANNELS).count_ones() as usize,
+    );
+    if buf.len() < codes_len + commands_len {
+        return codes_len + commands_len;
+    }
+    let (buf, commands_buf) = buf.split_at_mut(codes_len);
+
+    for (data, buf) in codes
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| channels & (1 << i) != 0)
+        .map(|(_, data)| data)
+        .zip(buf.chunks_exact_mut(N * CODES))
+    {
+        // Note(unsafe): The codes are plain data and the target is little-endian.
+        let data = unsafe {
+            core::slice::from_raw_parts(
+                data.as_ptr() as *const MaybeUninit<u8>,
+                N * CODES,
+            )
+        };
+        buf.copy_from_slice(data);
+    }
+
+    for (data, buf) in commands
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| channels & command_channel(*i) != 0)
+        .map(|(_, data)| data)
+        .zip(commands_buf.chunks_exact_mut(N * COMMANDS))
+    {
+        for (command, buf) in data.iter().zip(buf.chunks_exact_mut(COMMANDS)) {
+            for (byte, buf) in command.to_le_bytes().iter().zip(buf) {
+                buf.write(*byte);
+            }
+        }
+    }
+
+    codes_len + commands_len
+}
+
+// Serialize the selected channels of a batch in [StreamFormat::AdcDacVolts] format. Returns the
+// size of the batch, even if it exceeds the buffer.
+fn serialize_volts<const N: usize>(
+    buf: &mut [MaybeUninit<u8>],
+    codes: &[[u16; N]; 4],
+    commands: &[[i32; N]; 2],
+    channels: u8,
+    gains: [f32; 2],
+) -> usize {
+    let size = N * core::mem::size_of::<f32>();
+    let len = size
+        * (channels & (ALL_CHANNELS | COMMAND_CHANNELS)).count_ones() as usize;
+    if buf.len() < len {
+        return len;
+    }
+
+    let codes = codes
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| channels & (1 << i) != 0)
+        .map(|(i, data)| {
+            data.map(|code| {
+                // Refer ADC samples to the AFE input.
+                if i < 2 {
+                    f32::from(AdcCode(code)) / gains[i]
+                } else {
+                    f32::from(DacCode(code))
+                }
+            })
+        });
+    let commands = commands
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| channels & command_channel(*i) != 0)
+        .map(|(_, data)| data.map(|x| x as f32 / DacCode::LSB_PER_VOLT));
+
+    for (data, buf) in codes.chain(commands).zip(buf.chunks_exact_mut(size)) {
+        for (volts, buf) in data.iter().zip(buf.chunks_exact_mut(4)) {
+            for (byte, buf) in volts.to_le_bytes().iter().zip(buf) {
+                buf.write(*byte);
+            }
+        }
+    }
+
+    len
+}
+
 /// The "consumer" portion of the data stream.
 ///
 /// # Note
 /// This is responsible for consuming data and sending it over UDP.
 pub struct DataStream {
     stack: NetworkReference,
+    clock: SystemTimer,
     socket: Option<<NetworkReference as UdpClientStack>::UdpSocket>,
     queue: Consumer<'static, StreamFrame, FRAME_QUEUE_SIZE>,
     frame_pool: &'static Pool<Frame>,
+    frame_count: usize,
     remote: SocketAddr,
+    descriptor: StreamDescriptor,
+    describe: bool,
+    enabled: bool,
+    rejected: bool,
+    last_attempt: u32,
+    backoff: u32,
 }
 
 impl DataStream {
@@ -285,22 +948,103 @@ impl DataStream {
     ///
     /// # Args
     /// * `stack` - A reference to the shared network stack.
+    /// * `clock` - The system timer used to space out connection attempts.
     /// * `consumer` - The read side of the queue containing data to transmit.
     /// * `frame_pool` - The Pool to return stream frame objects into.
+    /// * `frame_count` - The number of frames in the pool.
     fn new(
         stack: NetworkReference,
+        clock: SystemTimer,
         consumer: Consumer<'static, StreamFrame, FRAME_QUEUE_SIZE>,
         frame_pool: &'static Pool<Frame>,
+        frame_count: usize,
     ) -> Self {
         Self {
             stack,
+            clock,
             socket: None,
             remote: StreamTarget::default().into(),
             queue: consumer,
             frame_pool,
+            frame_count,
+            descriptor: StreamDescriptor::default(),
+            describe: false,
+            enabled: true,
+            rejected: false,
+            last_attempt: 0,
+            backoff: 0,
         }
     }
 
+    /// Configure the layout description of the stream.
+    ///
+    /// # Note
+    /// The descriptor is (re-)transmitted before the next data frame if it changed.
+    ///
+    /// # Args
+    /// * `descriptor` - The description of the streamed data.
+    pub fn describe(&mut self, descriptor: StreamDescriptor) {
+        if descriptor != self.descriptor {
+            self.describe = true;
+        }
+        self.descriptor = descriptor;
+    }
+
+    /// Change the format code of the stream description.
+    ///
+    /// # Args
+    /// * `format` - The format code of the data frames.
+    pub fn set_format(&mut self, format: u8) {
+        self.describe(StreamDescriptor {
+            format,
+            ..self.descriptor
+        });
+    }
+
+    /// Select the channels present in the stream.
+    ///
+    /// # Note
+    /// Frames that were already enqueued may still contain the previously selected channels.
+    ///
+    /// # Args
+    /// * `channels` - A bit mask of the channels present in each batch.
+    pub fn set_channels(&mut self, channels: u8) {
+        self.describe(StreamDescriptor {
+            channels,
+            ..self.descriptor
+        });
+    }
+
+    /// Change the decimation of the stream description.
+    ///
+    /// # Args
+    /// * `decimation` - The number of consecutive samples averaged into each streamed sample.
+    pub fn set_decimation(&mut self, decimation: u32) {
+        self.describe(StreamDescriptor {
+            decimation,
+            ..self.descriptor
+        });
+    }
+
+    /// Add frame buffers to the stream.
+    ///
+    /// # Note
+    /// Deeper buffering allows the stream to bridge longer network stalls without dropping
+    /// data. Each frame requires [FRAME_SIZE] bytes of the provided memory. At most 32 frames
+    /// can be buffered in total.
+    ///
+    /// # Args
+    /// * `memory` - The memory to use for additional frame buffers.
+    ///
+    /// # Returns
+    /// The number of frame buffers added.
+    pub fn grow(&mut self, memory: &'static mut [u8]) -> usize {
+        let count = self.frame_pool.grow(memory);
+        self.frame_count += count;
+        assert!(self.frame_count <= MAX_FRAME_COUNT);
+        count
+    }
+
     fn close(&mut self) {
         if let Some(socket) = self.socket.take() {
             log::info!("Closing stream");
@@ -309,27 +1053,96 @@ impl DataStream {
         }
     }
 
+    // Space out failed connection attempts exponentially.
+    fn retry_later(&mut self) {
+        self.backoff = (self.backoff * 2).clamp(MIN_RETRY_MS, MAX_RETRY_MS);
+    }
+
     // Open new socket.
     fn open(&mut self) -> Result<(), ()> {
-        // If there is already a socket of if remote address is unspecified,
-        // do not open a new socket.
-        if self.socket.is_some() || self.remote.ip().is_unspecified() {
+        // If there is already a socket of if remote address is unspecified or was rejected by
+        // the network stack, do not open a new socket.
+        if self.socket.is_some()
+            || self.remote.ip().is_unspecified()
+            || self.rejected
+        {
+            return Err(());
+        }
+
+        // Wait for the backoff after a failed attempt to expire.
+        // Note(unwrap): The system timer is infallible.
+        let now = self
+            .clock
+            .try_now()
+            .unwrap()
+            .duration_since_epoch()
+            .integer();
+        if now.wrapping_sub(self.last_attempt) < self.backoff {
             return Err(());
         }
+        self.last_attempt = now;
 
         log::info!("Opening stream");
 
-        let mut socket = self.stack.socket().or(Err(()))?;
+        let mut socket = match self.stack.socket() {
+            Ok(socket) => socket,
+            Err(_) => {
+                self.retry_later();
+                return Err(());
+            }
+        };
+
+        // Note: We only connect with a new socket, so it is guaranteed to not already be bound.
+        // Connecting may still fail, e.g. if the network stack has no local address yet or
+        // rejects the remote.
+        if let Err(e) = self.stack.connect(&mut socket, self.remote) {
+            // Note(unwrap): The socket was just created and is guaranteed to be valid.
+            self.stack.close(socket).unwrap();
+
+            match e {
+                // Retry once an address has been assigned to the interface.
+                smoltcp_nal::NetworkError::NoIpAddress => self.retry_later(),
+                e => {
+                    log::warn!("Cannot stream to {}: {:?}", self.remote, e);
+                    self.rejected = true;
+                }
+            }
 
-        // Note(unwrap): We only connect with a new socket, so it is guaranteed to not already be
-        // bound.
-        self.stack.connect(&mut socket, self.remote).unwrap();
+            return Err(());
+        }
 
         self.socket.replace(socket);
+        self.backoff = 0;
+
+        // Every newly opened stream starts with a description of its layout.
+        self.describe = true;
 
         Ok(())
     }
 
+    /// Pause the stream.
+    ///
+    /// # Note
+    /// While paused, generated data is discarded instead of being buffered, so that the stream
+    /// continues with fresh data when resumed.
+    pub fn pause(&mut self) {
+        self.enabled = false;
+    }
+
+    /// Resume a paused stream.
+    pub fn resume(&mut self) {
+        self.enabled = true;
+    }
+
+    /// Check whether stream data is being transmitted.
+    ///
+    /// # Note
+    /// The stream is connectionless. This only indicates that the stream is enabled and a socket
+    /// to the configured remote is open, not that the remote actually receives the data.
+    pub fn is_streaming(&self) -> bool {
+        self.enabled && self.socket.is_some()
+    }
+
     /// Configure the remote endpoint of the stream.
     ///
     /// # Args
@@ -338,12 +1151,22 @@ impl DataStream {
         // Close socket to be reopened if the remote has changed.
         if remote != self.remote {
             self.close();
+            self.rejected = false;
+            self.backoff = 0;
         }
         self.remote = remote;
     }
 
     /// Process any data for transmission.
     pub fn process(&mut self) {
+        if !self.enabled {
+            // Discard data generated while paused.
+            while let Some(frame) = self.queue.dequeue() {
+                self.frame_pool.free(frame.buffer);
+            }
+            return;
+        }
+
         match self.socket.as_mut() {
             None => {
                 // If there's no socket available, try to connect to our remote.
@@ -355,7 +1178,12 @@ impl DataStream {
                 }
             }
             Some(handle) => {
-                if let Some(mut frame) = self.queue.dequeue() {
+                if self.describe {
+                    let descriptor = self.descriptor.serialize();
+                    if self.stack.send(handle, &descriptor).is_ok() {
+                        self.describe = false;
+                    }
+                } else if let Some(mut frame) = self.queue.dequeue() {
                     // Transmit the frame and return it to the pool.
                     let buf = frame.finish();
                     let data = unsafe {
@@ -371,3 +1199,88 @@ impl DataStream {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_target_round_trip() {
+        let target = StreamTarget {
+            ip: [192, 168, 0, 1],
+            port: 1111,
+        };
+        let mut buf = [0u8; 128];
+
+        let data = postcard::to_slice(&target, &mut buf).unwrap();
+        let decoded: StreamTarget = postcard::from_bytes(data).unwrap();
+        assert_eq!(decoded.ip, target.ip);
+        assert_eq!(decoded.port, 1111);
+
+        assert_eq!(
+            SocketAddr::from(decoded),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)), 1111)
+        );
+        assert!(!decoded.is_unspecified());
+        assert!(StreamTarget::default().is_unspecified());
+    }
+
+    #[test]
+    fn stream_target_json() {
+        let (target, _): (StreamTarget, _) = serde_json_core::from_str(
+            r#"{"ip": [192, 168, 0, 1], "port": 1111}"#,
+        )
+        .unwrap();
+        assert_eq!(target.ip, [192, 168, 0, 1]);
+
+        assert!(serde_json_core::from_str::<StreamTarget>(
+            r#"{"ip": [192, 168, 0], "port": 1111}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn serialize_descriptor() {
+        let descriptor = StreamDescriptor {
+            format: StreamFormat::AdcDacData.into(),
+            batch_size: 8,
+            channels: ALL_CHANNELS,
+            decimation: 0x1_0000,
+        };
+        let buf = descriptor.serialize();
+        assert_eq!(buf[..3], [0x7b, 0x05, DESCRIPTOR_FORMAT]);
+        assert_eq!(
+            buf[HEADER_SIZE..],
+            [DESCRIPTOR_VERSION, 1, 8, 0, 0b1111, 0, 0, 1, 0]
+        );
+    }
+
+    #[test]
+    fn serialize_command_channels() {
+        let codes = [[1, 2], [3, 4], [5, 6], [7, 8]];
+        let commands = [[-1, 0x1_0000], [9, 10]];
+        // ADC1, DAC1 and CMD0
+        let channels = 0b010_1010;
+
+        let mut buf = [MaybeUninit::new(0u8); 16];
+        assert_eq!(serialize_codes(&mut buf, &codes, &commands, channels), 16);
+        let buf = buf.map(|b| unsafe { b.assume_init() });
+        assert_eq!(
+            buf,
+            [3, 0, 4, 0, 7, 0, 8, 0, 0xff, 0xff, 0xff, 0xff, 0, 0, 1, 0]
+        );
+
+        // The batch size is returned even if the buffer is too small.
+        let mut buf = [MaybeUninit::new(0u8); 8];
+        assert_eq!(serialize_codes(&mut buf, &codes, &commands, channels), 16);
+
+        let mut buf = [MaybeUninit::new(0u8); 24];
+        assert_eq!(
+            serialize_volts(&mut buf, &codes, &commands, channels, [1.; 2]),
+            24
+        );
+        let buf = buf.map(|b| unsafe { b.assume_init() });
+        let volts = f32::from_le_bytes(buf[16..20].try_into().unwrap());
+        assert_eq!(volts, -1. / DacCode::LSB_PER_VOLT);
+    }
+}
@@ -14,7 +14,7 @@ pub mod network_processor;
 pub mod telemetry;
 
 use crate::hardware::{EthernetPhy, NetworkManager, NetworkStack, SystemTimer};
-use data_stream::{DataStream, FrameGenerator};
+use data_stream::{DataStream, FrameGenerator, StreamDescriptor};
 use network_processor::NetworkProcessor;
 use telemetry::TelemetryClient;
 
@@ -30,6 +30,8 @@ pub type NetworkReference =
 pub struct MqttStorage {
     telemetry: [u8; 2048],
     settings: [u8; 1024],
+    user: String<32>,
+    password: String<64>,
 }
 
 impl Default for MqttStorage {
@@ -37,6 +39,8 @@ impl Default for MqttStorage {
         Self {
             telemetry: [0u8; 2048],
             settings: [0u8; 1024],
+            user: String::new(),
+            password: String::new(),
         }
     }
 }
@@ -69,6 +73,8 @@ where
     pub processor: NetworkProcessor,
     stream: DataStream,
     generator: Option<FrameGenerator>,
+    split_stream: DataStream,
+    split_generator: Option<FrameGenerator>,
     pub telemetry: TelemetryClient<T>,
 }
 
@@ -84,7 +90,9 @@ where
     /// * `phy` - The ethernet PHY connecting the network.
     /// * `clock` - A `SystemTimer` implementing `Clock`.
     /// * `app` - The name of the application.
-    /// * `broker` - The domain name of the MQTT broker to use.
+    /// * `device` - The device configuration providing the MQTT broker, the client ID base and
+    ///   the broker credentials.
+    /// * `settings` - The initial run-time settings.
     ///
     /// # Returns
     /// A new struct of network users.
@@ -93,8 +101,8 @@ where
         phy: EthernetPhy,
         clock: SystemTimer,
         app: &str,
-        broker: &str,
-        id: &str,
+        device: &crate::settings::Settings,
+        settings: S,
     ) -> Self {
         let stack_manager =
             cortex_m::singleton!(: NetworkManager = NetworkManager::new(stack))
@@ -103,51 +111,79 @@ where
         let processor =
             NetworkProcessor::new(stack_manager.acquire_stack(), phy);
 
+        let broker = &device.broker;
+        let id = &device.id;
         let prefix = get_device_prefix(app, id);
 
-        let store =
-            cortex_m::singleton!(: MqttStorage = MqttStorage::default())
-                .unwrap();
+        let MqttStorage {
+            telemetry: telemetry_buffer,
+            settings: settings_buffer,
+            user,
+            password,
+        } = cortex_m::singleton!(: MqttStorage = MqttStorage::default())
+            .unwrap();
 
         let named_broker = miniconf::minimq::broker::NamedBroker::new(
             broker,
             stack_manager.acquire_stack(),
         )
         .unwrap();
-        let settings = miniconf::MqttClient::new(
+        let mut settings_config =
+            miniconf::minimq::ConfigBuilder::new(named_broker, settings_buffer)
+                .client_id(&get_client_id(id, "settings"))
+                .unwrap();
+
+        let named_broker = minimq::broker::NamedBroker::new(
+            broker,
             stack_manager.acquire_stack(),
-            &prefix,
-            clock,
-            S::default(),
-            miniconf::minimq::ConfigBuilder::new(
-                named_broker,
-                &mut store.settings,
-            )
-            .client_id(&get_client_id(id, "settings"))
-            .unwrap(),
         )
         .unwrap();
+        let mut telemetry_config =
+            minimq::ConfigBuilder::new(named_broker, telemetry_buffer)
+                // The telemetry client only receives MQTT control packets and short settings
+                // readback requests. As such, we don't need much of the buffer for RX.
+                .rx_buffer(minimq::config::BufferConfig::Maximum(100))
+                .client_id(&get_client_id(id, "tlm"))
+                .unwrap();
 
-        let named_broker = minimq::broker::NamedBroker::new(
-            broker,
+        // Authenticate if a user name is configured. The credentials are referenced by the clients
+        // and must be kept in static storage.
+        if !device.mqtt_user.is_empty() {
+            // Note(unwrap): The storage has the capacity of the device settings.
+            user.push_str(&device.mqtt_user).unwrap();
+            password.push_str(&device.mqtt_password).unwrap();
+            let user: &'static str = user;
+            let password: &'static str = password;
+            settings_config = settings_config.set_auth(user, password).unwrap();
+            telemetry_config =
+                telemetry_config.set_auth(user, password).unwrap();
+        }
+
+        let settings = miniconf::MqttClient::new(
             stack_manager.acquire_stack(),
+            &prefix,
+            clock,
+            settings,
+            settings_config,
         )
         .unwrap();
+
         let mqtt = minimq::Minimq::new(
             stack_manager.acquire_stack(),
             clock,
-            minimq::ConfigBuilder::new(named_broker, &mut store.telemetry)
-                // The telemetry client doesn't receive any messages except MQTT control packets.
-                // As such, we don't need much of the buffer for RX.
-                .rx_buffer(minimq::config::BufferConfig::Maximum(100))
-                .client_id(&get_client_id(id, "tlm"))
-                .unwrap(),
+            telemetry_config,
         );
 
         let telemetry = TelemetryClient::new(mqtt, &prefix);
 
         let (generator, stream) =
-            data_stream::setup_streaming(stack_manager.acquire_stack());
+            data_stream::setup_streaming(stack_manager.acquire_stack(), clock);
+
+        let (split_generator, split_stream) =
+            data_stream::setup_split_streaming(
+                stack_manager.acquire_stack(),
+                clock,
+            );
 
         NetworkUsers {
             miniconf: settings,
@@ -155,6 +191,8 @@ where
             telemetry,
             stream,
             generator: Some(generator),
+            split_stream,
+            split_generator: Some(split_generator),
         }
     }
 
@@ -162,15 +200,138 @@ where
     ///
     /// # Args
     /// * `format` - A unique u8 code indicating the format of the data.
+    /// * `batch_size` - The number of samples of each channel in a batch.
     pub fn configure_streaming(
         &mut self,
         format: impl Into<u8>,
+        batch_size: usize,
     ) -> FrameGenerator {
+        let format = format.into();
         let mut generator = self.generator.take().unwrap();
         generator.configure(format);
+        self.stream.describe(StreamDescriptor {
+            format,
+            batch_size: batch_size as u16,
+            ..Default::default()
+        });
         generator
     }
 
+    /// Enable the split live data stream.
+    ///
+    /// # Note
+    /// The split stream is transmitted to its own remote, see [NetworkUsers::direct_split_stream].
+    /// It shares the enabled state and the format with the primary stream.
+    ///
+    /// # Args
+    /// * `format` - A unique u8 code indicating the format of the data.
+    /// * `batch_size` - The number of samples of each channel in a batch.
+    pub fn configure_split_streaming(
+        &mut self,
+        format: impl Into<u8>,
+        batch_size: usize,
+    ) -> FrameGenerator {
+        let format = format.into();
+        let mut generator = self.split_generator.take().unwrap();
+        generator.configure(format);
+        self.split_stream.describe(StreamDescriptor {
+            format,
+            batch_size: batch_size as u16,
+            ..Default::default()
+        });
+        generator
+    }
+
+    /// Add frame buffers to the live data stream.
+    ///
+    /// # Note
+    /// By default, 4 frames are buffered. Refer to [DataStream::grow] for details.
+    ///
+    /// # Args
+    /// * `memory` - The memory to use for additional frame buffers.
+    ///
+    /// # Returns
+    /// The number of frame buffers added.
+    pub fn grow_stream_buffer(&mut self, memory: &'static mut [u8]) -> usize {
+        self.stream.grow(memory)
+    }
+
+    /// Select the channels present in the live data stream.
+    ///
+    /// # Note
+    /// The application is responsible for serializing only the selected channels and for switching
+    /// the channels of the [FrameGenerator]. The stream descriptor is updated to inform the
+    /// receiver about the new layout.
+    ///
+    /// # Args
+    /// * `channels` - A bit mask of the channels present in each batch.
+    pub fn select_stream_channels(&mut self, channels: u8) {
+        self.stream.set_channels(channels);
+    }
+
+    /// Select the channels present in the split live data stream.
+    ///
+    /// # Note
+    /// Refer to [NetworkUsers::select_stream_channels].
+    ///
+    /// # Args
+    /// * `channels` - A bit mask of the channels present in each batch.
+    pub fn select_split_stream_channels(&mut self, channels: u8) {
+        self.split_stream.set_channels(channels);
+    }
+
+    /// Announce a change of the live data stream format.
+    ///
+    /// # Note
+    /// The application is responsible for switching the format of the [FrameGenerator]. The
+    /// stream descriptor is updated to inform the receiver about the new format.
+    ///
+    /// # Args
+    /// * `format` - A unique u8 code indicating the format of the data.
+    pub fn select_stream_format(&mut self, format: impl Into<u8>) {
+        let format = format.into();
+        self.stream.set_format(format);
+        self.split_stream.set_format(format);
+    }
+
+    /// Announce a change of the decimation of the live data stream.
+    ///
+    /// # Note
+    /// The application is responsible for decimating the streamed data. The stream descriptor is
+    /// updated to inform the receiver about the effective sample rate.
+    ///
+    /// # Args
+    /// * `decimation` - The number of consecutive samples averaged into each streamed sample.
+    pub fn select_stream_decimation(&mut self, decimation: u32) {
+        self.stream.set_decimation(decimation);
+        self.split_stream.set_decimation(decimation);
+    }
+
+    /// Pause or resume the live data stream.
+    ///
+    /// # Args
+    /// * `enabled` - Specifies whether stream data is transmitted.
+    pub fn enable_stream(&mut self, enabled: bool) {
+        if enabled {
+            self.stream.resume();
+            self.split_stream.resume();
+        } else {
+            self.stream.pause();
+            self.split_stream.pause();
+        }
+    }
+
+    /// Check whether live data is being streamed to a remote target on the primary or the split
+    /// stream.
+    ///
+    /// # Note
+    /// Refer to [DataStream::is_streaming] for details.
+    pub fn is_streaming(&self) -> bool {
+        (self.generator.is_none() && self.stream.is_streaming())
+            || (self.split_generator.is_none()
+                && self.split_stream.is_streaming())
+    }
+
     /// Direct the stream to the provided remote target.
     ///
     /// # Args
@@ -181,6 +342,17 @@ where
         }
     }
 
+    /// Direct the split stream to the provided remote target.
+    ///
+    /// # Args
+    /// * `remote` - The destination for the split stream data. An unspecified address closes
+    ///   the split stream.
+    pub fn direct_split_stream(&mut self, remote: SocketAddr) {
+        if self.split_generator.is_none() {
+            self.split_stream.set_remote(remote);
+        }
+    }
+
     /// Update and process all of the network users state.
     ///
     /// # Returns
@@ -188,12 +360,17 @@ where
     /// The SettingsChanged option contains the path of the settings that changed.
     pub fn update(&mut self) -> NetworkState {
         // Update the MQTT clients.
-        self.telemetry.update();
+        let settings = self.miniconf.settings();
+        self.telemetry
+            .update(|path, buf| settings.get_json(path, buf).ok());
 
         // Update the data stream.
         if self.generator.is_none() {
             self.stream.process();
         }
+        if self.split_generator.is_none() {
+            self.split_stream.process();
+        }
 
         // Poll for incoming data.
         let poll_result = match self.processor.update() {
@@ -10,11 +10,29 @@
 //! sampling frequency. Instead, the raw codes are stored and the telemetry is generated as
 //! required immediately before transmission. This ensures that any slower computation required
 //! for unit conversion can be off-loaded to lower priority tasks.
+//!
+//! # Settings Readback
+//! The telemetry client also serves read requests of the current settings. Publishing a settings
+//! path (e.g. `/afe/0`) to `<prefix>/get` causes the current JSON value of that setting to be
+//! published to `<prefix>/readback<path>` (e.g. `<prefix>/readback/afe/0`).
+//!
+//! # Sample Rate
+//! The effective ADC sample rate in Hz is published as a retained message to
+//! `<prefix>/sample_rate` whenever the client connects to the broker. It accounts for the
+//! `sample_divider` of the device settings, which can be changed through the USB serial
+//! interface and takes effect after a reset.
 use heapless::{String, Vec};
 use serde::Serialize;
 
 use super::NetworkReference;
-use crate::hardware::{adc::AdcCode, afe::Gain, dac::DacCode, SystemTimer};
+use crate::dsp::interval_stats::IntervalStatistics;
+use crate::hardware::{
+    adc::{self, AdcCode},
+    afe::Gain,
+    dac::DacCode,
+    design_parameters::SYSCLK,
+    SystemTimer,
+};
 
 /// The telemetry client for reporting telemetry data over MQTT.
 pub struct TelemetryClient<T: Serialize> {
@@ -25,6 +43,12 @@ pub struct TelemetryClient<T: Serialize> {
         minimq::broker::NamedBroker<NetworkReference>,
     >,
     telemetry_topic: String<128>,
+    get_topic: String<128>,
+    readback_topic: String<128>,
+    sample_rate_topic: String<128>,
+    sample_rate: Option<f32>,
+    subscribed: bool,
+    sample_rate_published: bool,
     _telemetry: core::marker::PhantomData<T>,
 }
 
@@ -36,12 +60,37 @@ pub struct TelemetryClient<T: Serialize> {
 /// run-time overhead during conversion to SI units.
 #[derive(Copy, Clone)]
 pub struct TelemetryBuffer {
-    /// The latest input sample on ADC0/ADC1.
+    /// The mean input code of the latest batch on ADC0/ADC1.
     pub adcs: [AdcCode; 2],
-    /// The latest output code on DAC0/DAC1.
+    /// The mean output code of the latest batch on DAC0/DAC1.
     pub dacs: [DacCode; 2],
     /// The latest digital input states during processing.
     pub digital_inputs: [bool; 2],
+    /// The RMS of the ADC0/ADC1 control error in ADC LSB, if the application tracks it.
+    pub error_rms: Option<[u32; 2]>,
+    /// The latest PLL lock state, if the application uses a PLL.
+    pub pll_locked: Option<bool>,
+    /// The latest PLL frequency estimate as the phase increment per batch (2**32 per turn), if
+    /// the application uses a PLL.
+    pub pll_frequency: Option<u32>,
+    /// The number of rejected PLL reference timestamps, if the application uses a PLL.
+    pub pll_rejected: Option<u32>,
+    /// The number of reference timestamp over-captures, if the application uses a PLL.
+    pub pll_overcaptures: Option<u32>,
+    /// The statistics of the accepted PLL reference intervals since the last report, if the
+    /// application uses a PLL.
+    pub pll_intervals: Option<IntervalStatistics>,
+    /// The signal phase of the quadrature nulling loop, if it is enabled.
+    pub lockin_null_phase: Option<i32>,
+    /// The number of dropped stream batches, if the application streams data.
+    pub stream_dropped: Option<u32>,
+    /// The number of batches remaining in a one-shot stream capture, if one is configured.
+    pub stream_capture: Option<u32>,
+    /// The latest state of the data stream, if the application streams data.
+    pub streaming: Option<bool>,
+    /// The maximum number of CPU cycles spent processing a batch since the last report, if the
+    /// application measures it.
+    pub process_cycles: Option<u32>,
 }
 
 /// The telemetry structure is data that is ultimately reported as telemetry over MQTT.
@@ -51,30 +100,169 @@ pub struct TelemetryBuffer {
 /// overhead.
 #[derive(Serialize)]
 pub struct Telemetry {
-    /// Most recent input voltage measurement.
+    /// Mean input voltage of the most recent batch.
     pub adcs: [f32; 2],
 
-    /// Most recent output voltage.
+    /// Mean output voltage of the most recent batch.
     pub dacs: [f32; 2],
 
     /// Most recent digital input assertion state.
     pub digital_inputs: [bool; 2],
 
+    /// The number of ADC0/ADC1 input overruns since power-up. Every overrun discards the current
+    /// batch and resynchronizes the ADCs and DACs.
+    pub adc_overruns: [u32; 2],
+
+    /// The RMS of the control error of each channel in volts, referred to the AFE input. Only
+    /// reported by applications tracking it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_rms: Option<[f32; 2]>,
+
+    /// Whether the PLL is locked to the reference. Only reported by applications using a PLL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pll_locked: Option<bool>,
+
+    /// The recovered reference frequency in Hz. Only reported by applications using a PLL.
+    ///
+    /// # Note
+    /// The conversion from [TelemetryBuffer::pll_frequency] depends on the batch period of the
+    /// application and is done by the application after [TelemetryBuffer::finalize].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pll_frequency: Option<f32>,
+
+    /// The number of reference timestamps rejected by the PLL. Only reported by applications using
+    /// a PLL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pll_rejected: Option<u32>,
+
+    /// The number of reference timestamp over-captures since power-up, i.e. events where at least
+    /// one reference edge was lost. Only reported by applications using a PLL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pll_overcaptures: Option<u32>,
+
+    /// Statistics of the intervals between the accepted reference timestamps since the previous
+    /// report. Only reported by applications using a PLL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pll_intervals: Option<IntervalTelemetry>,
+
+    /// The signal phase of the lockin quadrature nulling loop in degrees, i.e. the negative LO
+    /// rotation. It includes the `lockin_phase` offset. Only reported while the loop is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lockin_null_phase: Option<f32>,
+
+    /// The number of stream batches dropped due to a lack of frame buffers. Only reported by
+    /// applications streaming data.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_dropped: Option<u32>,
+
+    /// The number of batches remaining in a one-shot stream capture. Zero once the capture has
+    /// completed. Not reported while streaming continuously.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_capture: Option<u32>,
+
+    /// Whether stream data is being transmitted, i.e. the stream is enabled and a socket to the
+    /// stream target is open. Only reported by applications streaming data.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub streaming: Option<bool>,
+
+    /// The maximum execution time of the DSP processing of a batch since the previous report in
+    /// microseconds. Compare to the batch period to assess the processing margin. Only reported
+    /// by applications measuring it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub process_time: Option<f32>,
+
     /// The CPU temperature in degrees Celsius.
     pub cpu_temp: f32,
 }
 
+/// Statistics of the intervals between timestamps.
+#[derive(Serialize)]
+pub struct IntervalTelemetry {
+    /// The number of intervals.
+    pub count: u32,
+
+    /// The minimum interval in timestamp timer ticks.
+    pub min: u32,
+
+    /// The maximum interval in timestamp timer ticks.
+    pub max: u32,
+
+    /// The mean interval in timestamp timer ticks.
+    pub mean: f32,
+
+    /// The sample variance of the intervals in squared timestamp timer ticks.
+    pub variance: f32,
+}
+
+impl From<IntervalStatistics> for IntervalTelemetry {
+    fn from(stats: IntervalStatistics) -> Self {
+        Self {
+            count: stats.count(),
+            min: stats.min(),
+            max: stats.max(),
+            mean: stats.mean(),
+            variance: stats.variance(),
+        }
+    }
+}
+
 impl Default for TelemetryBuffer {
     fn default() -> Self {
         Self {
             adcs: [AdcCode(0), AdcCode(0)],
             dacs: [DacCode(0), DacCode(0)],
             digital_inputs: [false, false],
+            error_rms: None,
+            pll_locked: None,
+            pll_frequency: None,
+            pll_rejected: None,
+            pll_overcaptures: None,
+            pll_intervals: None,
+            lockin_null_phase: None,
+            stream_dropped: None,
+            stream_capture: None,
+            streaming: None,
+            process_cycles: None,
         }
     }
 }
 
 impl TelemetryBuffer {
+    /// Update the ADC/DAC measurements with the mean of a batch of codes.
+    ///
+    /// # Args
+    /// * `adcs` - The batch of ADC0/ADC1 input codes.
+    /// * `dacs` - The batch of DAC0/DAC1 output codes.
+    pub fn update_codes<A, D>(&mut self, adcs: &[A; 2], dacs: &[D; 2])
+    where
+        A: AsRef<[u16]>,
+        D: AsRef<[u16]>,
+    {
+        fn mean(codes: impl ExactSizeIterator<Item = i16>) -> i16 {
+            let len = codes.len() as i32;
+            (codes.map(i32::from).sum::<i32>() / len) as i16
+        }
+
+        for (code, adc) in self.adcs.iter_mut().zip(adcs) {
+            let adc = adc.as_ref().iter().map(|x| AdcCode(*x).into());
+            *code = AdcCode::from(mean(adc));
+        }
+
+        for (code, dac) in self.dacs.iter_mut().zip(dacs) {
+            let dac = dac.as_ref().iter().map(|x| DacCode(*x).into());
+            *code = DacCode::from(mean(dac));
+        }
+    }
+
+    /// Track the maximum execution time of the DSP processing.
+    ///
+    /// # Args
+    /// * `cycles` - The number of CPU cycles spent processing the latest batch.
+    pub fn update_process_cycles(&mut self, cycles: u32) {
+        let max = self.process_cycles.get_or_insert(0);
+        *max = (*max).max(cycles);
+    }
+
     /// Convert the telemetry buffer to finalized, SI-unit telemetry for reporting.
     ///
     /// # Args
@@ -93,6 +281,29 @@ impl TelemetryBuffer {
             adcs: [in0_volts, in1_volts],
             dacs: [self.dacs[0].into(), self.dacs[1].into()],
             digital_inputs: self.digital_inputs,
+            adc_overruns: adc::overruns(),
+            error_rms: self.error_rms.map(|rms| {
+                [
+                    rms[0] as f32 * AdcCode::VOLT_PER_LSB
+                        / afe0.as_multiplier(),
+                    rms[1] as f32 * AdcCode::VOLT_PER_LSB
+                        / afe1.as_multiplier(),
+                ]
+            }),
+            pll_locked: self.pll_locked,
+            pll_frequency: None,
+            pll_rejected: self.pll_rejected,
+            pll_overcaptures: self.pll_overcaptures,
+            pll_intervals: self.pll_intervals.map(Into::into),
+            lockin_null_phase: self
+                .lockin_null_phase
+                .map(|phase| phase as f32 * (180. / (1u64 << 31) as f32)),
+            stream_dropped: self.stream_dropped,
+            stream_capture: self.stream_capture,
+            streaming: self.streaming,
+            process_time: self
+                .process_cycles
+                .map(|cycles| cycles as f32 / SYSCLK.to_MHz() as f32),
         }
     }
 }
@@ -118,9 +329,24 @@ impl<T: Serialize> TelemetryClient<T> {
         let mut telemetry_topic: String<128> = String::from(prefix);
         telemetry_topic.push_str("/telemetry").unwrap();
 
+        let mut get_topic: String<128> = String::from(prefix);
+        get_topic.push_str("/get").unwrap();
+
+        let mut readback_topic: String<128> = String::from(prefix);
+        readback_topic.push_str("/readback").unwrap();
+
+        let mut sample_rate_topic: String<128> = String::from(prefix);
+        sample_rate_topic.push_str("/sample_rate").unwrap();
+
         Self {
             mqtt,
             telemetry_topic,
+            get_topic,
+            readback_topic,
+            sample_rate_topic,
+            sample_rate: None,
+            subscribed: false,
+            sample_rate_published: false,
             _telemetry: core::marker::PhantomData,
         }
     }
@@ -148,14 +374,96 @@ impl<T: Serialize> TelemetryClient<T> {
             .ok();
     }
 
+    /// Configure the sample rate published by the client.
+    ///
+    /// # Args
+    /// * `rate` - The effective ADC sample rate in Hz.
+    pub fn set_sample_rate(&mut self, rate: f32) {
+        self.sample_rate = Some(rate);
+        self.sample_rate_published = false;
+    }
+
     /// Update the telemetry client
     ///
     /// # Note
     /// This function is provided to force the underlying MQTT state machine to process incoming
     /// and outgoing messages. Without this, the client will never connect to the broker. This
     /// should be called regularly.
-    pub fn update(&mut self) {
-        match self.mqtt.poll(|_client, _topic, _message, _properties| {}) {
+    ///
+    /// # Args
+    /// * `get` - A closure serializing the setting at the provided path into the provided
+    ///           buffer. Returns the number of bytes written or `None` if the setting could not be
+    ///           serialized.
+    pub fn update<F>(&mut self, mut get: F)
+    where
+        F: FnMut(&str, &mut [u8]) -> Option<usize>,
+    {
+        if !self.mqtt.client().is_connected() {
+            self.subscribed = false;
+            self.sample_rate_published = false;
+        } else if !self.subscribed {
+            self.subscribed = self
+                .mqtt
+                .client()
+                .subscribe(
+                    &[minimq::types::TopicFilter::new(&self.get_topic)],
+                    &[],
+                )
+                .is_ok();
+        } else if let (false, Some(rate)) =
+            (self.sample_rate_published, self.sample_rate)
+        {
+            let rate: Vec<u8, 32> = serde_json_core::to_vec(&rate).unwrap();
+            self.sample_rate_published = self
+                .mqtt
+                .client()
+                .publish(
+                    minimq::Publication::<&[u8]>::new(&rate)
+                        .topic(&self.sample_rate_topic)
+                        .retain()
+                        .finish()
+                        .unwrap(),
+                )
+                .is_ok();
+        }
+
+        let get_topic = &self.get_topic;
+        let readback_topic = &self.readback_topic;
+        match self.mqtt.poll(|client, topic, message, _properties| {
+            if topic != get_topic.as_str() {
+                return;
+            }
+
+            let path = match core::str::from_utf8(message) {
+                Ok(path) => path,
+                Err(_) => return,
+            };
+
+            let mut response_topic = readback_topic.clone();
+            if response_topic.push_str(path).is_err() {
+                log::warn!("Readback path too long: {}", path);
+                return;
+            }
+
+            let mut value = [0u8; 256];
+            let len = match get(path, &mut value) {
+                Some(len) => len,
+                None => {
+                    log::warn!("Readback of {} failed", path);
+                    return;
+                }
+            };
+
+            client
+                .publish(
+                    minimq::Publication::<&[u8]>::new(&value[..len])
+                        .topic(&response_topic)
+                        .finish()
+                        .unwrap(),
+                )
+                .map_err(|e| log::error!("Readback publishing error: {:?}", e))
+                .ok();
+        }) {
             Err(minimq::Error::Network(
                 smoltcp_nal::NetworkError::TcpConnectionFailure(
                     smoltcp_nal::smoltcp::socket::tcp::ConnectError::Unaddressable
@@ -6,12 +6,15 @@
 //! 2. Dynamic Run-time Settings
 //!
 //! Static device configuration settings are loaded and used only at device power-up. These include
-//! things like the MQTT broker address and the MQTT identified. Conversely, the dynamic run-time
+//! things like the MQTT broker address and credentials, the MQTT identifier and the sample rate divider. Conversely, the dynamic run-time
 //! settings can be changed and take effect immediately during device operation.
 //!
-//! This settings management interface is currently targeted at the static device configuration
+//! This settings management interface is primarily targeted at the static device configuration
 //! settings. Settings are persisted into the unused 1MB flash bank of Stabilizer for future
 //! recall. They can be modified via the USB interface to facilitate device configuration.
+//! Applications additionally persist their run-time settings in the same flash bank when they are
+//! explicitly committed and restore them at power-up. The keys of run-time settings are prefixed
+//! with the application name, as the settings of different applications are not compatible.
 //!
 //! Settings are stored in flash using a key-value pair mapping, where the `key` is the name of the
 //! entry in the settings structure. This has a number of benefits:
@@ -33,6 +36,14 @@ use stm32h7xx_hal::flash::LockedFlashBank;
 pub struct Settings {
     pub broker: heapless::String<255>,
     pub id: heapless::String<23>,
+    /// The user name to authenticate with the MQTT broker. Empty if the broker does not require
+    /// authentication.
+    pub mqtt_user: heapless::String<32>,
+    /// The password to authenticate with the MQTT broker.
+    pub mqtt_password: heapless::String<64>,
+    /// Divides the sampling and timestamp timer clocks, reducing the sample rate of the
+    /// application by this factor. Must be at least 1. See [crate::hardware::setup::setup].
+    pub sample_divider: u16,
     #[tree(skip)]
     pub mac: smoltcp_nal::smoltcp::wire::EthernetAddress,
 }
@@ -51,52 +62,177 @@ impl Settings {
         Self {
             broker: "mqtt".into(),
             id,
+            mqtt_user: heapless::String::new(),
+            mqtt_password: heapless::String::new(),
+            sample_divider: 1,
             mac,
         }
     }
 
     pub fn reload(&mut self, storage: &mut Flash) {
-        // Loop over flash and read settings
-        let mut buffer = [0u8; 512];
-        for path in Settings::iter_paths::<heapless::String<32>>("/") {
-            let path = path.unwrap();
-
-            // Try to fetch the setting from flash.
-            let Some(item) =
-                sequential_storage::map::fetch_item::<SettingsItem, _>(
-                    storage,
-                    storage.range(),
-                    &mut buffer,
-                    path.clone(),
-                )
-                .unwrap()
-            else {
-                continue;
-            };
+        load_from_flash(self, storage, "")
+    }
+}
+
+/// Load a settings tree from flash.
+///
+/// # Note
+/// Every settings item is stored with a checksum. Items that are missing, corrupt or fail to
+/// deserialize retain their current value.
+///
+/// # Args
+/// * `structure` - The settings tree to load.
+/// * `storage` - The flash storage holding the settings items.
+/// * `prefix` - The prefix of the flash keys of the settings items.
+pub fn load_from_flash<T, const Y: usize>(
+    structure: &mut T,
+    storage: &mut Flash,
+    prefix: &str,
+) where
+    T: TreeKey<Y> + for<'de> TreeDeserialize<'de, Y>,
+{
+    // Loop over flash and read settings
+    let mut buffer = [0u8; 512];
+    for path in T::iter_paths::<heapless::String<32>>("/") {
+        let Ok(path) = path else {
+            continue;
+        };
 
-            log::info!("Found `{path}` in flash settings");
+        let Some(key) = item_key(prefix, &path) else {
+            log::warn!("Flash key of `{path}` is too long");
+            continue;
+        };
 
-            let mut deserializer = postcard::Deserializer::from_flavor(
-                postcard::de_flavors::Slice::new(&item.data),
-            );
-            if let Err(e) = self
-                .deserialize_by_key(path.split('/').skip(1), &mut deserializer)
-            {
-                log::warn!("Failed to load {path} from flash settings: {e:?}");
+        // Try to fetch the setting from flash.
+        let item = match sequential_storage::map::fetch_item::<SettingsItem, _>(
+            storage,
+            storage.range(),
+            &mut buffer,
+            key,
+        ) {
+            Ok(Some(item)) => item,
+            Ok(None) => continue,
+            Err(e) => {
+                log::warn!("Failed to fetch `{path}` from flash: {e:?}");
+                continue;
             }
+        };
+
+        log::info!("Found `{path}` in flash settings");
+
+        let mut deserializer = postcard::Deserializer::from_flavor(
+            postcard::de_flavors::Slice::new(&item.data),
+        );
+        if let Err(e) = structure
+            .deserialize_by_key(path.split('/').skip(1), &mut deserializer)
+        {
+            log::warn!("Failed to load {path} from flash settings: {e:?}");
+        }
+    }
+}
+
+/// Persist a settings tree in flash.
+///
+/// # Note
+/// Only items that differ from their stored value are written to flash.
+///
+/// # Args
+/// * `structure` - The settings tree to store.
+/// * `storage` - The flash storage to store the settings items in.
+/// * `prefix` - The prefix of the flash keys of the settings items.
+/// * `skip` - The paths of settings items that are not stored.
+/// * `buf` - A buffer used for reading and writing settings items.
+pub fn save_to_flash<T, const Y: usize>(
+    structure: &T,
+    storage: &mut Flash,
+    prefix: &str,
+    skip: &[&str],
+    buf: &mut [u8],
+) -> Result<
+    (),
+    Error<<LockedFlashBank as embedded_storage::nor_flash::ErrorType>::Error>,
+>
+where
+    T: TreeKey<Y> + TreeSerialize<Y>,
+{
+    for path in T::iter_paths::<heapless::String<32>>("/") {
+        let Ok(path) = path else {
+            continue;
+        };
+
+        if skip.contains(&path.as_str()) {
+            continue;
+        }
+
+        let Some(key) = item_key(prefix, &path) else {
+            log::warn!("Flash key of `{path}` is too long");
+            continue;
+        };
+
+        // Matches the capacity of `SettingsItem::data`.
+        let mut data = [0u8; 256];
+        let mut serializer = postcard::Serializer {
+            output: postcard::ser_flavors::Slice::new(&mut data),
+        };
+
+        if let Err(e) =
+            structure.serialize_by_key(path.split('/').skip(1), &mut serializer)
+        {
+            log::warn!("Failed to save {path} to flash: {e:?}");
+            continue;
+        }
+
+        let item = SettingsItem {
+            data: heapless::Vec::from_slice(serializer.output.finalize()?)
+                .map_err(|_| postcard::Error::SerializeBufferFull)?,
+            path: key,
+        };
+
+        let range = storage.range();
+
+        // Check if the settings has changed from what's currently in flash (or if it doesn't
+        // yet exist).
+        if sequential_storage::map::fetch_item::<SettingsItem, _>(
+            storage,
+            range.clone(),
+            buf,
+            item.path.clone(),
+        )?
+        .map(|old| old.data != item.data)
+        .unwrap_or(true)
+        {
+            log::info!("Storing setting `{}` in flash", item.path);
+            sequential_storage::map::store_item(storage, range, buf, item)?;
         }
     }
+
+    Ok(())
+}
+
+/// Get the flash key of a settings item.
+///
+/// # Args
+/// * `prefix` - The prefix of the key.
+/// * `path` - The path of the settings item.
+///
+/// # Returns
+/// The key, or `None` if it exceeds the key capacity.
+fn item_key(prefix: &str, path: &str) -> Option<heapless::String<64>> {
+    let mut key = heapless::String::new();
+    key.push_str(prefix).ok()?;
+    key.push_str(path).ok()?;
+    Some(key)
 }
 
 #[derive(Default, serde::Serialize, serde::Deserialize)]
 pub struct SettingsItem {
     // We only make these owned vec/string to get around lifetime limitations.
-    pub path: heapless::String<32>,
+    pub path: heapless::String<64>,
     pub data: heapless::Vec<u8, 256>,
 }
 
 impl sequential_storage::map::StorageItem for SettingsItem {
-    type Key = heapless::String<32>;
+    type Key = heapless::String<64>;
     type Error = postcard::Error;
 
     fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
@@ -116,6 +252,7 @@ impl sequential_storage::map::StorageItem for SettingsItem {
 pub enum Error<F> {
     Postcard(postcard::Error),
     Flash(F),
+    Storage(sequential_storage::map::MapError<postcard::Error, F>),
 }
 
 impl<F> From<postcard::Error> for Error<F> {
@@ -124,6 +261,14 @@ impl<F> From<postcard::Error> for Error<F> {
     }
 }
 
+impl<F> From<sequential_storage::map::MapError<postcard::Error, F>>
+    for Error<F>
+{
+    fn from(e: sequential_storage::map::MapError<postcard::Error, F>) -> Self {
+        Self::Storage(e)
+    }
+}
+
 pub struct SerialSettingsPlatform {
     /// The interface to read/write data to/from serially (via text) to the user.
     pub interface: serial_settings::BestEffortInterface<
@@ -135,6 +280,54 @@ pub struct SerialSettingsPlatform {
     pub storage: Flash,
 }
 
+impl SerialSettingsPlatform {
+    /// Load run-time application settings from flash.
+    ///
+    /// # Note
+    /// Refer to [load_from_flash] for the handling of missing or corrupt items.
+    ///
+    /// # Args
+    /// * `app` - The name of the application the settings belong to.
+    /// * `settings` - The run-time settings to load.
+    pub fn load<T, const Y: usize>(&mut self, app: &str, settings: &mut T)
+    where
+        T: TreeKey<Y> + for<'de> TreeDeserialize<'de, Y>,
+    {
+        let mut prefix: heapless::String<32> = heapless::String::new();
+        if write!(&mut prefix, "/{app}").is_err() {
+            log::warn!("Application name `{app}` is too long");
+            return;
+        }
+        load_from_flash(settings, &mut self.storage, &prefix)
+    }
+
+    /// Persist run-time application settings in flash.
+    ///
+    /// # Note
+    /// Erasing flash may block for a significant amount of time.
+    ///
+    /// # Args
+    /// * `app` - The name of the application the settings belong to.
+    /// * `settings` - The run-time settings to store.
+    /// * `skip` - The paths of settings that are not stored, e.g. one-shot commands. They take
+    ///   their default value at the next power-up.
+    pub fn store<T, const Y: usize>(
+        &mut self,
+        app: &str,
+        settings: &T,
+        skip: &[&str],
+    ) -> Result<(), <Self as serial_settings::Platform>::Error>
+    where
+        T: TreeKey<Y> + TreeSerialize<Y>,
+    {
+        let mut prefix: heapless::String<32> = heapless::String::new();
+        write!(&mut prefix, "/{app}")
+            .map_err(|_| postcard::Error::SerializeBufferFull)?;
+        let mut buf = [0u8; 512];
+        save_to_flash(settings, &mut self.storage, &prefix, skip, &mut buf)
+    }
+}
+
 impl serial_settings::Platform for SerialSettingsPlatform {
     type Interface = serial_settings::BestEffortInterface<
         usbd_serial::SerialPort<'static, crate::hardware::UsbBus>,
@@ -145,55 +338,7 @@ impl serial_settings::Platform for SerialSettingsPlatform {
     >;
 
     fn save(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
-        for path in Settings::iter_paths::<heapless::String<32>>("/") {
-            let mut item = SettingsItem {
-                path: path.unwrap(),
-                ..Default::default()
-            };
-
-            item.data.resize(item.data.capacity(), 0).unwrap();
-
-            let mut serializer = postcard::Serializer {
-                output: postcard::ser_flavors::Slice::new(&mut item.data),
-            };
-
-            if let Err(e) = self
-                .settings
-                .serialize_by_key(item.path.split('/').skip(1), &mut serializer)
-            {
-                log::warn!("Failed to save {} to flash: {e:?}", item.path);
-                continue;
-            }
-
-            let len = serializer.output.finalize()?.len();
-            item.data.truncate(len);
-
-            let range = self.storage.range();
-
-            // Check if the settings has changed from what's currently in flash (or if it doesn't
-            // yet exist).
-            if sequential_storage::map::fetch_item::<SettingsItem, _>(
-                &mut self.storage,
-                range.clone(),
-                buf,
-                item.path.clone(),
-            )
-            .unwrap()
-            .map(|old| old.data != item.data)
-            .unwrap_or(true)
-            {
-                log::info!("Storing setting `{}` in flash", item.path);
-                sequential_storage::map::store_item(
-                    &mut self.storage,
-                    range,
-                    buf,
-                    item,
-                )
-                .unwrap();
-            }
-        }
-
-        Ok(())
+        save_to_flash(&self.settings, &mut self.storage, "", &[], buf)
     }
 
     fn cmd(&mut self, cmd: &str) {